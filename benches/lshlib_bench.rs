@@ -1,10 +1,37 @@
-use rush::lshlib;
+use rush::lsh::stable_hash::StableHashFunction;
+use rush::lsh::hadamard_hash::HadamardHashFunction;
+use rush::lsh::sparse_hash::{SparseHashFunction, SparseVector};
+use rush::simd::{SimdVecImpl, SimdVector, f32x4};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
 fn bench_stable_hash_function(c: &mut Criterion) {
-    let hashfn = lshlib::StableHashFunction::rand(64, 768);
-    let v = vec![1.0; 768];
-    c.bench_function("hash 768", |b| b.iter(|| hashfn.hash(&v)));
+    let hashfn = StableHashFunction::<SimdVecImpl<f32x4, 192>>::new(64, 768);
+    let v = vec![1f32; 768].into_iter().collect::<SimdVecImpl<f32x4, 192>>();
+    c.bench_function("hash 768", |b| b.iter(|| hashfn.hash(black_box(&v))));
+
+    let hashfn = StableHashFunction::<SimdVector<f32x4>>::new(64, 768);
+    let v = vec![1f32; 768].into_iter().collect::<SimdVector<f32x4>>();
+    c.bench_function("hash 768 runtime dimension", |b| b.iter(|| hashfn.hash(black_box(&v))));
+
+    let hashfn = HadamardHashFunction::<SimdVector<f32x4>>::new(64, 768);
+    c.bench_function("hadamard hash 768", |b| b.iter(|| hashfn.hash(black_box(&v))));
+
+    // A dataset hashes every vector once per table, and 32 tables of dense
+    // projections no longer fit in cache
+    let dense = (0..32).map(|_| StableHashFunction::<SimdVector<f32x4>>::new(64, 768)).collect::<Vec<_>>();
+    c.bench_function("hash 768 x 32 tables", |b| b.iter(|| dense.iter().fold(0u64, |acc, f| acc ^ f.hash(black_box(&v)))));
+    let hadamard = (0..32).map(|_| HadamardHashFunction::<SimdVector<f32x4>>::new(64, 768)).collect::<Vec<_>>();
+    c.bench_function("hadamard hash 768 x 32 tables", |b| b.iter(|| hadamard.iter().fold(0u64, |acc, f| acc ^ f.hash(black_box(&v)))));
+}
+
+fn bench_sparse_hash_function(c: &mut Criterion) {
+    let dim = 100_000;
+    let hashfn = SparseHashFunction::<SimdVector<f32x4>>::new(64, dim, SparseHashFunction::<SimdVector<f32x4>>::default_density(dim));
+    let v = (0..dim).map(|i| (i as f32).sin()).collect::<SimdVector<f32x4>>();
+    c.bench_function("sparse hash 100000", |b| b.iter(|| hashfn.hash(black_box(&v))));
+
+    let v = SparseVector::new(dim, (0..dim).step_by(1000).map(|i| (i, 1f32))).unwrap();
+    c.bench_function("sparse hash 100000 of 100 nonzeros", |b| b.iter(|| hashfn.hash_sparse(black_box(&v))));
 }
 
 /*
@@ -16,6 +43,6 @@ fn bench_query_to_lsh_db(c: &mut Criterion) {
     });
 }
 */
-criterion_group!(stable_hash_benches, bench_stable_hash_function);
+criterion_group!(stable_hash_benches, bench_stable_hash_function, bench_sparse_hash_function);
 //criterion_group!(lsh_db_benches, bench_query_to_lsh_db);
 criterion_main!(stable_hash_benches);
@@ -1,9 +1,9 @@
-use rush::simdlib::*;
-use rush::lshlib::*;
+use rush::simd::*;
+use rush::lsh::vector::Vector;
 use criterion::{criterion_group, criterion_main, Criterion};
 use itertools::zip_eq;
 
-fn l2(x: &Vec<f32>, y: &Vec<f32>) -> f32 {
+fn l2(x: &[f32], y: &[f32]) -> f32 {
     zip_eq(x.iter(), y.iter()).
         fold(0.0, |acc, (x, y)| {
             let delta = x - y;
@@ -29,7 +29,7 @@ fn bench_simd_f32x4_l2_distance(c: &mut Criterion) {
         |b| {
             let x = SimdVecImpl::<f32x4, 192>::new();
             let y = SimdVecImpl::<f32x4, 192>::new();
-            b.iter(|| (&x).distance(&y))
+            b.iter(|| x.distance(&y))
         }
     );
 }
@@ -40,7 +40,7 @@ fn bench_simd_f32x8_l2_distance(c: &mut Criterion) {
         |b| {
             let x = SimdVecImpl::<f32x8, 96>::new();
             let y = SimdVecImpl::<f32x8, 96>::new();
-            b.iter(|| (&x).distance(&y))
+            b.iter(|| x.distance(&y))
         }
     );
 }
@@ -0,0 +1,7 @@
+// Generates the gRPC messages, server and client from proto/rush.proto.  The
+// protoc binary comes vendored, so the build doesn't depend on one being
+// installed.
+fn main() {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    tonic_build::compile_protos("proto/rush.proto").unwrap();
+}
@@ -0,0 +1,458 @@
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write as _;
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+use rush::client::Request;
+use rush::net::{Connection, DatasetConfig, Frame, Mode};
+
+const USAGE: &str = "\
+usage: rush-cli [--host HOST] [--port PORT] [--token TOKEN] [--mode stream|bulk|single] [--full] [COMMAND...]
+
+Without a COMMAND, rush-cli starts an interactive session.  Several commands
+can be sent at once by separating them with ';'.  In stream mode they are
+pipelined over the open connection, and in bulk mode they are sent together
+as one bulk message.  A --token is sent ahead of the commands to servers that
+require authentication.
+
+commands:
+    PUT <dataset> <vector>
+    GET <dataset> <vector>
+    TOPK <dataset> <vector> <k>
+    DELETE <dataset> <vector>
+    CREATE <dataset> <dimension> [K <bits>] [L <tables>] [METRIC euclidean|cosine|dot] [HASH dense|hadamard|sparse] [DENSITY <fraction>]
+    DROP <dataset>
+    LIST
+    STATS
+
+A vector is either a JSON array of numbers, e.g. [0.5, -1, 2], or @PATH to
+read the JSON array from a file.";
+
+struct Options {
+    addr: String,
+    token: Option<String>,
+    mode: Mode,
+    // Print every element of a vector rather than a preview
+    full: bool,
+    command: Option<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let result = match &options.command {
+        Some(line) => run_line(&options, &mut None, line).await,
+        None => repl(&options).await,
+    };
+
+    if let Err(err) = result {
+        eprintln!("(error) {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item=String>) -> rush::Result<Options> {
+    let mut host = "127.0.0.1".to_string();
+    let mut port = "9090".to_string();
+    let mut token = None;
+    let mut mode = Mode::Stream;
+    let mut full = false;
+    let mut command = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for argument {}", arg));
+        match &arg[..] {
+            "--host" => host = value()?,
+            "--port" => port = value()?,
+            "--token" => token = Some(value()?),
+            "--mode" => mode = match &value()?[..] {
+                "stream" => Mode::Stream,
+                "bulk" => Mode::Bulk,
+                "single" => Mode::Single,
+                other => return Err(format!("unrecognized mode {}", other).into()),
+            },
+            "--full" => full = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => {
+                // Everything from the first positional argument on is the command
+                command.push(arg);
+                command.extend(args);
+                break;
+            }
+        }
+    }
+
+    Ok(Options {
+        addr: format!("{}:{}", host, port),
+        token,
+        mode,
+        full,
+        command: if command.is_empty() { None } else { Some(command.join(" ")) },
+    })
+}
+
+async fn repl(options: &Options) -> rush::Result<()> {
+    let mut stream = None;
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        print!("rush {}> ", options.addr);
+        std::io::stdout().flush()?;
+
+        let line = match lines.next_line().await? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+
+        match line.trim() {
+            "" => continue,
+            "quit" | "exit" => return Ok(()),
+            "help" => println!("{}", USAGE),
+            line => {
+                if let Err(err) = run_line(options, &mut stream, line).await {
+                    println!("(error) {}", err);
+                    // Start over with a fresh connection in case this one is broken
+                    stream = None;
+                }
+            }
+        }
+    }
+}
+
+// Sends the commands on one line and prints the responses.  Stream mode keeps
+// its connection open between lines; the other modes connect for every line.
+async fn run_line(options: &Options, stream: &mut Option<Connection>, line: &str) -> rush::Result<()> {
+    let commands = line.
+        split(';').
+        map(str::trim).
+        filter(|command| !command.is_empty()).
+        map(parse_command).
+        collect::<rush::Result<Vec<Frame>>>()?;
+
+    let responses = match options.mode {
+        Mode::Stream => {
+            if stream.is_none() {
+                *stream = Some(connect(options, Mode::Stream).await?);
+            }
+            let connection = stream.as_mut().unwrap();
+            for command in &commands {
+                connection.write_frame(command).await?;
+            }
+            let mut responses = Vec::with_capacity(commands.len());
+            for _ in &commands {
+                responses.push(read_response(connection).await?);
+            }
+            responses
+        }
+        Mode::Bulk => {
+            let mut connection = connect(options, Mode::Bulk).await?;
+            connection.write_frame(&Frame::Array(commands)).await?;
+            match read_response(&mut connection).await? {
+                Frame::Array(responses) => responses,
+                frame => vec![frame],
+            }
+        }
+        Mode::Single => {
+            let mut responses = Vec::with_capacity(commands.len());
+            for command in &commands {
+                let mut connection = connect(options, Mode::Single).await?;
+                connection.write_frame(command).await?;
+                responses.push(read_response(&mut connection).await?);
+            }
+            responses
+        }
+    };
+
+    for response in &responses {
+        println!("{}", render(response, options.full));
+    }
+    Ok(())
+}
+
+async fn connect(options: &Options, mode: Mode) -> rush::Result<Connection> {
+    let socket = TcpStream::connect(&options.addr).await?;
+    let mut connection = Connection::new(socket);
+    connection.write_frame(&Frame::Integer(mode as i64)).await?;
+
+    if let Some(token) = &options.token {
+        let auth = Frame::Array(vec![Frame::Simple("AUTH".into()), Frame::Bulk(Bytes::from(token.clone()))]);
+        connection.write_frame(&auth).await?;
+        if let Frame::Error(msg) = read_response(&mut connection).await? {
+            return Err(msg.into());
+        }
+    }
+    Ok(connection)
+}
+
+async fn read_response(connection: &mut Connection) -> rush::Result<Frame> {
+    match connection.read_frame().await? {
+        Some(frame) => Ok(frame),
+        None => Err("connection closed by server".into()),
+    }
+}
+
+fn parse_command(command: &str) -> rush::Result<Frame> {
+    let tokens = tokenize(command)?;
+    let name = tokens[0].to_uppercase();
+
+    if name == "CREATE" {
+        return parse_create(&tokens);
+    }
+
+    let arity = match &name[..] {
+        "PUT" | "GET" | "DELETE" => 3,
+        "TOPK" => 4,
+        "DROP" => 2,
+        "STATS" | "LIST" => 1,
+        _ => return Err(format!("unknown command {}", tokens[0]).into()),
+    };
+    if tokens.len() != arity {
+        return Err(format!("{} takes {} arguments", name, arity - 1).into());
+    }
+
+    let request = match &name[..] {
+        "PUT" => Request::put(&tokens[1], &parse_vector(&tokens[2])?),
+        "GET" => Request::get(&tokens[1], &parse_vector(&tokens[2])?),
+        "DELETE" => Request::delete(&tokens[1], &parse_vector(&tokens[2])?),
+        "TOPK" => {
+            let k = tokens[3].parse::<usize>().map_err(|_| format!("invalid neighbour count {}", tokens[3]))?;
+            Request::query_topk(&tokens[1], &parse_vector(&tokens[2])?, k)
+        }
+        "DROP" => Request::drop_dataset(&tokens[1]),
+        "LIST" => Request::ListDatasets,
+        // The client answers STATS with a call of its own rather than a
+        // `Request`, so it is sent as the bare command
+        _ => return Ok(Frame::Array(vec![Frame::Simple(name)])),
+    };
+    Ok(request.to_frame())
+}
+
+// CREATE takes a dimension followed by pairs of options, each an option name
+// and its value.
+fn parse_create(tokens: &[String]) -> rush::Result<Frame> {
+    if tokens.len() < 3 || tokens.len().is_multiple_of(2) {
+        return Err("CREATE takes a dataset, a dimension and pairs of options".into());
+    }
+
+    let integer = |token: &String| token.parse::<usize>().map_err(|_| format!("invalid integer {}", token));
+    let mut config = DatasetConfig::new(integer(&tokens[2])?);
+    for option in tokens[3..].chunks(2) {
+        config = match &option[0].to_uppercase()[..] {
+            "K" => config.with_hash_bits(integer(&option[1])?),
+            "L" => config.with_tables(integer(&option[1])?),
+            "METRIC" => config.with_metric(option[1].parse()?),
+            "HASH" => config.with_hash(option[1].parse()?),
+            "DENSITY" => config.with_density(option[1].parse().map_err(|_| format!("invalid density {}", option[1]))?),
+            _ => return Err(format!("unknown CREATE option {}", option[0]).into()),
+        };
+    }
+
+    Ok(Request::create_dataset(&tokens[1], config).to_frame())
+}
+
+// Splits on whitespace, except inside the brackets of a JSON array.
+fn tokenize(command: &str) -> rush::Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+
+    for c in command.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 0 => return Err("unbalanced ']'".into()),
+            ']' => depth -= 1,
+            _ => {}
+        }
+
+        if c.is_whitespace() && depth == 0 {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+        }
+        else {
+            current.push(c);
+        }
+    }
+
+    if depth > 0 {
+        return Err("unbalanced '['".into());
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    if tokens.is_empty() {
+        return Err("empty command".into());
+    }
+    Ok(tokens)
+}
+
+fn parse_vector(token: &str) -> rush::Result<Vec<f32>> {
+    let json = match token.strip_prefix('@') {
+        Some(path) => fs::read_to_string(path).map_err(|err| format!("can't read {}: {}", path, err))?,
+        None => token.to_string(),
+    };
+
+    let vector = serde_json::from_str(&json).
+        map_err(|err| format!("vectors must be JSON arrays of numbers: {}", err))?;
+    Ok(vector)
+}
+
+// Bulk strings sent by the server are float blobs, which are shown as arrays.
+fn render(frame: &Frame, full: bool) -> String {
+    let mut out = String::new();
+    render_into(&mut out, frame, 0, full);
+    out
+}
+
+fn render_into(out: &mut String, frame: &Frame, indent: usize, full: bool) {
+    match frame {
+        Frame::Simple(val) => out.push_str(val),
+        Frame::Error(msg) => { let _ = write!(out, "(error) {}", msg); }
+        Frame::Integer(val) => { let _ = write!(out, "(integer) {}", val); }
+        Frame::Null() => out.push_str("(nil)"),
+        Frame::Bulk(blob) => out.push_str(&render_blob(blob, full)),
+        Frame::Array(entries) if entries.is_empty() => out.push_str("(empty array)"),
+        Frame::Array(entries) => {
+            // Line up nested entries under the first one, like redis-cli does
+            let width = entries.len().to_string().len();
+            for (i, entry) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(indent));
+                }
+                let prefix = format!("{:>width$}) ", i + 1, width = width);
+                out.push_str(&prefix);
+                render_into(out, entry, indent + prefix.len(), full);
+            }
+        }
+        Frame::Double(val) => { let _ = write!(out, "(double) {}", val); }
+        Frame::Boolean(val) => { let _ = write!(out, "({})", val); }
+        Frame::BigNumber(val) => { let _ = write!(out, "(big number) {}", val); }
+        Frame::Map(pairs) if pairs.is_empty() => out.push_str("(empty hash)"),
+        Frame::Map(pairs) => {
+            let width = pairs.len().to_string().len();
+            for (i, (key, value)) in pairs.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(indent));
+                }
+                let _ = write!(out, "{:>width$}# {} => ", i + 1, key, width = width);
+                let offset = out.len() - out.rfind('\n').map_or(0, |newline| newline + 1);
+                render_into(out, value, offset, full);
+            }
+        }
+        // Attributes are metadata, so only the frame they describe is shown
+        Frame::Attribute(_, frame) => render_into(out, frame, indent, full),
+    }
+}
+
+fn render_blob(blob: &Bytes, full: bool) -> String {
+    // Previews of long vectors only show the leading elements
+    const PREVIEW: usize = 8;
+
+    if !blob.len().is_multiple_of(4) {
+        return format!("{:?}", blob);
+    }
+
+    let count = blob.len() / 4;
+    let mut reader = blob.clone();
+    let shown = if full { count } else { count.min(PREVIEW) };
+    let elements = (0..shown).
+        map(|_| format!("{}", reader.get_f32_le())).
+        collect::<Vec<String>>().
+        join(", ");
+
+    if shown < count {
+        format!("[{}, ... ({} elements)]", elements, count)
+    }
+    else {
+        format!("[{}]", elements)
+    }
+}
+
+#[cfg(test)]
+mod cli_test {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_keeps_json_arrays_together() {
+        let tokens = tokenize("PUT dataset [1, 2.5,  -3]").unwrap();
+        assert_eq!(tokens, vec!["PUT", "dataset", "[1, 2.5,  -3]"]);
+        assert!(tokenize("PUT dataset [1, 2").is_err());
+    }
+
+    #[test]
+    fn test_parse_topk_command() {
+        let frame = parse_command("topk ds [1, 2] 5").unwrap();
+        let parts = match frame {
+            Frame::Array(parts) => parts,
+            frame => panic!("unexpected frame {:?}", frame),
+        };
+
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "TOPK");
+        assert_eq!(parts[1], "ds");
+        match &parts[2] {
+            Frame::Bulk(blob) => assert_eq!(&blob[..], &[0, 0, 128, 63, 0, 0, 0, 64][..]),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+        assert!(matches!(parts[3], Frame::Integer(5)));
+        assert!(parse_command("TOPK ds [1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_parse_create_command() {
+        let frame = parse_command("create ds 16 l 8 metric cosine hash hadamard").unwrap();
+        let parts = match frame {
+            Frame::Array(parts) => parts,
+            frame => panic!("unexpected frame {:?}", frame),
+        };
+
+        // Options left out are sent with their defaults
+        assert_eq!(parts.len(), 11);
+        assert_eq!(parts[0], "CREATE");
+        assert_eq!(parts[1], "ds");
+        assert!(matches!(parts[2], Frame::Integer(16)));
+        assert_eq!(parts[3], "K");
+        assert!(matches!(parts[4], Frame::Integer(64)));
+        assert_eq!(parts[5], "L");
+        assert!(matches!(parts[6], Frame::Integer(8)));
+        assert_eq!(parts[8], "cosine");
+        assert_eq!(parts[10], "hadamard");
+        assert!(parse_command("CREATE ds 16 K").is_err());
+        assert!(parse_command("CREATE ds 16 X 1").is_err());
+        assert!(parse_command("CREATE ds 16 METRIC manhattan").is_err());
+    }
+
+    #[test]
+    fn test_render_nested_float_blobs() {
+        let blob = |elements: &[f32]| elements.iter().flat_map(|elt| elt.to_le_bytes()).collect::<Bytes>();
+        let neighbour = Frame::Array(vec![Frame::Bulk(blob(&[1f32, 2f32])), Frame::Bulk(blob(&[0.5f32]))]);
+        let rendered = render(&Frame::Array(vec![neighbour]), false);
+        assert_eq!(rendered, "1) 1) [1, 2]\n   2) [0.5]");
+
+        let long = Frame::Bulk(blob(&[1f32; 10]));
+        assert_eq!(render(&long, false), "[1, 1, 1, 1, 1, 1, 1, 1, ... (10 elements)]");
+    }
+
+    #[test]
+    fn test_render_resp3_map() {
+        let map = Frame::Map(vec![
+            (Frame::Simple("proto".into()), Frame::Integer(3)),
+            (Frame::Simple("distances".into()), Frame::Array(vec![Frame::Double(0.5), Frame::Double(1.5)])),
+        ]);
+        assert_eq!(render(&map, false), "1# proto => (integer) 3\n2# distances => 1) (double) 0.5\n                2) (double) 1.5");
+    }
+}
@@ -0,0 +1,468 @@
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time;
+use tracing::debug;
+use crate::net::{Connection, DatasetConfig, Frame, Mode, ServerError};
+use crate::net::blob::{decode_vector, encode_vector};
+
+mod pool;
+pub use pool::{Pool, PooledClient};
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Address of the rush server, e.g. "127.0.0.1:9090".
+    pub addr: String,
+    /// Upper bound on the time to connect, or to send a request and receive its response.
+    pub timeout: Duration,
+    /// How many times a request is retried on a fresh connection after the
+    /// current one fails.  Only requests that don't modify the server are
+    /// retried, since a write may have been applied before the failure.
+    pub reconnect_attempts: usize,
+    /// Token sent to servers that require authentication.
+    pub token: Option<String>,
+}
+
+impl ClientConfig {
+    pub fn new(addr: &str) -> Self {
+        ClientConfig {
+            addr: addr.to_string(),
+            timeout: Duration::from_secs(5),
+            reconnect_attempts: 1,
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Put { dataset: String, vector: Vec<f32> },
+    Get { dataset: String, vector: Vec<f32> },
+    TopK { dataset: String, vector: Vec<f32>, k: usize },
+    Delete { dataset: String, vector: Vec<f32> },
+    CreateDataset { dataset: String, config: DatasetConfig },
+    DropDataset { dataset: String },
+    ListDatasets,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Ok,
+    Vector(Option<Vec<f32>>),
+    Neighbours(Vec<Neighbour>),
+    Deleted(bool),
+    Datasets(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Neighbour {
+    pub vector: Vec<f32>,
+    pub distance: f32,
+}
+
+impl Request {
+    pub fn put(dataset: &str, vector: &[f32]) -> Request {
+        Request::Put { dataset: dataset.to_string(), vector: vector.to_vec() }
+    }
+
+    pub fn get(dataset: &str, vector: &[f32]) -> Request {
+        Request::Get { dataset: dataset.to_string(), vector: vector.to_vec() }
+    }
+
+    pub fn query_topk(dataset: &str, vector: &[f32], k: usize) -> Request {
+        Request::TopK { dataset: dataset.to_string(), vector: vector.to_vec(), k }
+    }
+
+    pub fn delete(dataset: &str, vector: &[f32]) -> Request {
+        Request::Delete { dataset: dataset.to_string(), vector: vector.to_vec() }
+    }
+
+    pub fn create_dataset(dataset: &str, config: DatasetConfig) -> Request {
+        Request::CreateDataset { dataset: dataset.to_string(), config }
+    }
+
+    pub fn drop_dataset(dataset: &str) -> Request {
+        Request::DropDataset { dataset: dataset.to_string() }
+    }
+
+    /// Whether the request modifies the database.
+    pub fn is_write(&self) -> bool {
+        matches!(self, Request::Put { .. } | Request::Delete { .. } | Request::CreateDataset { .. } | Request::DropDataset { .. })
+    }
+
+    /// The request as the command frame the server reads.
+    pub fn to_frame(&self) -> Frame {
+        let command = |name: &str, dataset: &str, vector: &[f32]| vec![
+            Frame::Simple(name.to_string()),
+            Frame::Simple(dataset.to_string()),
+            Frame::Bulk(encode_vector(vector.iter().copied())),
+        ];
+
+        Frame::Array(match self {
+            Request::Put { dataset, vector } => command("PUT", dataset, vector),
+            Request::Get { dataset, vector } => command("GET", dataset, vector),
+            Request::Delete { dataset, vector } => command("DELETE", dataset, vector),
+            Request::TopK { dataset, vector, k } => {
+                let mut frames = command("TOPK", dataset, vector);
+                frames.push(Frame::Integer(*k as i64));
+                frames
+            }
+            Request::CreateDataset { dataset, config } => {
+                let mut frames = vec![
+                    Frame::Simple("CREATE".into()),
+                    Frame::Simple(dataset.to_string()),
+                    Frame::Integer(config.dimension as i64),
+                    Frame::Simple("K".into()),
+                    Frame::Integer(config.hash_bits as i64),
+                    Frame::Simple("L".into()),
+                    Frame::Integer(config.tables as i64),
+                    Frame::Simple("METRIC".into()),
+                    Frame::Simple(config.metric.to_string()),
+                    Frame::Simple("HASH".into()),
+                    Frame::Simple(config.hash.to_string()),
+                ];
+                if let Some(density) = config.density {
+                    frames.push(Frame::Simple("DENSITY".into()));
+                    frames.push(Frame::Simple(density.to_string()));
+                }
+                frames
+            }
+            Request::DropDataset { dataset } => vec![Frame::Simple("DROP".into()), Frame::Simple(dataset.to_string())],
+            Request::ListDatasets => vec![Frame::Simple("LIST".into())],
+        })
+    }
+
+    pub(crate) fn parse_response(&self, frame: Frame) -> crate::Result<Response> {
+        if let Frame::Error(msg) = frame {
+            return Err(ServerError::from_frame(msg));
+        }
+
+        match (self, frame) {
+            (Request::Put { .. }, Frame::Simple(ref ok)) if ok == "OK" => Ok(Response::Ok),
+            (Request::CreateDataset { .. }, Frame::Simple(ref ok)) if ok == "OK" => Ok(Response::Ok),
+            (Request::DropDataset { .. }, Frame::Simple(ref ok)) if ok == "OK" => Ok(Response::Ok),
+            (Request::ListDatasets, Frame::Array(names)) => {
+                let names = names.
+                    into_iter().
+                    map(|name| match name {
+                        Frame::Simple(name) => Ok(name),
+                        frame => Err(frame.to_error()),
+                    }).
+                    collect::<crate::Result<Vec<String>>>()?;
+                Ok(Response::Datasets(names))
+            }
+            (Request::Get { .. }, Frame::Bulk(blob)) => Ok(Response::Vector(Some(decode_vector(blob)?))),
+            (Request::Get { .. }, Frame::Null()) => Ok(Response::Vector(None)),
+            (Request::Delete { .. }, Frame::Integer(removed)) => Ok(Response::Deleted(removed > 0)),
+            (Request::TopK { .. }, Frame::Array(entries)) => {
+                let neighbours = entries.
+                    into_iter().
+                    map(parse_neighbour).
+                    collect::<crate::Result<Vec<Neighbour>>>()?;
+                Ok(Response::Neighbours(neighbours))
+            }
+            (_, frame) => Err(frame.to_error()),
+        }
+    }
+}
+
+fn parse_neighbour(frame: Frame) -> crate::Result<Neighbour> {
+    let mut parts = match frame {
+        Frame::Array(parts) if parts.len() == 2 => parts.into_iter(),
+        frame => return Err(frame.to_error()),
+    };
+
+    match (parts.next(), parts.next()) {
+        (Some(Frame::Bulk(vector)), Some(Frame::Bulk(distance))) => {
+            let distance = match decode_vector(distance)?[..] {
+                [distance] => distance,
+                _ => return Err(ServerError::Protocol("expected a single f32 distance".into()).into()),
+            };
+            Ok(Neighbour { vector: decode_vector(vector)?, distance })
+        }
+        _ => Err(ServerError::Protocol("expected [vector, distance] pair".into()).into()),
+    }
+}
+
+/// An async client for the rush protocol.  Requests are sent over a single
+/// stream mode connection, which is re-established on demand if it breaks.
+pub struct Client {
+    config: ClientConfig,
+    connection: Option<Connection>,
+}
+
+impl Client {
+    pub async fn connect(addr: &str) -> crate::Result<Client> {
+        Client::connect_with_config(ClientConfig::new(addr)).await
+    }
+
+    pub async fn connect_with_config(config: ClientConfig) -> crate::Result<Client> {
+        let connection = open(&config, Mode::Stream).await?;
+        Ok(Client {
+            config,
+            connection: Some(connection),
+        })
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Whether the client currently holds an open connection.
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    pub async fn put(&mut self, dataset: &str, vector: &[f32]) -> crate::Result<()> {
+        match self.execute(Request::put(dataset, vector)).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// The approximate nearest neighbour of `vector`, if there are any candidates.
+    pub async fn get(&mut self, dataset: &str, vector: &[f32]) -> crate::Result<Option<Vec<f32>>> {
+        match self.execute(Request::get(dataset, vector)).await? {
+            Response::Vector(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Up to `k` approximate nearest neighbours of `vector`, nearest first.
+    pub async fn query_topk(&mut self, dataset: &str, vector: &[f32], k: usize) -> crate::Result<Vec<Neighbour>> {
+        match self.execute(Request::query_topk(dataset, vector, k)).await? {
+            Response::Neighbours(neighbours) => Ok(neighbours),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Returns whether `vector` was present in the dataset.
+    pub async fn delete(&mut self, dataset: &str, vector: &[f32]) -> crate::Result<bool> {
+        match self.execute(Request::delete(dataset, vector)).await? {
+            Response::Deleted(removed) => Ok(removed),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Create an empty dataset laid out as `config` describes.
+    pub async fn create_dataset(&mut self, dataset: &str, config: DatasetConfig) -> crate::Result<()> {
+        match self.execute(Request::create_dataset(dataset, config)).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Drop a dataset along with every vector in it.
+    pub async fn drop_dataset(&mut self, dataset: &str) -> crate::Result<()> {
+        match self.execute(Request::drop_dataset(dataset)).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// The names of every dataset on the server, in order.
+    pub async fn list_datasets(&mut self) -> crate::Result<Vec<String>> {
+        match self.execute(Request::ListDatasets).await? {
+            Response::Datasets(names) => Ok(names),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn execute(&mut self, request: Request) -> crate::Result<Response> {
+        let mut responses = self.pipeline(vec![request]).await?;
+        Ok(responses.remove(0))
+    }
+
+    /// Write every request before reading any of the responses, which come
+    /// back in the same order as the requests.  Very large pipelines should be
+    /// split up, since the server stops reading while its responses go unread.
+    pub async fn pipeline(&mut self, requests: Vec<Request>) -> crate::Result<Vec<Response>> {
+        let frames = requests.iter().map(Request::to_frame).collect::<Vec<Frame>>();
+        let read_only = !requests.iter().any(Request::is_write);
+        let mut retries = 0;
+        loop {
+            match self.roundtrip(&frames).await {
+                Ok(responses) => {
+                    // The server hangs up after a protocol error, so a
+                    // connection that answered with one isn't reused.  Other
+                    // errors only fail the request that caused them.
+                    if responses.iter().any(is_protocol_error) {
+                        self.connection = None;
+                    }
+                    return parse_responses(&requests, responses);
+                }
+                Err(failure) => self.retry_or_fail(failure, read_only, &mut retries)?,
+            }
+        }
+    }
+
+    /// Send all the requests as a single bulk mode message.  The server runs
+    /// them concurrently and answers once they have all completed.
+    pub async fn bulk(&mut self, requests: Vec<Request>) -> crate::Result<Vec<Response>> {
+        let frame = Frame::Array(requests.iter().map(Request::to_frame).collect());
+        let read_only = !requests.iter().any(Request::is_write);
+        let mut retries = 0;
+        let response = loop {
+            match self.bulk_roundtrip(&frame).await {
+                Ok(response) => break response,
+                Err(failure) => self.retry_or_fail(failure, read_only, &mut retries)?,
+            }
+        };
+
+        match response {
+            Frame::Array(responses) if responses.len() == requests.len() => parse_responses(&requests, responses),
+            Frame::Error(msg) => Err(ServerError::from_frame(msg)),
+            frame => Err(frame.to_error()),
+        }
+    }
+
+    /// The server's counters, as (name, value) pairs.
+    pub async fn stats(&mut self) -> crate::Result<Vec<(String, u64)>> {
+        let frames = vec![Frame::Array(vec![Frame::Simple("STATS".into())])];
+        let mut retries = 0;
+        let mut responses = loop {
+            match self.roundtrip(&frames).await {
+                Ok(responses) => break responses,
+                Err(failure) => self.retry_or_fail(failure, true, &mut retries)?,
+            }
+        };
+
+        let mut stats = Vec::new();
+        let mut it = match responses.remove(0) {
+            Frame::Array(entries) => entries.into_iter(),
+            frame => return Err(frame.to_error()),
+        };
+        while let (Some(name), Some(value)) = (it.next(), it.next()) {
+            match (name, value) {
+                (Frame::Simple(name), Frame::Integer(value)) => stats.push((name, value as u64)),
+                (name, _) => return Err(name.to_error()),
+            }
+        }
+        Ok(stats)
+    }
+
+    // Decides whether a failed request should be retried on a new connection.
+    // Timeouts aren't retried, since the server is most likely still busy, and
+    // neither are writes, since the server may have applied them before the
+    // connection failed and sending them again would apply them twice.
+    fn retry_or_fail(&self, failure: Failure, read_only: bool, retries: &mut usize) -> crate::Result<()> {
+        match failure {
+            Failure::Timeout => Err("request timed out".into()),
+            Failure::Transport(err) if !read_only || *retries >= self.config.reconnect_attempts => Err(err),
+            Failure::Transport(err) => {
+                debug!(cause = %err, "reconnecting after request failure");
+                *retries += 1;
+                Ok(())
+            }
+        }
+    }
+
+    async fn roundtrip(&mut self, frames: &[Frame]) -> Result<Vec<Frame>, Failure> {
+        let timeout = self.config.timeout;
+        let result = time::timeout(timeout, async {
+            let connection = self.connection().await?;
+            for frame in frames {
+                connection.write_frame(frame).await?;
+            }
+
+            let mut responses = Vec::with_capacity(frames.len());
+            for _ in frames {
+                match connection.read_frame().await? {
+                    Some(frame) => responses.push(frame),
+                    None => return Err("connection closed by server".into()),
+                }
+            }
+            Ok(responses)
+        }).await;
+
+        self.settle(result)
+    }
+
+    async fn bulk_roundtrip(&mut self, frame: &Frame) -> Result<Frame, Failure> {
+        // The server closes a bulk mode connection once it has responded, so
+        // every bulk request gets a connection of its own.
+        let config = self.config.clone();
+        let result = time::timeout(config.timeout, async {
+            let mut connection = open(&config, Mode::Bulk).await?;
+            connection.write_frame(frame).await?;
+            match connection.read_frame().await? {
+                Some(frame) => Ok(frame),
+                None => Err("connection closed by server".into()),
+            }
+        }).await;
+
+        match result {
+            Ok(Ok(frame)) => Ok(frame),
+            Ok(Err(err)) => Err(Failure::Transport(err)),
+            Err(_) => Err(Failure::Timeout),
+        }
+    }
+
+    // A request that failed or timed out leaves the connection in an unknown
+    // state, so it is dropped and a new one is opened by the next request.
+    fn settle<T>(&mut self, result: Result<crate::Result<T>, time::error::Elapsed>) -> Result<T, Failure> {
+        match result {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(err)) => {
+                self.connection = None;
+                Err(Failure::Transport(err))
+            }
+            Err(_) => {
+                self.connection = None;
+                Err(Failure::Timeout)
+            }
+        }
+    }
+
+    async fn connection(&mut self) -> crate::Result<&mut Connection> {
+        if self.connection.is_none() {
+            self.connection = Some(open(&self.config, Mode::Stream).await?);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+}
+
+enum Failure {
+    Timeout,
+    Transport(crate::Error),
+}
+
+async fn open(config: &ClientConfig, mode: Mode) -> crate::Result<Connection> {
+    let socket = match time::timeout(config.timeout, TcpStream::connect(&config.addr)).await {
+        Ok(socket) => socket?,
+        Err(_) => return Err("timed out connecting to server".into()),
+    };
+    socket.set_nodelay(true)?;
+
+    let mut connection = Connection::new(socket);
+    connection.write_frame(&Frame::Integer(mode as i64)).await?;
+
+    if let Some(token) = &config.token {
+        let auth = Frame::Array(vec![Frame::Simple("AUTH".into()), Frame::Bulk(token.clone().into())]);
+        connection.write_frame(&auth).await?;
+        match time::timeout(config.timeout, connection.read_frame()).await {
+            Ok(Ok(Some(Frame::Simple(_)))) => {}
+            Ok(Ok(Some(Frame::Error(msg)))) => return Err(ServerError::from_frame(msg)),
+            Ok(Ok(Some(frame))) => return Err(format!("unexpected response to AUTH: {}", frame).into()),
+            Ok(Ok(None)) => return Err("connection closed during authentication".into()),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Err("timed out authenticating with server".into()),
+        }
+    }
+    Ok(connection)
+}
+
+fn parse_responses(requests: &[Request], responses: Vec<Frame>) -> crate::Result<Vec<Response>> {
+    requests.
+        iter().
+        zip(responses).
+        map(|(request, frame)| request.parse_response(frame)).
+        collect()
+}
+
+fn is_protocol_error(frame: &Frame) -> bool {
+    matches!(frame, Frame::Error(msg) if matches!(ServerError::parse(msg), Some(ServerError::Protocol(_))))
+}
+
+fn unexpected(response: Response) -> crate::Error {
+    format!("unexpected response: {:?}", response).into()
+}
@@ -0,0 +1,77 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use crate::client::{Client, ClientConfig};
+
+/// A fixed size pool of clients connected to the same server.  Connections
+/// are opened lazily and reused once the `PooledClient` holding them is dropped.
+pub struct Pool {
+    config: ClientConfig,
+    idle: Mutex<Vec<Client>>,
+    limiter: Semaphore,
+}
+
+/// A client checked out of a `Pool`.
+pub struct PooledClient<'a> {
+    client: Option<Client>,
+    pool: &'a Pool,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Pool {
+    pub fn new(config: ClientConfig, max_size: usize) -> Self {
+        Pool {
+            config,
+            idle: Mutex::new(Vec::with_capacity(max_size)),
+            limiter: Semaphore::new(max_size),
+        }
+    }
+
+    /// Waits for a free slot in the pool, then hands out an idle client or
+    /// connects a new one.
+    pub async fn get(&self) -> crate::Result<PooledClient<'_>> {
+        let permit = self.limiter.acquire().await?;
+
+        let idle = self.idle.lock().unwrap().pop();
+        let client = match idle {
+            Some(client) => client,
+            None => Client::connect_with_config(self.config.clone()).await?,
+        };
+
+        Ok(PooledClient {
+            client: Some(client),
+            pool: self,
+            _permit: permit,
+        })
+    }
+
+    /// The number of open connections waiting to be handed out.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        // Clients whose connection broke aren't worth keeping around
+        if let Some(client) = self.client.take() {
+            if client.is_connected() {
+                self.pool.idle.lock().unwrap().push(client);
+            }
+        }
+    }
+}
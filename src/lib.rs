@@ -1,6 +1,7 @@
 pub mod lsh;
 pub mod simd;
 pub mod net;
+pub mod client;
 //mod expressionlib;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// Tells long running work that its result is no longer wanted, because it
+/// was cancelled or because it ran past its deadline.  Clones share the same
+/// flag, so cancelling any of them cancels them all.  Cancellation is
+/// cooperative: the work has to check `is_cancelled` and stop by itself.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// A token sharing this one's flag that also fires at `deadline`.
+    pub fn with_deadline(&self, deadline: Option<Instant>) -> Self {
+        CancelToken { cancelled: self.cancelled.clone(), deadline }
+    }
+
+    /// A guard that cancels the token when dropped, for work that should stop
+    /// once whoever was waiting on it goes away.
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || matches!(self.deadline, Some(deadline) if Instant::now() >= deadline)
+    }
+}
+
+/// Cancels its token when dropped.  See `CancelToken::cancel_on_drop`.
+#[derive(Debug)]
+pub struct CancelOnDrop(CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
@@ -0,0 +1,107 @@
+use serde::Deserialize;
+use std::fmt;
+use crate::lsh::{CancelToken, HashFamily, Metric};
+
+pub trait Database {
+    type Item;
+    /// An empty database laid out as `config` describes.
+    fn create(config: &DatasetConfig) -> crate::Result<Self> where Self: Sized;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn insert(&mut self, item: Self::Item) -> crate::Result<()>;
+    /// Remove `item` from the database, returning whether it was present.
+    fn delete(&mut self, item: &Self::Item) -> bool;
+    fn query<'a>(&'a self, item: &Self::Item) -> Option<&'a Self::Item>;
+    /// The (approximate) `k` nearest neighbours of `item`, nearest first.
+    fn query_topk<'a>(&'a self, item: &Self::Item, k: usize) -> Vec<&'a Self::Item>;
+    /// Like `query_topk`, but gives up with `None` once `cancel` is cancelled.
+    /// Databases that can stop part way through a query should override this.
+    fn query_topk_cancellable<'a>(&'a self, item: &Self::Item, k: usize, cancel: &CancelToken) -> Option<Vec<&'a Self::Item>> {
+        if cancel.is_cancelled() {
+            return None;
+        }
+        Some(self.query_topk(item, k))
+    }
+}
+
+/// How a dataset lays out its database.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DatasetConfig {
+    /// Number of elements in every vector.
+    pub dimension: usize,
+    /// Bits in each hash, K.  More bits make buckets smaller and more precise.
+    #[serde(default = "default_hash_bits")]
+    pub hash_bits: usize,
+    /// Number of hash tables, L.  More tables find more of the true neighbours.
+    #[serde(default = "default_tables")]
+    pub tables: usize,
+    #[serde(default)]
+    pub metric: Metric,
+    /// How the tables hash vectors.
+    #[serde(default)]
+    pub hash: HashFamily,
+    /// Fraction of the elements of sparse projections that are nonzero,
+    /// 1 / sqrt(dimension) unless given.
+    #[serde(default)]
+    pub density: Option<f32>,
+}
+
+fn default_hash_bits() -> usize {
+    64
+}
+
+fn default_tables() -> usize {
+    32
+}
+
+impl DatasetConfig {
+    pub fn new(dimension: usize) -> Self {
+        DatasetConfig {
+            dimension,
+            hash_bits: default_hash_bits(),
+            tables: default_tables(),
+            metric: Metric::default(),
+            hash: HashFamily::default(),
+            density: None,
+        }
+    }
+
+    pub fn with_hash_bits(mut self, hash_bits: usize) -> Self {
+        self.hash_bits = hash_bits;
+        self
+    }
+
+    pub fn with_tables(mut self, tables: usize) -> Self {
+        self.tables = tables;
+        self
+    }
+
+    pub fn with_metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    pub fn with_hash(mut self, hash: HashFamily) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    pub fn with_density(mut self, density: f32) -> Self {
+        self.density = Some(density);
+        self
+    }
+}
+
+/// A layout, or a set of trained projections, that a database can't use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError(pub String);
+
+impl std::error::Error for ValidationError {}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.0)
+    }
+}
@@ -0,0 +1,162 @@
+use crate::lsh::vector::Vector;
+use crate::simd::dispatch;
+use rand::Rng;
+use rand::seq::index;
+use std::marker::PhantomData;
+use std::vec::Vec;
+
+/*
+
+A hash built on subsampled randomized Hadamard transforms.  A vector is padded
+with zeros to a power of two width, then mixed by flipping the sign of each
+element at random and taking the Walsh-Hadamard transform.  Each element of
+the result is then the inner product with a direction of random signs, and the
+hash keeps the signs of a random sample of them, as the dense hash keeps the
+signs of its projections.
+
+One round leaves sparse vectors poorly mixed: every element of the transform
+of a basis vector has the same magnitude, so the sampled signs would all come
+out alike.  As with the cross-polytope hashes of Andoni et al., three rounds
+are enough for the result to behave like a gaussian projection.
+
+A hash wider than the padded vector takes its bits from several independent
+transforms.  The functions keep only their random signs and samples, and cost
+3 log2(width) additions per element instead of one multiply-add per element
+per bit.
+
+*/
+
+const ROUNDS: usize = 3;
+
+// Longest set of transforms hashed without allocating, 4 KB of stack
+const STACK_SCRATCH: usize = 1024;
+
+pub struct HadamardHashFunction<T>
+where
+    T: Vector<DType=f32>,
+    for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+{
+    // ROUNDS sign flips for each transform, one after another
+    signs: Vec<f32>,
+    // Elements of the transforms, laid end to end, whose signs become the bits
+    samples: Vec<usize>,
+    dimension: usize,
+    width: usize,
+    marker: PhantomData<fn(&T)>
+}
+
+impl<T> HadamardHashFunction<T>
+where
+    T: Vector<DType=f32>,
+    for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+{
+    pub fn new(bits: usize, dimension: usize) -> Self {
+        HadamardHashFunction::with_rng(bits, dimension, &mut rand::thread_rng())
+    }
+
+    /// Like `new`, drawing the signs and samples from `rng`.
+    pub fn with_rng(bits: usize, dimension: usize, rng: &mut impl Rng) -> Self {
+        assert!(bits <= 64, "a hash has at most 64 bits");
+        let width = dimension.next_power_of_two();
+        let transforms = bits.div_ceil(width);
+
+        let signs = (0..transforms * ROUNDS * width).
+            map(|_| if rng.gen::<bool>() { 1f32 } else { -1f32 }).
+            collect::<Vec<f32>>();
+        let samples = index::sample(rng, transforms * width, bits).into_vec();
+        HadamardHashFunction { signs, samples, dimension, width, marker: PhantomData }
+    }
+
+    pub fn hash(&self, v: &T) -> u64 {
+        // Most vectors fit a buffer on the stack, and only very wide ones
+        // allocate
+        let len = self.signs.len() / ROUNDS;
+        if len <= STACK_SCRATCH {
+            self.hash_with(v, &mut [0f32; STACK_SCRATCH][..len])
+        } else {
+            self.hash_with(v, &mut vec![0f32; len])
+        }
+    }
+
+    // Hashes `v` using `mixed`, zeroed and as long as the transforms laid
+    // end to end, as scratch space.
+    fn hash_with(&self, v: &T, mixed: &mut [f32]) -> u64 {
+        let x = &v.as_slice()[..self.dimension];
+        for (transform, signs) in mixed.chunks_exact_mut(self.width).zip(self.signs.chunks_exact(ROUNDS * self.width)) {
+            transform[..self.dimension].copy_from_slice(x);
+            for round in signs.chunks_exact(self.width) {
+                for (elt, sign) in transform.iter_mut().zip(round.iter()) {
+                    *elt *= sign;
+                }
+                dispatch::fwht(transform);
+            }
+        }
+
+        self.samples.
+            iter().
+            enumerate().
+            fold(0u64, |acc, (i, sample)| acc | (((mixed[*sample] > 0f32) as u64) << i))
+    }
+}
+
+#[cfg(test)]
+mod hadamard_hash_test {
+    use super::*;
+    use crate::simd::vec::{SimdVecImpl, SimdVector};
+    use crate::simd::f32x4;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_hash_is_invariant_to_scale() {
+        // Wide enough vectors hash in a buffer on the heap
+        for dim in [100, 2 * STACK_SCRATCH].iter() {
+            let f = HadamardHashFunction::<SimdVector<f32x4>>::new(64, *dim);
+            let v = (0..*dim).map(|i| (i as f32 * 0.7).sin()).collect::<SimdVector<f32x4>>();
+            let scaled = (&v).into_iter().map(|x| 4f32 * x).collect::<SimdVector<f32x4>>();
+            let opposite = (&v).into_iter().map(|x| -x).collect::<SimdVector<f32x4>>();
+
+            assert_eq!(f.hash(&v), f.hash(&scaled));
+            assert_eq!(f.hash(&v), !f.hash(&opposite));
+        }
+    }
+
+    #[test]
+    fn test_hash_narrower_than_bits() {
+        // Sixty four bits of a four dimensional vector come from sixteen
+        // transforms
+        let f = HadamardHashFunction::<SimdVecImpl<f32x4, 1>>::new(64, 4);
+        assert_eq!(f.signs.len(), 16 * ROUNDS * 4);
+
+        let mut samples = f.samples.clone();
+        samples.sort_unstable();
+        samples.dedup();
+        assert_eq!(samples.len(), 64);
+
+        let v = vec![1f32, 0f32, 0f32, 0f32].into_iter().collect::<SimdVecImpl<f32x4, 1>>();
+        let ones = f.hash(&v).count_ones();
+        assert!(ones > 8 && ones < 56, "{} bits set", ones);
+    }
+
+    #[test]
+    fn test_collisions_follow_angle() {
+        // Bits differ with probability close to angle / pi, as for the dense
+        // hash, including for a pair of basis vectors
+        let mut rng = StdRng::seed_from_u64(48);
+        let dim = 96;
+        let basis = |idx: usize| (0..dim).map(|i| if i == idx { 1f32 } else { 0f32 }).collect::<SimdVector<f32x4>>();
+        let dense = (0..dim).map(|_| rng.gen_range(-1f32..1f32)).collect::<SimdVector<f32x4>>();
+        let nearby = (&dense).into_iter().map(|x| x + rng.gen_range(-0.3f32..0.3f32)).collect::<SimdVector<f32x4>>();
+
+        for (a, b) in [(basis(0), basis(1)), (dense.clone(), nearby)].iter() {
+            let angle = (a.dot(b) / (a.dot(a) * b.dot(b)).sqrt()).acos();
+            let differing = (0..100).
+                map(|_| {
+                    let f = HadamardHashFunction::<SimdVector<f32x4>>::with_rng(64, dim, &mut rng);
+                    (f.hash(a) ^ f.hash(b)).count_ones() as f32
+                }).
+                sum::<f32>() / 6400f32;
+            assert!((differing - angle / std::f32::consts::PI).abs() < 0.05, "{} bits differ at angle {}", differing, angle);
+        }
+    }
+}
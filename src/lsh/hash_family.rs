@@ -0,0 +1,90 @@
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use crate::lsh::hadamard_hash::HadamardHashFunction;
+use crate::lsh::sparse_hash::SparseHashFunction;
+use crate::lsh::stable_hash::StableHashFunction;
+use crate::lsh::vector::Vector;
+
+/// How the tables of a dataset hash its vectors.  All keep the signs of
+/// random projections, so they collide alike; they differ in what they cost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashFamily {
+    /// A dense random projection per bit, K * d floats for each table.
+    #[default]
+    Dense,
+    /// Randomized Hadamard transforms, a few sign flips for each table and
+    /// O(d log d) work per hash.
+    Hadamard,
+    /// Projections of -1, 0 and +1 stored by their nonzeros, for vectors too
+    /// wide for dense ones.
+    Sparse,
+}
+
+impl HashFamily {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashFamily::Dense => "dense",
+            HashFamily::Hadamard => "hadamard",
+            HashFamily::Sparse => "sparse",
+        }
+    }
+}
+
+impl FromStr for HashFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "dense" => Ok(HashFamily::Dense),
+            "hadamard" => Ok(HashFamily::Hadamard),
+            "sparse" => Ok(HashFamily::Sparse),
+            _ => Err(format!("unknown hash family '{}'; expected dense, hadamard or sparse", s)),
+        }
+    }
+}
+
+impl fmt::Display for HashFamily {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.name().fmt(fmt)
+    }
+}
+
+/// A hash function drawn from one of the families.
+pub enum HashFunction<T>
+where
+    T: Vector<DType=f32>,
+    for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+{
+    Dense(StableHashFunction<T>),
+    Hadamard(HadamardHashFunction<T>),
+    Sparse(SparseHashFunction<T>),
+}
+
+impl<T> HashFunction<T>
+where
+    T: Vector<DType=f32>,
+    for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+{
+    /// A function of `bits` bits from `family`.  The density only applies to
+    /// sparse projections, and defaults to 1 / sqrt(dimension).
+    pub fn new(family: HashFamily, bits: usize, dimension: usize, density: Option<f32>) -> Self {
+        match family {
+            HashFamily::Dense => HashFunction::Dense(StableHashFunction::new(bits, dimension)),
+            HashFamily::Hadamard => HashFunction::Hadamard(HadamardHashFunction::new(bits, dimension)),
+            HashFamily::Sparse => {
+                let density = density.unwrap_or_else(|| SparseHashFunction::<T>::default_density(dimension));
+                HashFunction::Sparse(SparseHashFunction::new(bits, dimension, density))
+            }
+        }
+    }
+
+    pub fn hash(&self, v: &T) -> u64 {
+        match self {
+            HashFunction::Dense(hashfn) => hashfn.hash(v),
+            HashFunction::Hadamard(hashfn) => hashfn.hash(v),
+            HashFunction::Sparse(hashfn) => hashfn.hash(v),
+        }
+    }
+}
@@ -1,10 +1,17 @@
+use std::cmp::Ordering;
 use std::collections::{HashSet, HashMap};
 use std::hash::{Hash, Hasher};
 use std::vec::Vec;
 use std::sync::Arc;
 use crate::lsh::vector::Vector;
-use crate::lsh::stable_hash::StableHashFunction;
-use crate::net::Database; 
+use crate::lsh::metric::Metric;
+use crate::lsh::hash_family::{HashFamily, HashFunction};
+use crate::lsh::training::TrainedProjections;
+use crate::lsh::cancel::CancelToken;
+use crate::lsh::database::{Database, DatasetConfig, ValidationError};
+
+// Number of candidates scored between checks for cancellation
+const SCORING_BATCH_SIZE: usize = 256;
 
 pub trait Cacheable {
     fn cache_id(&self) -> u128;
@@ -45,7 +52,7 @@ where
     for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
 {
     table: HashMap<u64, HashSet<Arc<CacheItem<T>>>>,
-    hashfn: StableHashFunction<T>
+    hashfn: HashFunction<T>
 }
 
 impl<T: Vector<DType=f32>> LocalitySensitiveHashTable<T> 
@@ -53,10 +60,10 @@ where
     T: Vector<DType=f32> + Cacheable,
     for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
 {
-    fn new(dimension: usize) -> Self {
+    fn new(family: HashFamily, bits: usize, dimension: usize, density: Option<f32>) -> Self {
         LocalitySensitiveHashTable {
             table: HashMap::<u64, HashSet<Arc<CacheItem<T>>>>::new(),
-            hashfn: StableHashFunction::<T>::new(64, dimension)
+            hashfn: HashFunction::<T>::new(family, bits, dimension, density)
         }
     }
 
@@ -72,9 +79,19 @@ where
         }
     }
 
+    fn remove(&mut self, item: &T, id: u128) {
+        let lsh_key = self.hashfn.hash(item);
+        if let Some(container) = self.table.get_mut(&lsh_key) {
+            container.retain(|x| x.hash != id);
+            if container.is_empty() {
+                self.table.remove(&lsh_key);
+            }
+        }
+    }
+
     fn query_set<'a>(&'a self, item: &T) -> Option<&'a HashSet<Arc<CacheItem<T>>>> {
         let key = &self.hashfn.hash(item);
-        self.table.get(&key)
+        self.table.get(key)
     }
 }
 
@@ -83,8 +100,11 @@ where
     T: Vector<DType=f32> + Cacheable,
     for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
 {
-    items: HashSet<Arc<CacheItem<T>>>,
-    tables: Vec<LocalitySensitiveHashTable<T>>
+    items: HashMap<u128, Arc<CacheItem<T>>>,
+    tables: Vec<LocalitySensitiveHashTable<T>>,
+    bits: usize,
+    dimension: usize,
+    metric: Metric
 }
 
 impl<T> Database for LocalitySensitiveHashDatabase<T>
@@ -94,63 +114,97 @@ where
 {
     type Item = T;
 
+    fn create(config: &DatasetConfig) -> crate::Result<Self> {
+        // The hash of an item is kept in a u64
+        if config.hash_bits == 0 || config.hash_bits > 64 {
+            return Err(ValidationError("K must be between 1 and 64".into()).into());
+        }
+        if config.tables == 0 {
+            return Err(ValidationError("L must be at least 1".into()).into());
+        }
+        if config.dimension == 0 {
+            return Err(ValidationError("dimension must be positive".into()).into());
+        }
+        match config.density {
+            Some(_) if config.hash != HashFamily::Sparse => {
+                return Err(ValidationError("DENSITY only applies to sparse hashes".into()).into());
+            }
+            Some(density) if !(density > 0f32 && density <= 1f32) => {
+                return Err(ValidationError("DENSITY must be greater than 0 and at most 1".into()).into());
+            }
+            _ => {}
+        }
+
+        // Vectors sized at compile time only come in one dimension
+        let dimension = (0..config.dimension).map(|_| 0f32).collect::<T>().dimension();
+        if config.dimension != dimension {
+            return Err(ValidationError(format!("dimension must be {} on this server", dimension)).into());
+        }
+
+        Ok(LocalitySensitiveHashDatabase::with_hashes(config.tables, config.hash_bits, dimension, config.metric, config.hash, config.density))
+    }
+
     fn len(&self) -> usize {
         self.items.len()    
     }
     
     fn insert(&mut self, item: T) -> crate::Result<()> {
         let value = Arc::new( CacheItem::new(item));
-        self.items.insert(Arc::clone(&value));
+        self.items.insert(value.hash, Arc::clone(&value));
         for table in self.tables.iter_mut() {
             table.insert(Arc::clone(&value));
         }
         Ok(())
     }
+
+    fn delete(&mut self, item: &T) -> bool {
+        let id = item.cache_id();
+        if self.items.remove(&id).is_none() {
+            return false;
+        }
+        for table in self.tables.iter_mut() {
+            table.remove(item, id);
+        }
+        true
+    }
     
     fn query<'a>(&'a self, item: &T) -> Option<&'a T> {
-        let empty = HashSet::<Arc<CacheItem<T>>>::new();
+        self.query_topk(item, 1).into_iter().next()
+    }
+
+    fn query_topk<'a>(&'a self, item: &T, k: usize) -> Vec<&'a T> {
+        self.query_topk_cancellable(item, k, &CancelToken::new()).unwrap_or_default()
+    }
+
+    fn query_topk_cancellable<'a>(&'a self, item: &T, k: usize, cancel: &CancelToken) -> Option<Vec<&'a T>> {
         // We deduplicate the results returned from each replica before
-        // computing Euclidian distances to find the nearest neighbour
+        // computing distances to find the nearest neighbours
         let candidates = self.tables.
             iter().
-            flat_map(|table| {
-                match table.query_set(item) {
-                    None => empty.iter(),
-                    Some(items) => items.iter() 
-                }
-            }).
-            cloned().
-            collect::<HashSet<Arc<CacheItem<T>>>>();
+            filter_map(|table| table.query_set(item)).
+            flatten().
+            collect::<HashSet<&'a Arc<CacheItem<T>>>>().
+            into_iter().
+            collect::<Vec<&'a Arc<CacheItem<T>>>>();
         
-        let maybe_nearest_neighbour = 
-            candidates.
-                iter().
-                map(|x| (item.distance(&x.value), x)).
-                fold(None, |acc, (d, v)| {
-                    match acc {
-                        None => Some((d, Arc::clone(v))), 
-                        Some((min, w)) => {
-                            if d < min { 
-                                Some((d, Arc::clone(v)))
-                            }
-                            else {
-                                Some((min, w))
-                            }
-                        }
-                    }
-                });
-
-        match maybe_nearest_neighbour {
-            Some((_, neighbour)) => {
-                if let Some(result) = self.items.get(&neighbour) {
-                    Some(&result.value)
-                }
-                else {
-                    None
-                }
-            },
-            None => None
+        // Scoring is where the time goes, so check between batches whether
+        // anyone still wants the answer
+        let mut neighbours = Vec::with_capacity(candidates.len());
+        for batch in candidates.chunks(SCORING_BATCH_SIZE) {
+            if cancel.is_cancelled() {
+                return None;
+            }
+            neighbours.extend(batch.iter().map(|x| (self.metric.distance(item, &x.value), &x.value)));
+        }
+
+        let by_distance = |a: &(f32, &T), b: &(f32, &T)| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal);
+        if k < neighbours.len() {
+            neighbours.select_nth_unstable_by(k, by_distance);
+            neighbours.truncate(k);
         }
+        neighbours.sort_unstable_by(by_distance);
+
+        Some(neighbours.into_iter().map(|(_, value)| value).collect())
     }
 }
 
@@ -160,13 +214,46 @@ where
     for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
 {
     pub fn new(replicas: usize, dimension: usize) -> Self {
+        LocalitySensitiveHashDatabase::with_hashes(replicas, 64, dimension, Metric::Euclidean, HashFamily::Dense, None)
+    }
+
+    /// A database of `replicas` tables, each keyed by a hash of `bits` bits
+    /// drawn from `family`, with projections of the given density if sparse.
+    pub fn with_hashes(replicas: usize, bits: usize, dimension: usize, metric: Metric, family: HashFamily, density: Option<f32>) -> Self {
         LocalitySensitiveHashDatabase {
-            items: HashSet::<Arc<CacheItem<T>>>::new(),
+            items: HashMap::<u128, Arc<CacheItem<T>>>::new(),
             tables: (0..replicas).
-                map(|_| LocalitySensitiveHashTable::<T>::new(dimension)).
-                collect::<Vec<LocalitySensitiveHashTable<T>>>()
+                map(|_| LocalitySensitiveHashTable::<T>::new(family, bits, dimension, density)).
+                collect::<Vec<LocalitySensitiveHashTable<T>>>(),
+            bits,
+            dimension,
+            metric
         }
     }
+
+    /// Hashes each table with its own trained projections in place of random
+    /// ones, and rehashes everything already stored.
+    pub fn use_trained_projections(&mut self, trained: &[TrainedProjections<T>]) -> crate::Result<()> {
+        if trained.len() != self.tables.len() {
+            return Err(ValidationError(format!("expected {} sets of projections, one per table", self.tables.len())).into());
+        }
+        if trained.iter().any(|t| t.projections().len() != self.bits) {
+            return Err(ValidationError(format!("expected {} projections for each table, one per bit", self.bits)).into());
+        }
+        let mut dimensions = trained.iter().flat_map(|t| t.projections().iter().map(|proj| proj.direction().dimension()));
+        if dimensions.any(|dimension| dimension != self.dimension) {
+            return Err(ValidationError(format!("projections must have dimension {}", self.dimension)).into());
+        }
+
+        for (table, projections) in self.tables.iter_mut().zip(trained.iter()) {
+            table.hashfn = HashFunction::Dense(projections.hash_function());
+            table.table.clear();
+            for item in self.items.values() {
+                table.insert(Arc::clone(item));
+            }
+        }
+        Ok(())
+    }
 }
 
 
@@ -174,11 +261,11 @@ where
 mod lsh_database_test {
     use super::*;
     use crate::simd::vec::SimdVecImpl;
-    use crate::simd::sse::f32x4;
+    use crate::simd::{Backend, f32x4, f32x8};
     
     #[test]
     fn test_lsh_table_insert() {
-        let mut table = LocalitySensitiveHashTable::<SimdVecImpl<f32x4, 4>>::new(16);
+        let mut table = LocalitySensitiveHashTable::<SimdVecImpl<f32x4, 4>>::new(HashFamily::Dense, 64, 16, None);
         
         // These two items must necessarily hash to two separate values, 
         // as they point in opposite directions
@@ -198,7 +285,7 @@ mod lsh_database_test {
     
     #[test]
     fn test_lsh_table_query() {
-        let mut table = LocalitySensitiveHashTable::<SimdVecImpl<f32x4, 4>>::new(16);
+        let mut table = LocalitySensitiveHashTable::<SimdVecImpl<f32x4, 4>>::new(HashFamily::Dense, 64, 16, None);
         
         // The next two items will hash to the same value because they are colinear.
         let item1 = Arc::new(
@@ -246,9 +333,9 @@ mod lsh_database_test {
         // This item will go to its own entry
         let item3 = vec![-1f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>();
 
-        db.insert(item1);
-        db.insert(item2);
-        db.insert(item3);
+        db.insert(item1).unwrap();
+        db.insert(item2).unwrap();
+        db.insert(item3).unwrap();
 
         let mut qtemp = vec![1f32; 16];
         qtemp[0] = 0.99f32;
@@ -258,4 +345,128 @@ mod lsh_database_test {
         
         assert_eq!(*q_result, item1_copy);
     }
+
+    #[test]
+    fn test_lshdb_query_topk() {
+        let mut db = LocalitySensitiveHashDatabase::<SimdVecImpl<f32x4, 4>>::new(32, 16);
+
+        for scale in [1f32, 1.5, 2f32, -1f32].iter() {
+            db.insert(vec![*scale; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>()).unwrap();
+        }
+
+        let q = vec![1.1f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>();
+        let result = db.query_topk(&q, 2);
+
+        // The neighbours come back nearest first, and never include the item
+        // pointing the opposite way
+        assert_eq!(result.len(), 2);
+        assert_eq!(*result[0], vec![1f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>());
+        assert_eq!(*result[1], vec![1.5f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>());
+        assert_eq!(db.query_topk(&q, 10).len(), 3);
+    }
+
+    #[test]
+    fn test_lshdb_hash_families() {
+        for (family, density) in [(HashFamily::Hadamard, None), (HashFamily::Sparse, None), (HashFamily::Sparse, Some(0.5))].iter() {
+            let mut db = LocalitySensitiveHashDatabase::<SimdVecImpl<f32x4, 4>>::with_hashes(16, 16, 16, Metric::Euclidean, *family, *density);
+            for scale in [1f32, 1.5, 2f32, -1f32].iter() {
+                db.insert(vec![*scale; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>()).unwrap();
+            }
+
+            let q = vec![1.1f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>();
+            let result = db.query_topk(&q, 2);
+            assert_eq!(*result[0], vec![1f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>());
+            assert_eq!(*result[1], vec![1.5f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>());
+
+            assert!(db.delete(&vec![1f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>()));
+            assert_eq!(db.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_lshdb_trained_projections() {
+        use crate::lsh::training::Rotation;
+        use rand::{Rng, SeedableRng};
+        use rand::rngs::StdRng;
+
+        let mut rng = StdRng::seed_from_u64(50);
+        let sample = (0..100).
+            map(|_| (0..16).map(|_| 1f32 + rng.gen_range(-1f32..1f32)).collect::<SimdVecImpl<f32x4, 4>>()).
+            collect::<Vec<SimdVecImpl<f32x4, 4>>>();
+
+        let mut db = LocalitySensitiveHashDatabase::<SimdVecImpl<f32x4, 4>>::with_hashes(4, 8, 16, Metric::Euclidean, HashFamily::Dense, None);
+        for item in sample.iter().take(50) {
+            db.insert(item.into_iter().collect()).unwrap();
+        }
+
+        let trained = (0..4).
+            map(|_| TrainedProjections::train(&sample, 8, Rotation::Itq { iterations: 10 }).unwrap()).
+            collect::<Vec<TrainedProjections<SimdVecImpl<f32x4, 4>>>>();
+        assert!(db.use_trained_projections(&trained[1..]).is_err());
+        let narrow = (0..4).
+            map(|_| TrainedProjections::train(&sample, 6, Rotation::None).unwrap()).
+            collect::<Vec<TrainedProjections<SimdVecImpl<f32x4, 4>>>>();
+        assert!(db.use_trained_projections(&narrow).is_err());
+        db.use_trained_projections(&trained).unwrap();
+
+        // Everything stored is found again under the new hashes
+        assert_eq!(db.len(), 50);
+        for item in sample.iter().take(50) {
+            assert_eq!(db.query(item), Some(item));
+        }
+        assert!(db.delete(&sample[0]));
+        assert!(db.tables.iter().all(|table| table.table.values().map(|bucket| bucket.len()).sum::<usize>() == 49));
+    }
+
+    #[test]
+    fn test_lshdb_f32x8() {
+        // On x86, f32x8 is an AVX register
+        if cfg!(any(target_arch = "x86", target_arch = "x86_64")) && !Backend::available().contains(&Backend::Avx2) {
+            return;
+        }
+
+        let mut db = LocalitySensitiveHashDatabase::<SimdVecImpl<f32x8, 4>>::new(32, 32);
+        for scale in [1f32, 2f32, -1f32].iter() {
+            db.insert(vec![*scale; 32].into_iter().collect::<SimdVecImpl<f32x8, 4>>()).unwrap();
+        }
+        assert_eq!(db.len(), 3);
+
+        let q = vec![1.1f32; 32].into_iter().collect::<SimdVecImpl<f32x8, 4>>();
+        assert_eq!(*db.query(&q).unwrap(), vec![1f32; 32].into_iter().collect::<SimdVecImpl<f32x8, 4>>());
+        assert!(db.delete(&vec![2f32; 32].into_iter().collect::<SimdVecImpl<f32x8, 4>>()));
+        assert_eq!(db.len(), 2);
+    }
+
+    #[test]
+    fn test_lshdb_query_topk_cancelled() {
+        let mut db = LocalitySensitiveHashDatabase::<SimdVecImpl<f32x4, 4>>::new(8, 16);
+        db.insert(vec![1f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>()).unwrap();
+
+        let q = vec![1.1f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>();
+        let cancel = CancelToken::new();
+        assert_eq!(db.query_topk_cancellable(&q, 1, &cancel).map(|result| result.len()), Some(1));
+
+        // A deadline in the past stops the query without cancelling the rest
+        let expired = cancel.with_deadline(Some(std::time::Instant::now()));
+        assert!(db.query_topk_cancellable(&q, 1, &expired).is_none());
+        assert!(!cancel.is_cancelled());
+
+        cancel.cancel();
+        assert!(db.query_topk_cancellable(&q, 1, &cancel).is_none());
+    }
+
+    #[test]
+    fn test_lshdb_delete() {
+        let mut db = LocalitySensitiveHashDatabase::<SimdVecImpl<f32x4, 4>>::new(32, 16);
+        let item = || vec![1f32; 16].into_iter().collect::<SimdVecImpl<f32x4, 4>>();
+
+        db.insert(item()).unwrap();
+        assert_eq!(db.len(), 1);
+
+        assert!(db.delete(&item()));
+        assert!(!db.delete(&item()));
+        assert!(db.is_empty());
+        assert!(db.query(&item()).is_none());
+        assert!(db.tables.iter().all(|table| table.table.is_empty()));
+    }
 }
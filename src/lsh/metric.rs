@@ -0,0 +1,85 @@
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use crate::lsh::vector::Vector;
+
+/// How the distance between two vectors is measured when ranking neighbours.
+/// Smaller is always nearer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    #[default]
+    Euclidean,
+    /// One minus the cosine of the angle between the vectors.
+    Cosine,
+    /// The negated inner product, for vectors whose length carries meaning.
+    Dot,
+}
+
+impl Metric {
+    pub fn distance<T>(&self, a: &T, b: &T) -> f32
+    where
+        T: Vector<DType=f32>,
+        for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+    {
+        match self {
+            Metric::Euclidean => a.distance(b),
+            Metric::Cosine => {
+                let norms = (a.dot(a) * b.dot(b)).sqrt();
+                if norms == 0f32 { 1f32 } else { 1f32 - a.dot(b) / norms }
+            }
+            Metric::Dot => -a.dot(b),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Euclidean => "euclidean",
+            Metric::Cosine => "cosine",
+            Metric::Dot => "dot",
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "euclidean" => Ok(Metric::Euclidean),
+            "cosine" => Ok(Metric::Cosine),
+            "dot" => Ok(Metric::Dot),
+            _ => Err(format!("unknown metric '{}'; expected euclidean, cosine or dot", s)),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.name().fmt(fmt)
+    }
+}
+
+#[cfg(test)]
+mod metric_test {
+    use super::*;
+    use crate::simd::vec::SimdVecImpl;
+    use crate::simd::f32x4;
+
+    type V = SimdVecImpl<f32x4, 4>;
+
+    #[test]
+    fn test_metrics() {
+        let x = (0..16).map(|i| if i == 0 { 1f32 } else { 0f32 }).collect::<V>();
+        let y = (0..16).map(|i| if i == 1 { 2f32 } else { 0f32 }).collect::<V>();
+        let scaled = (0..16).map(|i| if i == 0 { 3f32 } else { 0f32 }).collect::<V>();
+
+        assert!(Metric::Cosine.distance(&x, &scaled).abs() < 1e-6);
+        assert!((Metric::Cosine.distance(&x, &y) - 1f32).abs() < 1e-6);
+        assert_eq!(Metric::Dot.distance(&x, &scaled), -3f32);
+        assert_eq!(Metric::Euclidean.distance(&x, &scaled), x.distance(&scaled));
+
+        assert_eq!("Cosine".parse::<Metric>().unwrap(), Metric::Cosine);
+        assert!("manhattan".parse::<Metric>().is_err());
+    }
+}
@@ -1,5 +1,16 @@
 pub mod vector;
+pub mod database;
+pub mod cancel;
 pub mod lsh_database;
 pub mod stable_hash;
+pub mod hadamard_hash;
+pub mod sparse_hash;
+pub mod training;
+pub mod hash_family;
 pub mod random_projection;
+pub mod metric;
 pub use lsh_database::LocalitySensitiveHashDatabase;
+pub use metric::Metric;
+pub use hash_family::HashFamily;
+pub use database::{Database, DatasetConfig, ValidationError};
+pub use cancel::{CancelOnDrop, CancelToken};
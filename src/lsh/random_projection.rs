@@ -3,9 +3,8 @@ use std::mem::size_of;
 
 use std::fmt;
 use std::marker::PhantomData;
-use std::io::Write;
 use rand::Rng;
-use byteorder::{ByteOrder, LittleEndian};
+use byteorder::ByteOrder;
 use serde::{Serialize, Deserialize, Serializer, Deserializer};
 use serde::de;
 
@@ -43,9 +42,22 @@ where
         }
     }
 
+    /// The projection onto `direction`, scaled to unit length.
+    pub fn from_direction(direction: T) -> Self {
+        let norm = T::dot(&direction, &direction).sqrt();
+        RandomProjection {
+            proj: UnitVector {u: direction / norm}
+        }
+    }
+
     pub fn hash(&self, v: &T) -> u64 {
         if T::dot(&self.proj.u, v) > 0f32 { 1u64 } else { 0u64 }
     }
+
+    /// The unit vector being projected onto.
+    pub fn direction(&self) -> &T {
+        &self.proj.u
+    }
 }
 
 
@@ -60,10 +72,10 @@ where
         let mut buf = Vec::<u8>::new();
         let element_count = self.proj.u.dimension() as u32;
         
-        buf.write(&element_count.to_le_bytes());
+        buf.extend_from_slice(&element_count.to_le_bytes());
         
         for element in &self.proj.u {
-            buf.write(&element.to_le_bytes());
+            buf.extend_from_slice(&element.to_le_bytes());
         }
         
         serializer.serialize_bytes(buf.as_slice())
@@ -116,10 +128,9 @@ impl<'de, T> de::Visitor<'de> for RPVisitor<T> where
             return Err(de::Error::invalid_length(v.len(), &self))
         }
         
-        let mut float_elts = Vec::<f32>::new();
-        float_elts.reserve(dim);
+        let mut float_elts = vec![0f32; dim];
  
-        byteorder::LittleEndian::read_f32_into(v, float_elts.as_mut_slice());
+        byteorder::LittleEndian::read_f32_into(&v[4..], float_elts.as_mut_slice());
         let unit = float_elts.into_iter().collect::<T>();
         let norm = T::dot(&unit, &unit).sqrt();
         
@@ -150,8 +161,7 @@ impl<'de, T> de::Visitor<'de> for RPVisitor<T> where
         //let expected_byte_count = size_of::<<T as Vector>::DType>() * dim;
         let mut byte_count: usize = 0;
         
-        let mut float_elts = Vec::<f32>::new();
-        float_elts.reserve(dim);
+        let mut float_elts = Vec::<f32>::with_capacity(dim);
 
         while let Ok(Some(element)) = v.next_element::<u8>() {
             byte_count += 1;
@@ -194,13 +204,13 @@ where
 mod tests {
     use super::*;
     use crate::simd::vec::SimdVecImpl;
-    use crate::simd::sse::f32x4;
+    use crate::simd::f32x4;
     use serde_json;
 
     #[test]
     fn test_create_random_projection() {
         let rp = RandomProjection::<SimdVecImpl<f32x4, 8>>::new(8 * 4);
-        let norm = (&rp.proj.u).dot(&rp.proj.u).sqrt();
+        let norm = rp.proj.u.dot(&rp.proj.u).sqrt();
         assert!((norm - 1f32).abs() < 1e-5);
     }
     
@@ -218,7 +228,7 @@ mod tests {
               191,97,160,190,   128,86,147,62,  155,128,3,62,   255,47,92,62]";
 
         let rp: RandomProjection<SimdVecImpl<f32x4, 8>> = 
-            serde_json::from_str(&serialized_random_proj).unwrap();
+            serde_json::from_str(serialized_random_proj).unwrap();
 
         let mut positive = vec![0f32; 32];
         let mut negative = vec![0f32; 32];
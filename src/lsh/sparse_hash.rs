@@ -0,0 +1,246 @@
+use crate::lsh::vector::Vector;
+use rand::Rng;
+use std::marker::PhantomData;
+use std::vec::Vec;
+
+/*
+
+A hash whose projections are sparse, with each element -1, 0 or +1, as in the
+database friendly projections of Achlioptas and the very sparse projections of
+Li, Hastie and Church.  Each element is nonzero with probability `density`,
+which defaults to 1 / sqrt(d), so even very wide vectors have projections that
+fit in memory.
+
+The nonzeros are stored by column.  A column holds one byte for each
+projection touching that element of the vector, the index of the projection
+in its low six bits and the sign in its high bit:
+
+    offsets     [0, 2, 2, 3, ...]            one past each column's last byte
+    entries     [0x03, 0x8a, 0x15, ...]      column 0 adds to bit 3 and
+                                             subtracts from bit 10, ...
+
+Hashing walks the elements of the vector, adding or subtracting each into the
+projections its column touches.  A sparse vector only visits the columns of
+its own nonzeros, so it costs next to nothing however wide it is.
+
+Only the hash itself takes `SparseVector`s.  The database and the servers
+store and send vectors densely, so datasets using this family still pay for
+every element of their vectors outside of hashing.
+
+*/
+
+const SIGN: u8 = 0x80;
+
+/// A vector stored as its nonzero elements, in order of index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparseVector {
+    dimension: usize,
+    indices: Vec<u32>,
+    values: Vec<f32>,
+}
+
+impl SparseVector {
+    /// Builds a vector of `dimension` elements from its nonzero elements,
+    /// given as index and value in any order.  Fails if an index repeats or
+    /// is out of range.
+    pub fn new(dimension: usize, elements: impl IntoIterator<Item=(usize, f32)>) -> crate::Result<Self> {
+        let mut elements = elements.
+            into_iter().
+            filter(|(_, value)| *value != 0f32).
+            collect::<Vec<(usize, f32)>>();
+        elements.sort_unstable_by_key(|(idx, _)| *idx);
+        if let Some(pair) = elements.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!("index {} appears more than once", pair[0].0).into());
+        }
+        if let Some((idx, _)) = elements.last().filter(|(idx, _)| *idx >= dimension) {
+            return Err(format!("index {} is out of range for dimension {}", idx, dimension).into());
+        }
+
+        Ok(SparseVector {
+            dimension,
+            indices: elements.iter().map(|(idx, _)| *idx as u32).collect(),
+            values: elements.iter().map(|(_, value)| *value).collect(),
+        })
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// The nonzero elements as index and value, in order of index.
+    pub fn nonzeros(&self) -> impl Iterator<Item=(usize, f32)> + '_ {
+        self.indices.iter().map(|idx| *idx as usize).zip(self.values.iter().copied())
+    }
+}
+
+pub struct SparseHashFunction<T>
+where
+    T: Vector<DType=f32>,
+    for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+{
+    offsets: Vec<u32>,
+    entries: Vec<u8>,
+    bits: usize,
+    marker: PhantomData<fn(&T)>
+}
+
+impl<T> SparseHashFunction<T>
+where
+    T: Vector<DType=f32>,
+    for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+{
+    /// The density sparse projections have unless asked for another.
+    pub fn default_density(dimension: usize) -> f32 {
+        1f32 / (dimension as f32).sqrt()
+    }
+
+    pub fn new(bits: usize, dimension: usize, density: f32) -> Self {
+        assert!(bits <= 64, "a hash has at most 64 bits");
+        assert!(density > 0f32 && density <= 1f32, "density must be in (0, 1]");
+        let mut rng = rand::thread_rng();
+
+        // Skipping ahead by geometrically distributed gaps visits each element
+        // with probability `density` in time proportional to the nonzeros
+        let mut nonzeros = Vec::<(u32, u8)>::new();
+        for bit in 0..bits {
+            let mut col = 0usize;
+            loop {
+                if density < 1f32 {
+                    let u = 1f32 - rng.gen::<f32>();
+                    col = col.saturating_add((u.ln() / (1f32 - density).ln()) as usize);
+                }
+                if col >= dimension {
+                    break;
+                }
+                let sign = if rng.gen::<bool>() { 0 } else { SIGN };
+                nonzeros.push((col as u32, bit as u8 | sign));
+                col += 1;
+            }
+        }
+        nonzeros.sort_unstable();
+
+        let mut offsets = vec![0u32; dimension + 1];
+        for (col, _) in nonzeros.iter() {
+            offsets[*col as usize + 1] += 1;
+        }
+        for col in 0..dimension {
+            offsets[col + 1] += offsets[col];
+        }
+        let entries = nonzeros.into_iter().map(|(_, entry)| entry).collect::<Vec<u8>>();
+        SparseHashFunction { offsets, entries, bits, marker: PhantomData }
+    }
+
+    pub fn hash(&self, v: &T) -> u64 {
+        let mut products = [0f32; 64];
+        for (value, bounds) in v.as_slice().iter().zip(self.offsets.windows(2)) {
+            add_column(&mut products, &self.entries[bounds[0] as usize..bounds[1] as usize], *value);
+        }
+        signs(&products[..self.bits])
+    }
+
+    pub fn hash_sparse(&self, v: &SparseVector) -> u64 {
+        assert_eq!(v.dimension(), self.offsets.len() - 1, "vector has the wrong dimension");
+        let mut products = [0f32; 64];
+        for (col, value) in v.nonzeros() {
+            add_column(&mut products, &self.entries[self.offsets[col] as usize..self.offsets[col + 1] as usize], value);
+        }
+        signs(&products[..self.bits])
+    }
+}
+
+#[inline(always)]
+fn add_column(products: &mut [f32; 64], column: &[u8], value: f32) {
+    // Picking the signed value by index keeps the random signs from costing a
+    // mispredicted branch each
+    let signed = [value, -value];
+    for entry in column {
+        products[(entry & !SIGN) as usize] += signed[(entry >> 7) as usize];
+    }
+}
+
+fn signs(products: &[f32]) -> u64 {
+    products.
+        iter().
+        enumerate().
+        fold(0u64, |acc, (i, product)| acc | (((*product > 0f32) as u64) << i))
+}
+
+#[cfg(test)]
+mod sparse_hash_test {
+    use super::*;
+    use crate::simd::vec::{SimdVecImpl, SimdVector};
+    use crate::simd::f32x4;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // The projections as rows of -1, 0 and +1
+    fn rows<T>(f: &SparseHashFunction<T>) -> Vec<Vec<f32>>
+    where
+        T: Vector<DType=f32>,
+        for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+    {
+        let mut rows = vec![vec![0f32; f.offsets.len() - 1]; f.bits];
+        for (col, bounds) in f.offsets.windows(2).enumerate() {
+            for entry in &f.entries[bounds[0] as usize..bounds[1] as usize] {
+                rows[(entry & !SIGN) as usize][col] = if entry & SIGN == 0 { 1f32 } else { -1f32 };
+            }
+        }
+        rows
+    }
+
+    #[test]
+    fn test_density() {
+        let f = SparseHashFunction::<SimdVector<f32x4>>::new(64, 10000, 0.01);
+        assert_eq!(f.offsets.len(), 10001);
+        assert_eq!(*f.offsets.last().unwrap() as usize, f.entries.len());
+        // 6400 nonzeros expected, with a standard deviation of 80
+        assert!((6000..6800).contains(&f.entries.len()), "{} nonzeros", f.entries.len());
+
+        let f = SparseHashFunction::<SimdVector<f32x4>>::new(13, 100, 1f32);
+        assert!(rows(&f).iter().all(|row| row.iter().all(|x| x.abs() == 1f32)));
+    }
+
+    #[test]
+    fn test_hash_bits_are_projection_signs() {
+        let f = SparseHashFunction::<SimdVecImpl<f32x4, 32>>::new(40, 128, 0.2);
+        // Small integers add up exactly in any order
+        let v = (0..128).map(|i| (i % 7) as f32 - 3f32).collect::<SimdVecImpl<f32x4, 32>>();
+
+        let expected = rows(&f).
+            iter().
+            enumerate().
+            fold(0u64, |acc, (i, row)| {
+                let product = row.iter().zip(v.as_slice().iter()).map(|(x, y)| x * y).sum::<f32>();
+                acc | (((product > 0f32) as u64) << i)
+            });
+        assert_eq!(f.hash(&v), expected);
+    }
+
+    #[test]
+    fn test_sparse_and_dense_vectors_agree() {
+        let mut rng = StdRng::seed_from_u64(49);
+        let dim = 5000;
+        let f = SparseHashFunction::<SimdVector<f32x4>>::new(64, dim, SparseHashFunction::<SimdVector<f32x4>>::default_density(dim));
+
+        for _ in 0..20 {
+            let sparse = SparseVector::new(dim, (0..50).map(|_| (rng.gen_range(0..dim), rng.gen_range(-1f32..1f32))).collect::<std::collections::HashMap<usize, f32>>()).unwrap();
+            let mut dense = vec![0f32; dim];
+            for (idx, value) in sparse.nonzeros() {
+                dense[idx] = value;
+            }
+            assert_eq!(f.hash_sparse(&sparse), f.hash(&dense.into_iter().collect::<SimdVector<f32x4>>()));
+        }
+    }
+
+    #[test]
+    fn test_sparse_vector() {
+        let v = SparseVector::new(10, vec![(7, 2f32), (1, -1f32), (4, 0f32)]).unwrap();
+        assert_eq!(v.nonzeros().collect::<Vec<(usize, f32)>>(), vec![(1, -1f32), (7, 2f32)]);
+        assert_eq!(v.dimension(), 10);
+
+        assert!(SparseVector::new(10, vec![(3, 1f32), (3, 2f32)]).is_err());
+        assert!(SparseVector::new(10, vec![(10, 1f32)]).is_err());
+        // Zeros are dropped before the checks
+        assert!(SparseVector::new(10, vec![(10, 0f32)]).is_ok());
+    }
+}
@@ -1,13 +1,22 @@
 use crate::lsh::random_projection::RandomProjection;
 use crate::lsh::vector::Vector;
+use crate::simd::dispatch;
+use std::marker::PhantomData;
 use std::vec::Vec;
 
+// The random projections are kept as the rows of one row-major matrix, so
+// every bit of a hash comes out of a single matrix-vector product.  A bit is
+// set when its projection exceeds its threshold, which is zero unless the
+// projections were trained on data that isn't centred on the origin.
 pub struct StableHashFunction<T> 
 where
     T: Vector<DType=f32>,
     for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
 {
-    projections: Vec<RandomProjection<T>>
+    projections: Vec<f32>,
+    thresholds: Vec<f32>,
+    bits: usize,
+    marker: PhantomData<fn(&T)>
 }
 
 impl<T: Vector<DType=f32>> StableHashFunction<T> 
@@ -19,15 +28,31 @@ where
         let projections = (0..bits).
             map(|_| RandomProjection::<T>::new(dimension)).
             collect::<Vec<RandomProjection<T>>>();
-        StableHashFunction { projections }
+        StableHashFunction::from_projections(&projections, &vec![0f32; bits])
+    }
+
+    /// A hash whose bit i is set when the projection onto `projections[i]`
+    /// is greater than `thresholds[i]`.
+    pub fn from_projections(projections: &[RandomProjection<T>], thresholds: &[f32]) -> Self {
+        assert!(projections.len() <= 64, "a hash has at most 64 bits");
+        assert_eq!(projections.len(), thresholds.len());
+        StableHashFunction {
+            projections: projections.iter().flat_map(|proj| proj.direction().as_slice().to_vec()).collect(),
+            thresholds: thresholds.to_vec(),
+            bits: projections.len(),
+            marker: PhantomData
+        }
     }
 
     pub fn hash(&self, v: &T) -> u64 {
-        self.projections.
+        let mut products = [0f32; 64];
+        let products = &mut products[..self.bits];
+        dispatch::gemv(&self.projections, v.as_slice(), products);
+        products.
             iter().
-            map(|proj| proj.hash(v)).
+            zip(self.thresholds.iter()).
             enumerate().
-            fold(0u64, |acc, (i, sgn)| acc | (sgn << i))
+            fold(0u64, |acc, (i, (product, threshold))| acc | (((*product > *threshold) as u64) << i))
     }
 }
 
@@ -35,8 +60,7 @@ where
 mod test {
     use super::*;
     use crate::simd::vec::SimdVecImpl;
-    use crate::simd::sse::f32x4;
-    use serde_json;
+    use crate::simd::f32x4;
     
     #[test]
     fn test_insert_to_stable_hash_table() {
@@ -50,8 +74,27 @@ mod test {
 
         let norm = SimdVecImpl::<f32x4,4>::dot(&random_vector, &random_vector).sqrt();
         let random_unit = random_vector / norm;
+        let scaled = (&random_unit).into_iter().map(|x| 3f32 * x).collect::<SimdVecImpl<f32x4,4>>();
+        let opposite = (&random_unit).into_iter().map(|x| -x).collect::<SimdVecImpl<f32x4,4>>();
+
+        // Rescaling doesn't change the hash, and the opposite vector flips every bit
+        assert_eq!(f.hash(&random_unit), f.hash(&scaled));
+        assert_eq!(f.hash(&random_unit), !f.hash(&opposite));
+    }
 
-// [4,0,0,0,0,0,128,127,0,0,128,255,0,0,128,127,0,0,128,255]
+    #[test]
+    fn test_hash_bits_are_projection_signs() {
+        let f = StableHashFunction::<SimdVecImpl<f32x4, 5>>::new(13, 20);
+        let v = (0..20).map(|i| (i as f32).sin()).collect::<SimdVecImpl<f32x4, 5>>();
+
+        let expected = f.projections.
+            chunks(20).
+            enumerate().
+            fold(0u64, |acc, (i, row)| {
+                let product = row.iter().zip(v.as_slice().iter()).map(|(x, y)| x * y).sum::<f32>();
+                acc | (((product > 0f32) as u64) << i)
+            });
+        assert_eq!(f.hash(&v), expected);
     }
 }
 
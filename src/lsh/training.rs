@@ -0,0 +1,397 @@
+use crate::lsh::random_projection::RandomProjection;
+use crate::lsh::stable_hash::StableHashFunction;
+use crate::lsh::vector::Vector;
+use crate::simd::dispatch;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use std::vec::Vec;
+
+/*
+
+Projections learned from a sample of the data rather than drawn at random.
+Random hyperplanes spend as many bits on directions the data barely varies
+along as on those it varies along most.  Training instead projects onto the
+principal directions of the sample, found by subspace iteration:
+
+    Q <- orthonormalise(X^T X Q)        X the centred sample, one row each
+
+which converges to the K directions of greatest variance without ever
+forming the d x d covariance.
+
+Signs of principal components make poor bits on their own, because the first
+few carry most of the variance.  Rotating within their span balances the
+bits, either at random, or by iterative quantization (Gong and Lazebnik),
+which alternates between the binary codes and the rotation best fitting them:
+
+    B <- sign(V R)                      V the sample's principal components
+    R <- U W^T,  where V^T B = U S W^T
+
+The trained directions are kept as `RandomProjection`s, with a threshold for
+each bit at the projection of the sample's mean, and serialize as such.
+
+*/
+
+const SUBSPACE_ITERATIONS: usize = 50;
+const JACOBI_SWEEPS: usize = 30;
+// A row left with less than this fraction of its length once the rows before
+// it are taken out is treated as dependent on them.  Rounding leaves around
+// 1e-7 of a dependent row behind in f32.
+const DEPENDENCE_TOLERANCE: f32 = 1e-5;
+
+/// How the principal directions are rotated before hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// The principal directions themselves.
+    None,
+    /// A random rotation, which balances the bits and gives each table
+    /// trained on the same sample its own hash.
+    Random,
+    /// Iterative quantization, starting from a random rotation.
+    Itq { iterations: usize },
+}
+
+/// Learned projections, one for each bit of a hash.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "T: Vector<DType=f32>, for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>")]
+pub struct TrainedProjections<T>
+where
+    T: Vector<DType=f32>,
+    for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+{
+    projections: Vec<RandomProjection<T>>,
+    thresholds: Vec<f32>,
+}
+
+impl<T> TrainedProjections<T>
+where
+    T: Vector<DType=f32>,
+    for <'a> &'a T: IntoIterator<Item=<T as Vector>::DType>
+{
+    /// Learns `bits` projections from `sample`.
+    pub fn train(sample: &[T], bits: usize, rotation: Rotation) -> crate::Result<Self> {
+        let dimension = match sample.first() {
+            Some(v) => v.dimension(),
+            None => return Err("training needs a sample".into()),
+        };
+        if bits == 0 || bits > 64 {
+            return Err("K must be between 1 and 64".into());
+        }
+        if bits > dimension {
+            return Err(format!("can't train {} projections of {} dimensional vectors", bits, dimension).into());
+        }
+        if sample.iter().any(|v| v.dimension() != dimension) {
+            return Err("sample vectors must all have the same dimension".into());
+        }
+        // Centring takes away a direction, so K directions of variance need at
+        // least K + 1 vectors
+        if sample.len() <= bits {
+            return Err(format!("can't train {} projections from {} vectors", bits, sample.len()).into());
+        }
+
+        // Centre the sample, laid out one row per vector
+        let mut mean = vec![0f32; dimension];
+        for v in sample {
+            for (total, x) in mean.iter_mut().zip(v.as_slice().iter()) {
+                *total += x;
+            }
+        }
+        mean.iter_mut().for_each(|total| *total /= sample.len() as f32);
+        let centred = sample.
+            iter().
+            flat_map(|v| v.as_slice()[..dimension].iter().zip(mean.iter()).map(|(x, mu)| x - mu)).
+            collect::<Vec<f32>>();
+
+        let mut rng = rand::thread_rng();
+        let mut directions = random_rows(&mut rng, bits, dimension);
+        orthonormalise(&mut directions, dimension);
+        let degenerate = || format!("the sample varies along fewer than {} directions", bits).into();
+        for _ in 0..SUBSPACE_ITERATIONS {
+            let components = project(&centred, &directions, dimension);
+            directions = vec![0f32; bits * dimension];
+            for (x, v) in centred.chunks_exact(dimension).zip(components.chunks_exact(bits)) {
+                for (direction, weight) in directions.chunks_exact_mut(dimension).zip(v.iter()) {
+                    direction.iter_mut().zip(x.iter()).for_each(|(d, x)| *d += weight * x);
+                }
+            }
+            // Duplicated or collinear vectors leave the sample with too few
+            // directions of variance to fill the rest of the rows
+            if !orthonormalise(&mut directions, dimension) {
+                return Err(degenerate());
+            }
+        }
+
+        let rotation = match rotation {
+            Rotation::None => identity(bits),
+            Rotation::Random => random_rotation(&mut rng, bits),
+            Rotation::Itq { iterations } => {
+                let components = project(&centred, &directions, dimension);
+                let mut rotation = random_rotation(&mut rng, bits);
+                for _ in 0..iterations {
+                    // V^T sign(V R), accumulated a vector at a time
+                    let mut correlation = vec![0f64; bits * bits];
+                    for v in components.chunks_exact(bits) {
+                        let codes = (0..bits).
+                            map(|col| (0..bits).map(|row| v[row] as f64 * rotation[row * bits + col]).sum::<f64>()).
+                            map(|x| if x >= 0f64 { 1f64 } else { -1f64 }).
+                            collect::<Vec<f64>>();
+                        for row in 0..bits {
+                            for col in 0..bits {
+                                correlation[row * bits + col] += v[row] as f64 * codes[col];
+                            }
+                        }
+                    }
+                    rotation = polar(&correlation, bits);
+                }
+                rotation
+            }
+        };
+
+        // Hashing takes sign(R^T Q x), so the final directions are the rows
+        // of R^T Q
+        let rotated = (0..bits).
+            map(|col| {
+                (0..dimension).
+                    map(|idx| (0..bits).map(|row| rotation[row * bits + col] as f32 * directions[row * dimension + idx]).sum::<f32>()).
+                    collect::<T>()
+            }).
+            map(RandomProjection::from_direction).
+            collect::<Vec<RandomProjection<T>>>();
+        let thresholds = rotated.
+            iter().
+            map(|proj| dispatch::dot(&proj.direction().as_slice()[..dimension], &mean)).
+            collect::<Vec<f32>>();
+
+        Ok(TrainedProjections { projections: rotated, thresholds })
+    }
+
+    pub fn projections(&self) -> &[RandomProjection<T>] {
+        &self.projections
+    }
+
+    /// A hash function built from the trained projections, to stand in for
+    /// a random one.
+    pub fn hash_function(&self) -> StableHashFunction<T> {
+        StableHashFunction::from_projections(&self.projections, &self.thresholds)
+    }
+}
+
+fn random_rows(rng: &mut impl Rng, rows: usize, cols: usize) -> Vec<f32> {
+    (0..rows * cols).map(|_| rng.gen_range(-1f32..1f32)).collect()
+}
+
+// The projection of each row of `data` onto each row of `directions`.
+fn project(data: &[f32], directions: &[f32], dimension: usize) -> Vec<f32> {
+    let rows = directions.len() / dimension;
+    let mut components = vec![0f32; data.len() / dimension * rows];
+    for (x, v) in data.chunks_exact(dimension).zip(components.chunks_exact_mut(rows)) {
+        dispatch::gemv(directions, x, v);
+    }
+    components
+}
+
+// Modified Gram-Schmidt over the rows of a row-major matrix.  Returns false,
+// leaving the rows part done, if they are linearly dependent.
+fn orthonormalise(rows: &mut [f32], dimension: usize) -> bool {
+    for idx in 0..rows.len() / dimension {
+        let (done, rest) = rows.split_at_mut(idx * dimension);
+        let row = &mut rest[..dimension];
+        let length = dispatch::dot(row, row).sqrt();
+        for other in done.chunks_exact(dimension) {
+            let overlap = dispatch::dot(row, other);
+            row.iter_mut().zip(other.iter()).for_each(|(x, y)| *x -= overlap * y);
+        }
+        let norm = dispatch::dot(row, row).sqrt();
+        // NaN from a sample holding infinities counts as dependent too
+        if norm.is_nan() || norm <= DEPENDENCE_TOLERANCE * length {
+            return false;
+        }
+        row.iter_mut().for_each(|x| *x /= norm);
+    }
+    true
+}
+
+fn identity(n: usize) -> Vec<f64> {
+    (0..n * n).map(|idx| if idx % (n + 1) == 0 { 1f64 } else { 0f64 }).collect()
+}
+
+fn random_rotation(rng: &mut impl Rng, n: usize) -> Vec<f64> {
+    polar(&(0..n * n).map(|_| rng.gen_range(-1f64..1f64)).collect::<Vec<f64>>(), n)
+}
+
+// The orthogonal matrix U W^T nearest an n x n matrix M = U S W^T, from the
+// one-sided Jacobi SVD, which rotates pairs of columns of M until they are
+// orthogonal.  The rotations accumulate into W, leaving M W = U S.
+fn polar(m: &[f64], n: usize) -> Vec<f64> {
+    let mut a = m.to_vec();
+    let mut w = identity(n);
+    for _ in 0..JACOBI_SWEEPS {
+        let mut rotated = false;
+        for p in 0..n {
+            for q in p + 1..n {
+                let (mut alpha, mut beta, mut gamma) = (0f64, 0f64, 0f64);
+                for row in 0..n {
+                    let (x, y) = (a[row * n + p], a[row * n + q]);
+                    alpha += x * x;
+                    beta += y * y;
+                    gamma += x * y;
+                }
+                if gamma.abs() <= 1e-12 * (alpha * beta).sqrt() {
+                    continue;
+                }
+                rotated = true;
+
+                let zeta = (beta - alpha) / (2f64 * gamma);
+                let t = zeta.signum() / (zeta.abs() + (1f64 + zeta * zeta).sqrt());
+                let c = 1f64 / (1f64 + t * t).sqrt();
+                let s = c * t;
+                for matrix in [&mut a, &mut w] {
+                    for row in 0..n {
+                        let (x, y) = (matrix[row * n + p], matrix[row * n + q]);
+                        matrix[row * n + p] = c * x - s * y;
+                        matrix[row * n + q] = s * x + c * y;
+                    }
+                }
+            }
+        }
+        if !rotated {
+            break;
+        }
+    }
+
+    // Normalise the columns of M W to get U, then multiply by W^T
+    for col in 0..n {
+        let norm = (0..n).map(|row| a[row * n + col] * a[row * n + col]).sum::<f64>().sqrt();
+        if norm > 0f64 {
+            (0..n).for_each(|row| a[row * n + col] /= norm);
+        }
+    }
+    (0..n * n).
+        map(|idx| (0..n).map(|k| a[(idx / n) * n + k] * w[(idx % n) * n + k]).sum::<f64>()).
+        collect()
+}
+
+#[cfg(test)]
+mod training_test {
+    use super::*;
+    use crate::simd::vec::SimdVector;
+    use crate::simd::f32x4;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    type V = SimdVector<f32x4>;
+
+    // Points spread widely along the first few axes and barely along the
+    // rest, around a mean far from the origin
+    fn sample(rng: &mut StdRng, n: usize, dimension: usize) -> Vec<V> {
+        (0..n).
+            map(|_| (0..dimension).map(|idx| 5f32 + rng.gen_range(-1f32..1f32) * if idx < 4 { 10f32 / (idx + 1) as f32 } else { 0.01 }).collect::<V>()).
+            collect()
+    }
+
+    #[test]
+    fn test_polar_is_nearest_rotation() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let n = 6;
+        let m = (0..n * n).map(|_| rng.gen_range(-1f64..1f64)).collect::<Vec<f64>>();
+        let r = polar(&m, n);
+
+        // R is orthogonal, and R^T M is symmetric positive definite
+        for i in 0..n {
+            for j in 0..n {
+                let rtr = (0..n).map(|k| r[k * n + i] * r[k * n + j]).sum::<f64>();
+                assert!((rtr - if i == j { 1f64 } else { 0f64 }).abs() < 1e-9);
+                let rtm = |i: usize, j: usize| (0..n).map(|k| r[k * n + i] * m[k * n + j]).sum::<f64>();
+                assert!((rtm(i, j) - rtm(j, i)).abs() < 1e-9);
+            }
+            assert!((0..n).map(|k| r[k * n + i] * m[k * n + i]).sum::<f64>() > 0f64);
+        }
+    }
+
+    #[test]
+    fn test_pca_finds_directions_of_variance() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let trained = TrainedProjections::<V>::train(&sample(&mut rng, 500, 20), 4, Rotation::None).unwrap();
+
+        // Each direction lies in the span of the first four axes
+        for proj in trained.projections() {
+            let direction = proj.direction().as_slice();
+            let inside = direction[..4].iter().map(|x| x * x).sum::<f32>();
+            assert!(inside > 0.99, "{} of the direction is in the span", inside);
+        }
+        // and the first is the widest one
+        assert!(trained.projections()[0].direction().as_slice()[0].abs() > 0.99);
+    }
+
+    #[test]
+    fn test_trained_bits_are_balanced() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let train = sample(&mut rng, 400, 16);
+        let test = sample(&mut rng, 400, 16);
+
+        for rotation in [Rotation::None, Rotation::Random, Rotation::Itq { iterations: 20 }].iter() {
+            let hashfn = TrainedProjections::<V>::train(&train, 4, *rotation).unwrap().hash_function();
+            for bit in 0..4 {
+                let ones = test.iter().filter(|v| hashfn.hash(v) & (1 << bit) != 0).count();
+                assert!((120..280).contains(&ones), "{:?} bit {} set {} times", rotation, bit, ones);
+            }
+        }
+
+    }
+
+    #[test]
+    fn test_itq_reduces_quantization_error() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let train = sample(&mut rng, 400, 8);
+
+        // The distance from each centred, projected vector to its code
+        let error = |rotation| {
+            let trained = TrainedProjections::<V>::train(&train, 4, rotation).unwrap();
+            train.
+                iter().
+                map(|v| {
+                    trained.projections.
+                        iter().
+                        zip(trained.thresholds.iter()).
+                        map(|(proj, threshold)| {
+                            let x = proj.direction().dot(v) - threshold;
+                            (x - x.signum()).powi(2)
+                        }).
+                        sum::<f32>()
+                }).
+                sum::<f32>()
+        };
+        assert!(error(Rotation::Itq { iterations: 50 }) < error(Rotation::None));
+    }
+
+    #[test]
+    fn test_trained_projections_round_trip() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let train = sample(&mut rng, 200, 8);
+        let trained = TrainedProjections::<V>::train(&train, 3, Rotation::Random).unwrap();
+
+        let ser = serde_json::to_string(&trained).unwrap();
+        let de: TrainedProjections<V> = serde_json::from_str(&ser).unwrap();
+        assert_eq!(de.thresholds, trained.thresholds);
+        for (a, b) in de.projections().iter().zip(trained.projections().iter()) {
+            assert_eq!(a.direction(), b.direction());
+        }
+        assert!(train.iter().all(|v| de.hash_function().hash(v) == trained.hash_function().hash(v)));
+
+        assert!(TrainedProjections::<V>::train(&train, 9, Rotation::None).is_err());
+        assert!(TrainedProjections::<V>::train(&[], 2, Rotation::None).is_err());
+    }
+
+    #[test]
+    fn test_degenerate_sample_is_rejected() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let train = sample(&mut rng, 2, 8);
+        assert!(TrainedProjections::<V>::train(&train, 4, Rotation::None).is_err());
+
+        // Plenty of vectors, but only two distinct ones
+        let repeated = (0..50).map(|idx| train[idx % 2].as_slice().iter().copied().collect::<V>()).collect::<Vec<V>>();
+        for rotation in [Rotation::None, Rotation::Itq { iterations: 5 }].iter() {
+            assert!(TrainedProjections::<V>::train(&repeated, 4, *rotation).is_err());
+        }
+        assert!(TrainedProjections::<V>::train(&repeated, 1, Rotation::None).is_ok());
+    }
+}
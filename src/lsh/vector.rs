@@ -24,4 +24,6 @@ where
     fn distance(&self, other: &Self) -> <Self as Vector>::DType;
     fn dot(&self, other: &Self) -> <Self as Vector>::DType;
     fn dimension(&self) -> usize;
+    /// The elements laid out contiguously, including any padding.
+    fn as_slice(&self) -> &[<Self as Vector>::DType];
 }
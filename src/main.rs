@@ -1,33 +1,134 @@
+use std::env;
 use std::future::Future;
-use tokio::net::TcpListener;
-use tokio::sync::{broadcast, Semaphore, RwLock};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{broadcast, Semaphore};
 use std::sync::Arc;
 use rand::Rng;
 use rush::lsh::LocalitySensitiveHashDatabase;
-use rush::simd::SimdVecImpl;
+use rush::simd::SimdVector;
 use rush::simd::f32x4;
 use rush::net::*;
+use rush::net::grpc::{self, RushServer};
+use tokio_rustls::TlsAcceptor;
+use tonic::transport::Server;
+use tracing::{error, info};
 
 
 #[tokio::main]
 pub async fn main() -> rush::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:9090").await?;
-    run_server(listener, tokio::signal::ctrl_c()).await;
+    tracing_subscriber::fmt::init();
+    let config = parse_args(env::args().skip(1))?;
+    let authenticator = match &config.auth_file {
+        Some(path) => Authenticator::from_file(path)?,
+        None => Authenticator::disabled(),
+    };
+    let tls_acceptor = match &config.tls {
+        Some(tls) => Some(tls.acceptor()?),
+        None => None,
+    };
+    let listener = match config.tcp {
+        true => Some(TcpListener::bind("127.0.0.1:9090").await?),
+        false => None,
+    };
+    let unix_listener = match &config.unix_socket {
+        Some(path) => {
+            // A socket file left behind by an earlier run would make the bind fail
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            Some(UnixListener::bind(path)?)
+        }
+        None => None,
+    };
+    let http_listener = match config.http_port {
+        Some(port) => Some(TcpListener::bind(("127.0.0.1", port)).await?),
+        None => None,
+    };
+    let grpc_listener = match config.grpc_port {
+        Some(port) => Some(TcpListener::bind(("127.0.0.1", port)).await?),
+        None => None,
+    };
+    let sockets = Sockets { tcp: listener, unix: unix_listener, http: http_listener, grpc: grpc_listener };
+    run_server(sockets, config, Arc::new(authenticator), tls_acceptor, tokio::signal::ctrl_c()).await;
     Ok(())
 }
 
-async fn run_server(listener: TcpListener, shutdown: impl Future) {
-    let db_ptr = Arc::new(RwLock::new(LocalitySensitiveHashDatabase::new(32, 768)));
-    let mut db = db_ptr.write().await;
+fn parse_args(mut args: impl Iterator<Item=String>) -> rush::Result<ServerConfig> {
+    let mut config = ServerConfig::default();
+    let (mut tls_cert, mut tls_key, mut tls_client_ca) = (None, None, None);
+
+    while let Some(flag) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(format!("missing value for argument {}", flag).into()),
+        };
+
+        match &flag[..] {
+            "--max-connections" => config.max_connections = value.parse()?,
+            "--max-connections-per-client" => config.max_connections_per_client = value.parse()?,
+            "--idle-timeout" => config.idle_timeout_secs = value.parse()?,
+            "--command-timeout" => config.command_timeout_ms = value.parse()?,
+            "--tcp" => config.tcp = value.parse()?,
+            "--unix-socket" => config.unix_socket = Some(value.into()),
+            "--http-port" => config.http_port = Some(value.parse()?),
+            "--grpc-port" => config.grpc_port = Some(value.parse()?),
+            "--auth-file" => config.auth_file = Some(value.into()),
+            "--tls-cert" => tls_cert = Some(value),
+            "--tls-key" => tls_key = Some(value),
+            "--tls-client-ca" => tls_client_ca = Some(value),
+            "--max-frame-size" => config.frame_limits.max_buffer_size = value.parse()?,
+            "--max-bulk-length" => config.frame_limits.max_bulk_len = value.parse()?,
+            "--max-array-length" => config.frame_limits.max_array_len = value.parse()?,
+            "--max-frame-depth" => config.frame_limits.max_depth = value.parse()?,
+            _ => return Err(format!("unrecognized argument {}", flag).into()),
+        }
+    }
+
+    if !config.tcp && config.unix_socket.is_none() {
+        return Err("--tcp false requires --unix-socket".into());
+    }
+
+    config.tls = match (tls_cert, tls_key, tls_client_ca) {
+        (Some(cert), Some(key), None) => Some(TlsConfig::new(cert, key)),
+        (Some(cert), Some(key), Some(ca)) => Some(TlsConfig::new(cert, key).with_client_ca(ca)),
+        (None, None, None) => None,
+        _ => return Err("--tls-cert and --tls-key must be given together, and are required by --tls-client-ca".into()),
+    };
+
+    Ok(config)
+}
+
+// Everything the server listens on, bound up front so that startup fails fast
+struct Sockets {
+    tcp: Option<TcpListener>,
+    unix: Option<UnixListener>,
+    http: Option<TcpListener>,
+    grpc: Option<TcpListener>,
+}
+
+async fn run_server(
+    sockets: Sockets,
+    config: ServerConfig,
+    authenticator: Arc<Authenticator>,
+    tls_acceptor: Option<TlsAcceptor>,
+    shutdown: impl Future,
+) {
+    info!(backend = %rush::simd::dispatch::backend(), "selected vector kernels");
+    let datasets = Arc::new(Datasets::<LocalitySensitiveHashDatabase<SimdVector<f32x4>>>::new());
+    let dim = 768usize;
+    let dataset = match datasets.create("default", DatasetConfig::new(dim)) {
+        Ok(dataset) => dataset,
+        Err(err) => panic!("Error creating the default dataset: {}", err),
+    };
+    let mut db = dataset.database().write().await;
     // Insert 10,000 random vectors
     let mut rng = rand::thread_rng();
-    let dim = 768usize;
     println!("Inserting 10_000 random vectors into LSH DB...");
 
     for _ in 0..10_000 {
         let random_vector = (0..dim).
             map(|_| rng.gen_range(-1f32..1f32)).
-            collect::<SimdVecImpl<f32x4, 192>>();
+            collect::<SimdVector<f32x4>>();
         match db.insert(random_vector) {
             Ok(_) => {},
             Err(_) => panic!("Error encountered while inserting to LSH DB.  This shouldn't have happened"),
@@ -40,21 +141,108 @@ async fn run_server(listener: TcpListener, shutdown: impl Future) {
     let (notify_shutdown, _) = broadcast::channel(1);
 
     //let (shutdown_sig, _) = broadcast::channel(1);
-    let max_connections = 255;
  
-    let mut server = Listener {
+    // All the listeners draw on the same connection budget and counters
+    let connection_limiter = Arc::new(Semaphore::new(config.max_connections));
+    let client_limiter = Arc::new(ClientLimiter::new(config.max_connections_per_client));
+    let stats = Arc::new(ServerStats::new());
+
+    let server = sockets.tcp.map(|listener| Listener {
         listener,
-        database: db_ptr.clone(),
-        connection_limiter: Arc::new(Semaphore::new(max_connections)),
-        shutdown_signal: notify_shutdown
+        datasets: datasets.clone(),
+        connection_limiter: connection_limiter.clone(),
+        client_limiter: client_limiter.clone(),
+        idle_timeout: config.idle_timeout(),
+        command_timeout: config.command_timeout(),
+        frame_limits: config.frame_limits,
+        stats: stats.clone(),
+        authenticator: authenticator.clone(),
+        tls_acceptor,
+        shutdown_signal: notify_shutdown.clone(),
+    });
+
+    let unix_server = sockets.unix.map(|listener| Listener {
+        listener,
+        datasets: datasets.clone(),
+        connection_limiter: connection_limiter.clone(),
+        client_limiter: client_limiter.clone(),
+        idle_timeout: config.idle_timeout(),
+        command_timeout: config.command_timeout(),
+        frame_limits: config.frame_limits,
+        stats: stats.clone(),
+        authenticator: authenticator.clone(),
+        tls_acceptor: None,
+        shutdown_signal: notify_shutdown,
+    });
+
+    let http_server = sockets.http.map(|listener| HttpListener {
+        listener,
+        datasets: datasets.clone(),
+        connection_limiter: connection_limiter.clone(),
+        client_limiter: client_limiter.clone(),
+        idle_timeout: config.idle_timeout(),
+        command_timeout: config.command_timeout(),
+        stats: stats.clone(),
+        authenticator: authenticator.clone(),
+    });
+
+    let grpc_server = sockets.grpc.map(|listener| {
+        let service = RushServer::new(GrpcService::new(datasets.clone(), stats.clone()).
+            with_authenticator(authenticator).
+            with_command_timeout(config.command_timeout()));
+        let incoming = grpc::incoming(listener, connection_limiter, client_limiter, stats);
+        Server::builder().add_service(service).serve_with_incoming(incoming)
+    });
+
+    let run_tcp = async {
+        match server {
+            Some(mut server) => server.run().await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let run_unix = async {
+        match unix_server {
+            Some(mut unix_server) => unix_server.run().await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let run_http = async {
+        match http_server {
+            Some(mut http_server) => http_server.run().await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let run_grpc = async {
+        match grpc_server {
+            Some(grpc_server) => grpc_server.await,
+            None => std::future::pending().await,
+        }
     };
     
     tokio::select! {
-        result = server.run() => {
+        result = run_tcp => {
             if let Err(err) = result {
                 println!("Oopsie woopsie! I made a fucky-wucky: {}", err);
             }
         }
+        result = run_unix => {
+            if let Err(err) = result {
+                error!(cause = %err, "unix socket listener stopped");
+            }
+        }
+        result = run_http => {
+            if let Err(err) = result {
+                error!(cause = %err, "http listener stopped");
+            }
+        }
+        result = run_grpc => {
+            if let Err(err) = result {
+                error!(cause = %err, "grpc server stopped");
+            }
+        }
         _ = shutdown => { 
             println!("Teehee! Bye bye!");
         }
@@ -0,0 +1,143 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/*
+
+Clients authenticate with a token, which grants one of two roles.  Tokens are
+loaded at startup from a JSON file of the form
+
+{
+    "tokens": [
+        { "token": "s3cret", "role": "read-write" },
+        { "token": "dashboards", "role": "read-only" }
+    ]
+}
+
+When no token file is given, authentication is disabled and every client is
+treated as read-write.  A token file must hold at least one token.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// May query the database, but not modify it.
+    ReadOnly,
+    ReadWrite,
+}
+
+impl Role {
+    pub fn can_write(self) -> bool {
+        self == Role::ReadWrite
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenFile {
+    tokens: Vec<TokenEntry>,
+}
+
+#[derive(Deserialize)]
+struct TokenEntry {
+    token: String,
+    role: Role,
+}
+
+/// The set of tokens accepted by the server.
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    tokens: Vec<(String, Role)>,
+}
+
+impl Authenticator {
+    /// An authenticator that lets every client in.
+    pub fn disabled() -> Self {
+        Authenticator::default()
+    }
+
+    pub fn new<I: IntoIterator<Item=(String, Role)>>(tokens: I) -> Self {
+        Authenticator { tokens: tokens.into_iter().collect() }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+        let file: TokenFile = serde_json::from_str(&contents).map_err(|err| format!("invalid token file {}: {}", path.display(), err))?;
+
+        // A token file is how authentication gets turned on, so one without
+        // tokens would leave the server open to everyone
+        if file.tokens.is_empty() {
+            return Err(format!("invalid token file {}: no tokens", path.display()).into());
+        }
+        if file.tokens.iter().any(|entry| entry.token.is_empty()) {
+            return Err(format!("invalid token file {}: tokens can't be empty", path.display()).into());
+        }
+        Ok(Authenticator::new(file.tokens.into_iter().map(|entry| (entry.token, entry.role))))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// The role of a connection that hasn't authenticated.
+    pub fn default_role(&self) -> Option<Role> {
+        if self.is_enabled() { None } else { Some(Role::ReadWrite) }
+    }
+
+    /// The role granted by `token`, if it's valid.
+    pub fn authenticate(&self, token: &str) -> Option<Role> {
+        // Every token is compared in full so that the time taken doesn't hint
+        // at how close a guess was.
+        let mut role = None;
+        for (candidate, candidate_role) in &self.tokens {
+            if constant_time_eq(candidate.as_bytes(), token.as_bytes()) {
+                role = Some(*candidate_role);
+            }
+        }
+        role
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod auth_test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_authenticate_roles() {
+        let auth = Authenticator::new(vec![("rw".to_string(), Role::ReadWrite), ("ro".to_string(), Role::ReadOnly)]);
+        assert!(auth.is_enabled());
+        assert_eq!(auth.default_role(), None);
+        assert_eq!(auth.authenticate("rw"), Some(Role::ReadWrite));
+        assert_eq!(auth.authenticate("ro"), Some(Role::ReadOnly));
+        assert_eq!(auth.authenticate("r"), None);
+        assert_eq!(auth.authenticate(""), None);
+
+        assert_eq!(Authenticator::disabled().default_role(), Some(Role::ReadWrite));
+    }
+
+    #[test]
+    fn test_load_token_file() {
+        let path = std::env::temp_dir().join(format!("rush-tokens-{}.json", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        write!(file, r#"{{"tokens": [{{"token": "abc", "role": "read-only"}}]}}"#).unwrap();
+
+        let auth = Authenticator::from_file(&path).unwrap();
+        assert_eq!(auth.authenticate("abc"), Some(Role::ReadOnly));
+
+        write!(fs::File::create(&path).unwrap(), r#"{{"tokens": [{{"token": "abc", "role": "admin"}}]}}"#).unwrap();
+        assert!(Authenticator::from_file(&path).is_err());
+
+        write!(fs::File::create(&path).unwrap(), r#"{{"tokens": []}}"#).unwrap();
+        assert!(Authenticator::from_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,27 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::mem;
+use crate::net::ServerError;
+
+// Vectors travel over the wire as bulk strings holding the little endian bytes
+// of each f32 element, in order.
+
+pub(crate) fn encode_vector<I: IntoIterator<Item=f32>>(elements: I) -> Bytes {
+    let elements = elements.into_iter();
+    let mut buf = BytesMut::with_capacity(mem::size_of::<f32>() * elements.size_hint().0);
+    for elt in elements {
+        buf.put_f32_le(elt);
+    }
+    buf.freeze()
+}
+
+pub(crate) fn decode_vector(mut blob: Bytes) -> crate::Result<Vec<f32>> {
+    if !blob.len().is_multiple_of(mem::size_of::<f32>()) {
+        return Err(ServerError::Validation("vector blob length must be a multiple of 4".into()).into());
+    }
+
+    let mut elements = Vec::with_capacity(blob.len() / mem::size_of::<f32>());
+    while blob.has_remaining() {
+        elements.push(blob.get_f32_le());
+    }
+    Ok(elements)
+}
@@ -0,0 +1,77 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::net::{FrameLimits, TlsConfig};
+
+/// Connection limits applied by the `Listener`, and the optional HTTP and
+/// gRPC APIs.  These are fixed for the lifetime of the server and are read
+/// once at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Maximum number of concurrently open connections across all clients.
+    pub max_connections: usize,
+    /// Maximum number of concurrently open connections from a single source IP.
+    pub max_connections_per_client: usize,
+    /// Close connections that haven't sent a frame in this many seconds.  Zero
+    /// disables the timeout.
+    pub idle_timeout_secs: u64,
+    /// Answer commands that take longer than this many milliseconds, waiting
+    /// for the database included, with a timeout error.  Zero disables the
+    /// default, though clients can still set their own.
+    pub command_timeout_ms: u64,
+    /// Serve RESP clients on TCP.  Turning this off only makes sense along
+    /// with a `unix_socket`.
+    pub tcp: bool,
+    /// Also serve RESP clients on a Unix domain socket at this path, for
+    /// clients on the same host.
+    pub unix_socket: Option<PathBuf>,
+    /// Also serve the HTTP/JSON API on this port.  The API is disabled when
+    /// no port is given.
+    pub http_port: Option<u16>,
+    /// Also serve the gRPC API on this port.  The API is disabled when no port
+    /// is given.
+    pub grpc_port: Option<u16>,
+    /// Bounds on the size and shape of frames accepted from clients.
+    pub frame_limits: FrameLimits,
+    /// JSON file of client tokens and their roles.  Authentication is
+    /// disabled when no file is given.
+    pub auth_file: Option<PathBuf>,
+    /// Serve the TCP listener over TLS.  Connections are plaintext when no
+    /// certificate is given, and the Unix socket is always plaintext.
+    pub tls: Option<TlsConfig>,
+}
+
+impl ServerConfig {
+    pub fn command_timeout(&self) -> Option<Duration> {
+        match self.command_timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.idle_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_connections: 255,
+            max_connections_per_client: 16,
+            idle_timeout_secs: 300,
+            command_timeout_ms: 0,
+            tcp: true,
+            unix_socket: None,
+            http_port: None,
+            grpc_port: None,
+            frame_limits: FrameLimits::default(),
+            auth_file: None,
+            tls: None,
+        }
+    }
+}
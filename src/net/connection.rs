@@ -1,25 +1,56 @@
 // This source code taken directly from https://github.com/tokio-rs/mini-redis/blob/master/src/frame.rs
 // I would have reproduced the MIT licence if they had a notice, but they did not.
-use crate::net::frame::Frame;
+use crate::net::frame::{self, Frame, FrameLimits, Protocol};
+use crate::net::ServerError;
 use bytes::{Buf, BytesMut};
 use std::io::{self, Cursor};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 use tokio::net::TcpStream;
 
+/// Reads and writes frames over a byte stream, usually a `TcpStream` or a TLS
+/// session wrapping one.
 #[derive(Debug)]
-pub struct Connection {
-    stream: BufWriter<TcpStream>,
+pub struct Connection<S = TcpStream> {
+    stream: BufWriter<S>,
     buffer: BytesMut,
+    protocol: Protocol,
+    limits: FrameLimits,
 }
    
-impl Connection {
-    pub fn new(socket: TcpStream) -> Connection {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(socket: S) -> Connection<S> {
+        Connection::with_limits(socket, FrameLimits::default())
+    }
+
+    pub fn with_limits(socket: S, limits: FrameLimits) -> Connection<S> {
         Connection {
             stream: BufWriter::new(socket),
             buffer: BytesMut::with_capacity(16_384),
+            protocol: Protocol::Resp2,
+            limits,
         }
     }
 
+    pub fn limits(&self) -> FrameLimits {
+        self.limits
+    }
+
+    /// Give back the underlying stream.  Anything buffered but not yet parsed
+    /// into a frame is lost.
+    pub fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Switch the protocol that frames are written in.  Frames are always read
+    /// in whichever protocol the peer sends them.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
     pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
         loop {
             if let Some(frame) = self.parse_frame()? {
@@ -33,6 +64,11 @@ impl Connection {
                     return Err("connection reset by peer".into());
                 }
             }
+
+            // Whatever is buffered is still an incomplete frame
+            if self.buffer.len() > self.limits.max_buffer_size {
+                return Err(ServerError::Protocol(format!("frame larger than {} bytes", self.limits.max_buffer_size)).into());
+            }
         }
     }
 
@@ -41,33 +77,24 @@ impl Connection {
 
         let mut buf = Cursor::new(&self.buffer[..]);
 
-        match Frame::check(&mut buf) {
+        match Frame::check(&mut buf, &self.limits) {
             Ok(_) => {
                 let len = buf.position() as usize;
 
                 buf.set_position(0);
 
-                let frame = Frame::parse(&mut buf)?;
+                let frame = Frame::parse(&mut buf).map_err(into_error)?;
 
                 self.buffer.advance(len);
                 Ok(Some(frame))
             }
             Err(Incomplete()) => Ok(None),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(into_error(e)),
         }
     }
 
     pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
-        match frame {
-            Frame::Array(val) => {
-                self.stream.write_u8(b'*').await?;
-                self.write_decimal(val.len() as u64).await?;
-                for entry in &**val {
-                    self.write_value(entry).await?;
-                }
-            }
-            _ => self.write_value(frame).await?,
-        }
+        self.write_value(frame).await?;
         self.stream.flush().await
     }
 
@@ -88,24 +115,89 @@ impl Connection {
                 self.stream.write_u8(b':').await?;
                 self.write_decimal(*val).await?;
             }
-            Frame::Null() => {
-                self.stream.write_all(b"$-1\r\n").await?;
+            Frame::Null() => match self.protocol {
+                Protocol::Resp2 => self.stream.write_all(b"$-1\r\n").await?,
+                Protocol::Resp3 => self.stream.write_all(b"_\r\n").await?,
+            },
+            Frame::Bulk(val) => self.write_bulk(val).await?,
+            Frame::Array(val) => {
+                self.stream.write_u8(b'*').await?;
+                self.write_decimal(val.len() as i64).await?;
+                // Responses to bulk and top-k requests nest arrays
+                for entry in val {
+                    Box::pin(self.write_value(entry)).await?;
+                }
+            }
+            Frame::Double(val) => {
+                let repr = if val.is_nan() { "nan".to_string() } else { val.to_string() };
+                match self.protocol {
+                    Protocol::Resp2 => self.write_bulk(repr.as_bytes()).await?,
+                    Protocol::Resp3 => {
+                        self.stream.write_u8(b',').await?;
+                        self.stream.write_all(repr.as_bytes()).await?;
+                        self.stream.write_all(b"\r\n").await?;
+                    }
+                }
             }
-            Frame::Bulk(val) => {
-                let len = val.len();
-
-                self.stream.write_u8(b'$').await?;
-                self.write_decimal(len as u64).await?;
-                self.stream.write_all(val).await?;
-                self.stream.write_all(b"\r\n").await?;
+            Frame::Boolean(val) => match self.protocol {
+                Protocol::Resp2 => {
+                    self.stream.write_u8(b':').await?;
+                    self.write_decimal(*val as i64).await?;
+                }
+                Protocol::Resp3 => {
+                    self.stream.write_all(if *val { b"#t\r\n" } else { b"#f\r\n" }).await?;
+                }
+            },
+            Frame::BigNumber(val) => match self.protocol {
+                Protocol::Resp2 => self.write_bulk(val.as_bytes()).await?,
+                Protocol::Resp3 => {
+                    self.stream.write_u8(b'(').await?;
+                    self.stream.write_all(val.as_bytes()).await?;
+                    self.stream.write_all(b"\r\n").await?;
+                }
+            },
+            Frame::Map(pairs) => {
+                match self.protocol {
+                    Protocol::Resp2 => {
+                        self.stream.write_u8(b'*').await?;
+                        self.write_decimal(2 * pairs.len() as i64).await?;
+                    }
+                    Protocol::Resp3 => {
+                        self.stream.write_u8(b'%').await?;
+                        self.write_decimal(pairs.len() as i64).await?;
+                    }
+                }
+                self.write_pairs(pairs).await?;
+            }
+            Frame::Attribute(pairs, frame) => {
+                if self.protocol == Protocol::Resp3 {
+                    self.stream.write_u8(b'|').await?;
+                    self.write_decimal(pairs.len() as i64).await?;
+                    self.write_pairs(pairs).await?;
+                }
+                Box::pin(self.write_value(frame)).await?;
             }
-            Frame::Array(_val) => unreachable!(),
         }
 
         Ok(())
     }
 
-    async fn write_decimal(&mut self, val: u64) -> io::Result<()> {
+    async fn write_bulk(&mut self, val: &[u8]) -> io::Result<()> {
+        self.stream.write_u8(b'$').await?;
+        self.write_decimal(val.len() as i64).await?;
+        self.stream.write_all(val).await?;
+        self.stream.write_all(b"\r\n").await
+    }
+
+    async fn write_pairs(&mut self, pairs: &[(Frame, Frame)]) -> io::Result<()> {
+        for (key, value) in pairs {
+            Box::pin(self.write_value(key)).await?;
+            Box::pin(self.write_value(value)).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_decimal(&mut self, val: i64) -> io::Result<()> {
         use std::io::Write;
 
         // Convert the value to a string
@@ -120,3 +212,11 @@ impl Connection {
         Ok(())
     }
 }
+
+// Unwraps parse errors so a `ServerError` inside keeps its kind.
+fn into_error(err: frame::Error) -> crate::Error {
+    match err {
+        frame::Error::Other(err) => err,
+        err => err.into(),
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock as StdRwLock};
+use tokio::sync::RwLock;
+use crate::lsh::{Database, DatasetConfig};
+use crate::lsh::vector::Vector;
+use crate::net::{Frame, Reply, ServerError};
+
+/*
+
+A server hosts any number of named datasets, each a database of its own with
+its own lock, laid out as it was asked to be when created:
+
+    CREATE <dataset> <dimension> [K <bits>] [L <tables>] [METRIC euclidean|cosine|dot] [HASH dense|hadamard|sparse] [DENSITY <fraction>]
+    DROP <dataset>
+    LIST
+
+The options are simple strings followed by their value, with K and L integers,
+the metric and hash family simple strings, and the density of sparse hashes a
+float given as a simple string.  Commands already running against a dataset
+that is dropped finish against it, but no new ones can reach it.
+
+*/
+
+// Reads the options that follow the dimension in a CREATE command.
+fn parse_config(dimension: Option<Frame>, mut options: impl Iterator<Item=Frame>) -> crate::Result<DatasetConfig> {
+    let mut config = match dimension {
+        Some(Frame::Integer(dimension)) if dimension > 0 => DatasetConfig::new(dimension as usize),
+        Some(Frame::Integer(_)) => return Err(ServerError::Validation("dimension must be positive".into()).into()),
+        _ => return Err(ServerError::Protocol("expected integer dimension".into()).into()),
+    };
+
+    while let Some(option) = options.next() {
+        match (option, options.next()) {
+            (Frame::Simple(name), Some(Frame::Integer(value))) if name.eq_ignore_ascii_case("k") && value >= 0 => {
+                config.hash_bits = value as usize;
+            }
+            (Frame::Simple(name), Some(Frame::Integer(value))) if name.eq_ignore_ascii_case("l") && value >= 0 => {
+                config.tables = value as usize;
+            }
+            (Frame::Simple(name), Some(Frame::Simple(metric))) if name.eq_ignore_ascii_case("metric") => {
+                config.metric = metric.parse().map_err(ServerError::Validation)?;
+            }
+            (Frame::Simple(name), Some(Frame::Simple(hash))) if name.eq_ignore_ascii_case("hash") => {
+                config.hash = hash.parse().map_err(ServerError::Validation)?;
+            }
+            (Frame::Simple(name), Some(Frame::Simple(density))) if name.eq_ignore_ascii_case("density") => {
+                let density = density.parse().map_err(|_| ServerError::Validation(format!("invalid density {}", density)))?;
+                config.density = Some(density);
+            }
+            (option, _) => return Err(ServerError::Protocol(format!("invalid CREATE option {}", option)).into()),
+        }
+    }
+    Ok(config)
+}
+
+/// A named database along with the layout it was created with.
+pub struct Dataset<DB> {
+    config: DatasetConfig,
+    database: RwLock<DB>,
+}
+
+impl<DB: Database> Dataset<DB> {
+    pub fn config(&self) -> &DatasetConfig {
+        &self.config
+    }
+
+    pub fn database(&self) -> &RwLock<DB> {
+        &self.database
+    }
+
+    /// Checks a vector sent by a client fits the dataset.
+    pub(crate) fn item(&self, vector: Vec<f32>) -> crate::Result<DB::Item>
+    where
+        DB::Item: Vector<DType=f32>,
+        for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+    {
+        if vector.len() != self.config.dimension {
+            let msg = format!("expected a vector of dimension {}, got {}", self.config.dimension, vector.len());
+            return Err(ServerError::Validation(msg).into());
+        }
+        Ok(vector.into_iter().collect())
+    }
+}
+
+/// Every dataset on the server, by name.  The map is only locked long enough
+/// to look a dataset up, and each dataset has a lock of its own.
+pub struct Datasets<DB> {
+    datasets: StdRwLock<HashMap<String, Arc<Dataset<DB>>>>,
+}
+
+impl<DB: Database> Datasets<DB> {
+    pub fn new() -> Self {
+        Datasets { datasets: StdRwLock::new(HashMap::new()) }
+    }
+
+    pub fn create(&self, name: &str, config: DatasetConfig) -> crate::Result<Arc<Dataset<DB>>> {
+        let database = DB::create(&config).map_err(|err| ServerError::from_error(&err))?;
+        let mut datasets = self.datasets.write().unwrap();
+        if datasets.contains_key(name) {
+            return Err(ServerError::Validation(format!("dataset '{}' already exists", name)).into());
+        }
+
+        let dataset = Arc::new(Dataset { config, database: RwLock::new(database) });
+        datasets.insert(name.to_string(), dataset.clone());
+        Ok(dataset)
+    }
+
+    pub fn drop_dataset(&self, name: &str) -> crate::Result<()> {
+        match self.datasets.write().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(not_found(name)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> crate::Result<Arc<Dataset<DB>>> {
+        self.datasets.read().unwrap().get(name).cloned().ok_or_else(|| not_found(name))
+    }
+
+    /// The names of every dataset, in order.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.datasets.read().unwrap().keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    /// The number of vectors across every dataset.
+    pub async fn vectors(&self) -> usize {
+        let datasets = self.datasets.read().unwrap().values().cloned().collect::<Vec<Arc<Dataset<DB>>>>();
+        let mut len = 0;
+        for dataset in datasets {
+            len += dataset.database.read().await.len();
+        }
+        len
+    }
+}
+
+impl<DB: Database> Default for Datasets<DB> {
+    fn default() -> Self {
+        Datasets::new()
+    }
+}
+
+fn not_found(name: &str) -> crate::Error {
+    ServerError::NotFound(format!("no such dataset '{}'", name)).into()
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct CreateDataset {
+    name: String,
+    config: DatasetConfig,
+}
+
+impl CreateDataset {
+    pub(crate) fn new(name: String, config: DatasetConfig) -> Self {
+        CreateDataset { name, config }
+    }
+
+    pub(crate) fn parse(name: String, mut args: impl Iterator<Item=Frame>) -> crate::Result<Self> {
+        let dimension = args.next();
+        Ok(CreateDataset::new(name, parse_config(dimension, args)?))
+    }
+
+    pub(crate) async fn execute<DB: Database>(self, datasets: &Datasets<DB>) -> crate::Result<Reply> {
+        datasets.create(&self.name, self.config)?;
+        Ok(Reply::Ok)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct DropDataset {
+    name: String,
+}
+
+impl DropDataset {
+    pub(crate) fn new(name: String) -> Self {
+        DropDataset { name }
+    }
+
+    pub(crate) async fn execute<DB: Database>(self, datasets: &Datasets<DB>) -> crate::Result<Reply> {
+        datasets.drop_dataset(&self.name)?;
+        Ok(Reply::Ok)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ListDatasets;
+
+impl ListDatasets {
+    // Replies with the dataset names, in order.
+    pub(crate) async fn execute<DB: Database>(self, datasets: &Datasets<DB>) -> crate::Result<Reply> {
+        Ok(Reply::Datasets(datasets.names()))
+    }
+}
+
+#[cfg(test)]
+mod dataset_test {
+    use super::*;
+    use crate::lsh::{HashFamily, LocalitySensitiveHashDatabase, Metric};
+    use crate::simd::vec::SimdVecImpl;
+    use crate::simd::f32x4;
+
+    type Database = LocalitySensitiveHashDatabase<SimdVecImpl<f32x4, 4>>;
+
+    fn code(err: crate::Error) -> &'static str {
+        err.downcast_ref::<ServerError>().unwrap().code()
+    }
+
+    #[test]
+    fn test_registry() {
+        let datasets = Datasets::<Database>::new();
+        datasets.create("b", DatasetConfig::new(16)).unwrap();
+        datasets.create("a", DatasetConfig::new(16).with_metric(Metric::Cosine)).unwrap();
+        assert_eq!(datasets.names(), vec!["a", "b"]);
+        assert_eq!(datasets.get("a").unwrap().config().metric, Metric::Cosine);
+
+        assert_eq!(code(datasets.create("a", DatasetConfig::new(16)).err().unwrap()), "INVALID");
+        assert_eq!(code(datasets.create("c", DatasetConfig::new(16).with_hash_bits(65)).err().unwrap()), "INVALID");
+        assert_eq!(code(datasets.create("c", DatasetConfig::new(16).with_density(0.5)).err().unwrap()), "INVALID");
+        let sparse = DatasetConfig::new(16).with_hash(HashFamily::Sparse);
+        assert_eq!(code(datasets.create("c", sparse.clone().with_density(1.5)).err().unwrap()), "INVALID");
+        datasets.create("c", sparse.with_density(0.5)).unwrap();
+        datasets.drop_dataset("c").unwrap();
+
+        datasets.drop_dataset("a").unwrap();
+        assert_eq!(code(datasets.get("a").err().unwrap()), "NOTFOUND");
+        assert_eq!(code(datasets.drop_dataset("a").err().unwrap()), "NOTFOUND");
+        assert_eq!(datasets.names(), vec!["b"]);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let options = vec![
+            Frame::Simple("L".into()), Frame::Integer(8),
+            Frame::Simple("metric".into()), Frame::Simple("dot".into()),
+            Frame::Simple("HASH".into()), Frame::Simple("Hadamard".into()),
+        ];
+        let config = parse_config(Some(Frame::Integer(16)), options.into_iter()).unwrap();
+        assert_eq!(config, DatasetConfig::new(16).with_tables(8).with_metric(Metric::Dot).with_hash(HashFamily::Hadamard));
+
+        let options = vec![
+            Frame::Simple("HASH".into()), Frame::Simple("sparse".into()),
+            Frame::Simple("DENSITY".into()), Frame::Simple("0.25".into()),
+        ];
+        let config = parse_config(Some(Frame::Integer(16)), options.into_iter()).unwrap();
+        assert_eq!(config, DatasetConfig::new(16).with_hash(HashFamily::Sparse).with_density(0.25));
+
+        assert!(parse_config(Some(Frame::Integer(0)), vec![].into_iter()).is_err());
+        assert!(parse_config(Some(Frame::Integer(16)), vec![Frame::Simple("K".into())].into_iter()).is_err());
+        let options = vec![Frame::Simple("HASH".into()), Frame::Simple("gaussian".into())];
+        assert!(parse_config(Some(Frame::Integer(16)), options.into_iter()).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::time::{self, Instant};
+use crate::net::{Frame, ServerError};
+
+/*
+
+Every command may carry a deadline, after which it is answered with a timeout
+error instead of its result.  The server applies a default, and clients can
+pick their own by ending a command with
+
+    +TIMEOUT\r\n:<milliseconds>\r\n
+
+A deadline covers waiting for the database lock and running the query, which
+checks it between batches of candidates and stops scoring once it passes.
+Writes that get the lock in time are always applied, since reporting a timeout
+for a write that went through would be a lie.
+
+*/
+
+pub(crate) fn timeout() -> crate::Error {
+    ServerError::Timeout("command missed its deadline".into()).into()
+}
+
+/// The instant a command must finish by, when it has a timeout at all.
+pub(crate) fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+pub(crate) fn expired(deadline: Option<Instant>) -> bool {
+    matches!(deadline, Some(deadline) if Instant::now() >= deadline)
+}
+
+/// Take a trailing `TIMEOUT ms` option off the end of a command.
+pub(crate) fn split_timeout(array: &mut Vec<Frame>) -> crate::Result<Option<Duration>> {
+    let len = array.len();
+    if len < 2 || !matches!(&array[len - 2], Frame::Simple(name) if name.eq_ignore_ascii_case("timeout")) {
+        return Ok(None);
+    }
+
+    let timeout = match array[len - 1] {
+        Frame::Integer(ms) if ms > 0 => Duration::from_millis(ms as u64),
+        Frame::Integer(_) => return Err(ServerError::Validation("timeout must be positive".into()).into()),
+        _ => return Err(ServerError::Protocol("expected integer timeout in milliseconds".into()).into()),
+    };
+    array.truncate(len - 2);
+    Ok(Some(timeout))
+}
+
+/// Wait for the read lock, giving up at the deadline.
+pub(crate) async fn read<DB>(db: &RwLock<DB>, deadline: Option<Instant>) -> Option<RwLockReadGuard<'_, DB>> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, db.read()).await.ok(),
+        None => Some(db.read().await),
+    }
+}
+
+/// Wait for the write lock, giving up at the deadline.
+pub(crate) async fn write<DB>(db: &RwLock<DB>, deadline: Option<Instant>) -> Option<RwLockWriteGuard<'_, DB>> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, db.write()).await.ok(),
+        None => Some(db.write().await),
+    }
+}
+
+#[cfg(test)]
+mod deadline_test {
+    use super::*;
+
+    #[test]
+    fn test_split_timeout() {
+        let mut array = vec![Frame::Simple("STATS".into()), Frame::Simple("TIMEOUT".into()), Frame::Integer(250)];
+        assert_eq!(split_timeout(&mut array).unwrap(), Some(Duration::from_millis(250)));
+        assert_eq!(array.len(), 1);
+
+        // Nothing to take off
+        assert_eq!(split_timeout(&mut array).unwrap(), None);
+        assert_eq!(array.len(), 1);
+
+        let mut array = vec![Frame::Simple("STATS".into()), Frame::Simple("timeout".into()), Frame::Integer(-1)];
+        assert!(split_timeout(&mut array).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lock_gives_up_at_deadline() {
+        let db = RwLock::new(0);
+        let guard = db.write().await;
+        assert!(read(&db, deadline(Some(Duration::from_millis(10)))).await.is_none());
+        assert!(write(&db, deadline(Some(Duration::from_millis(10)))).await.is_none());
+        drop(guard);
+        assert!(read(&db, deadline(Some(Duration::from_millis(10)))).await.is_some());
+    }
+}
@@ -0,0 +1,35 @@
+use tokio::time::Instant;
+use crate::net::{deadline, Datasets, Database, Reply};
+use crate::lsh::vector::Vector;
+
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Delete {
+    dataset: String,
+    vector: Vec<f32>
+}
+
+impl Delete {
+    pub(crate) fn new(dataset: String, vector: Vec<f32>) -> Self {
+        Delete { dataset, vector }
+    }
+
+    // Replies with whether the vector was there to remove.
+    pub(crate) async fn execute<DB: Database>(self, datasets: &Datasets<DB>, deadline: Option<Instant>) -> crate::Result<Reply>
+    where
+        DB::Item: Vector<DType=f32>,
+        for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>,
+    {
+        let dataset = datasets.get(&self.dataset)?;
+        let item = dataset.item(self.vector)?;
+
+        let mut db = match deadline::write(dataset.database(), deadline).await {
+            Some(db) => db,
+            None => return Err(deadline::timeout()),
+        };
+        let removed = db.delete(&item);
+        drop(db); 
+
+        Ok(Reply::Deleted(removed))
+    }
+}
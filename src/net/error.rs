@@ -0,0 +1,156 @@
+use std::fmt;
+use crate::lsh::ValidationError;
+use crate::net::Frame;
+
+/*
+
+Errors sent to clients start with a code naming what kind of error it is,
+followed by a message meant for people:
+
+    -PROTOCOL expected vector blob\r\n
+
+The codes are stable, so clients can branch on them.  The messages are not.
+
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerError {
+    /// The client broke the protocol, e.g. by sending the wrong kind of frame.
+    Protocol(String),
+    /// A well formed command with an argument the server can't accept.
+    Validation(String),
+    /// The command refers to something that doesn't exist.
+    NotFound(String),
+    /// The command missed its deadline.
+    Timeout(String),
+    /// The server is at one of its limits.
+    Capacity(String),
+    /// The client hasn't authenticated, or its token is wrong.
+    Unauthenticated(String),
+    /// The client's token doesn't allow the command.
+    Forbidden(String),
+    /// Something went wrong in the server itself.
+    Internal(String),
+}
+
+impl ServerError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::Protocol(_) => "PROTOCOL",
+            ServerError::Validation(_) => "INVALID",
+            ServerError::NotFound(_) => "NOTFOUND",
+            ServerError::Timeout(_) => "TIMEOUT",
+            ServerError::Capacity(_) => "CAPACITY",
+            ServerError::Unauthenticated(_) => "NOAUTH",
+            ServerError::Forbidden(_) => "NOPERM",
+            ServerError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ServerError::Protocol(msg) |
+            ServerError::Validation(msg) |
+            ServerError::NotFound(msg) |
+            ServerError::Timeout(msg) |
+            ServerError::Capacity(msg) |
+            ServerError::Unauthenticated(msg) |
+            ServerError::Forbidden(msg) |
+            ServerError::Internal(msg) => msg,
+        }
+    }
+
+    /// Reads an error back from the text of an error frame.  Errors without
+    /// one of the codes above, such as Redis style `ERR` replies, give `None`.
+    pub fn parse(text: &str) -> Option<ServerError> {
+        let (code, msg) = match text.find(' ') {
+            Some(space) => (&text[..space], text[space + 1..].to_string()),
+            None => (text, String::new()),
+        };
+
+        let err = match code {
+            "PROTOCOL" => ServerError::Protocol(msg),
+            "INVALID" => ServerError::Validation(msg),
+            "NOTFOUND" => ServerError::NotFound(msg),
+            "TIMEOUT" => ServerError::Timeout(msg),
+            "CAPACITY" => ServerError::Capacity(msg),
+            "NOAUTH" => ServerError::Unauthenticated(msg),
+            "NOPERM" => ServerError::Forbidden(msg),
+            "INTERNAL" => ServerError::Internal(msg),
+            _ => return None,
+        };
+        Some(err)
+    }
+
+    /// Classifies an error on its way to a client.  A database refusing what
+    /// it was given is the client's fault; anything else that isn't already a
+    /// `ServerError` is the server's own.
+    pub(crate) fn from_error(err: &crate::Error) -> ServerError {
+        if let Some(err) = err.downcast_ref::<ServerError>() {
+            return err.clone();
+        }
+        match err.downcast_ref::<ValidationError>() {
+            Some(ValidationError(msg)) => ServerError::Validation(msg.clone()),
+            None => ServerError::Internal(err.to_string()),
+        }
+    }
+
+    /// Turns the text of an error frame into an error, keeping its kind when
+    /// it has one.
+    pub(crate) fn from_frame(text: String) -> crate::Error {
+        match ServerError::parse(&text) {
+            Some(err) => err.into(),
+            None => text.into(),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} {}", self.code(), self.message())
+    }
+}
+
+impl From<ServerError> for Frame {
+    fn from(err: ServerError) -> Frame {
+        Frame::Error(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod error_test {
+    use super::*;
+
+    #[test]
+    fn test_parse_round_trip() {
+        let errors = vec![
+            ServerError::Protocol("expected vector blob".into()),
+            ServerError::Validation("vector blob length must be a multiple of 4".into()),
+            ServerError::NotFound("no such dataset".into()),
+            ServerError::Timeout("command missed its deadline".into()),
+            ServerError::Capacity("too many connections from client".into()),
+            ServerError::Unauthenticated("authentication required".into()),
+            ServerError::Forbidden("token is read-only".into()),
+            ServerError::Internal("something broke".into()),
+        ];
+        for err in errors {
+            assert_eq!(ServerError::parse(&err.to_string()), Some(err));
+        }
+
+        assert_eq!(ServerError::parse("ERR unknown command 'NOPE'"), None);
+    }
+
+    #[test]
+    fn test_from_error() {
+        let err: crate::Error = ServerError::NotFound("no such dataset".into()).into();
+        assert_eq!(ServerError::from_error(&err).code(), "NOTFOUND");
+
+        let err: crate::Error = ValidationError("L must be at least 1".into()).into();
+        assert_eq!(ServerError::from_error(&err), ServerError::Validation("L must be at least 1".into()));
+
+        let err: crate::Error = "disk on fire".into();
+        assert_eq!(ServerError::from_error(&err), ServerError::Internal("disk on fire".into()));
+    }
+}
@@ -6,17 +6,65 @@ use std::fmt;
 use std::io::Cursor;
 use std::num::TryFromIntError;
 use std::string::FromUtf8Error;
-use std::cmp::{Ord, Ordering};
+use std::cmp::{self, Ord, Ordering};
+use serde::Deserialize;
+use crate::net::ServerError;
 
-/// A frame in the Redis protocol.
+/// A frame in the Redis protocol.  Doubles, booleans, maps, big numbers and
+/// attributes only exist in RESP3; see `Protocol` for how they are sent to
+/// RESP2 clients.
 #[derive(Clone, Debug)]
 pub enum Frame {
     Simple(String),
     Error(String),
-    Integer(u64),
+    Integer(i64),
     Bulk(Bytes),
     Null(),
     Array(Vec<Frame>),
+    Double(f64),
+    Boolean(bool),
+    Map(Vec<(Frame, Frame)>),
+    BigNumber(String),
+    /// Out of band key/value pairs describing the frame that follows them.
+    Attribute(Vec<(Frame, Frame)>, Box<Frame>),
+}
+
+/// The protocol version spoken on a connection.  Connections start out on
+/// RESP2, and Redis clients can switch to RESP3 with HELLO.  Frames that only
+/// exist in RESP3 are sent to RESP2 clients as their nearest RESP2 equivalent:
+/// doubles and big numbers as bulk strings, booleans as integers, maps as flat
+/// arrays of keys and values, and attributes are dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+/// Bounds on the frames a `Connection` will accept, so that a misbehaving
+/// client can't make the server buffer or allocate without limit.  Frames that
+/// break them are rejected with a protocol error.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct FrameLimits {
+    /// Largest number of bytes buffered while waiting for a frame to complete.
+    pub max_buffer_size: usize,
+    /// Largest bulk string, in bytes.
+    pub max_bulk_len: usize,
+    /// Most elements in an array, or pairs in a map or attribute.
+    pub max_array_len: usize,
+    /// Deepest nesting of arrays, maps and attributes.
+    pub max_depth: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_buffer_size: 64 * 1024 * 1024,
+            max_bulk_len: 16 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_depth: 8,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -26,43 +74,9 @@ pub enum Error {
 }
 
 impl Frame {
-    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
-        match get_u8(src)? {
-            b'+' => {
-                get_line(src)?;
-                Ok(())
-            }
-            b'-' => {
-                get_line(src)?;
-                Ok(())
-            }
-            b':' => {
-                let _ = get_decimal(src)?;
-                Ok(())
-            }
-            b'$' => {
-                if b'-' == peek_u8(src)? {
-                    // Skip '-1\r\n'
-                    skip(src, 4)
-                } else {
-                    // Read the bulk string
-                    let len: usize = get_decimal(src)?.try_into()?;
-
-                    // skip that number of bytes + 2 (\r\n).
-                    skip(src, len + 2)
-                }
-            }
-            b'*' => {
-                let len = get_decimal(src)?;
-
-                for _ in 0..len {
-                    Frame::check(src)?;
-                }
-
-                Ok(())
-            }
-            actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
-        }
+    /// Checks that a whole frame within `limits` is buffered in `src`.
+    pub fn check(src: &mut Cursor<&[u8]>, limits: &FrameLimits) -> Result<(), Error> {
+        check_nested(src, limits, 0)
     }
 
     pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
@@ -85,23 +99,30 @@ impl Frame {
 
                 Ok(Frame::Error(string))
             }
-            b':' => {
-                let len = get_decimal(src)?;
-                Ok(Frame::Integer(len))
+            b':' => Ok(Frame::Integer(get_signed(src)?)),
+            b',' => Ok(Frame::Double(get_double(src)?)),
+            b'#' => Ok(Frame::Boolean(get_boolean(src)?)),
+            b'(' => Ok(Frame::BigNumber(get_big_number(src)?)),
+            b'_' => {
+                if !get_line(src)?.is_empty() {
+                    return Err("invalid frame format".into());
+                }
+
+                Ok(Frame::Null())
             }
             b'$' => {
                 if b'-' == peek_u8(src)? {
                     let line = get_line(src)?;
 
                     if line != b"-1" {
-                        return Err("protocol error; invalid frame format".into());
+                        return Err("invalid frame format".into());
                     }
 
                     Ok(Frame::Null())
                 } else {
                     // Read the bulk string
-                    let len = get_decimal(src)?.try_into()?;
-                    let n = len + 2;
+                    let len: usize = get_decimal(src)?.try_into()?;
+                    let n = len.checked_add(2).ok_or("invalid frame format")?;
 
                     if src.remaining() < n {
                         return Err(Error::Incomplete());
@@ -117,7 +138,9 @@ impl Frame {
             }
             b'*' => {
                 let len = get_decimal(src)?.try_into()?;
-                let mut out = Vec::with_capacity(len);
+                // Every element takes at least three bytes, which bounds the
+                // allocation even when `check` wasn't called first
+                let mut out = Vec::with_capacity(cmp::min(len, src.remaining() / 3));
 
                 for _ in 0..len {
                     out.push(Frame::parse(src)?);
@@ -125,12 +148,19 @@ impl Frame {
 
                 Ok(Frame::Array(out))
             }
-            _ => unimplemented!(),
+            b'%' => Ok(Frame::Map(parse_pairs(src)?)),
+            b'|' => {
+                let attributes = parse_pairs(src)?;
+                let frame = Frame::parse(src)?;
+
+                Ok(Frame::Attribute(attributes, Box::new(frame)))
+            }
+            actual => Err(format!("invalid frame type byte `{}`", actual).into()),
         }
     }
 
     pub(crate) fn to_error(&self) -> crate::Error {
-        format!("unexpected frame: {}", self).into()
+        ServerError::Protocol(format!("unexpected frame: {}", self)).into()
     }
 }
 
@@ -157,6 +187,9 @@ impl fmt::Display for Frame {
                 Err(_) => write!(fmt, "{:?}", msg),
             },
             Frame::Null() => "(nil)".fmt(fmt),
+            Frame::Double(num) => num.fmt(fmt),
+            Frame::Boolean(val) => val.fmt(fmt),
+            Frame::BigNumber(num) => num.fmt(fmt),
             Frame::Array(parts) => {
                 for (i, part) in parts.iter().enumerate() {
                     if i > 0 {
@@ -165,12 +198,121 @@ impl fmt::Display for Frame {
                     }
                 }
 
+                Ok(())
+            }
+            Frame::Map(pairs) | Frame::Attribute(pairs, _) => {
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{}: {}", key, value)?;
+                }
+
+                if let Frame::Attribute(_, frame) = self {
+                    write!(fmt, " | {}", frame)?;
+                }
+
                 Ok(())
             }
         }
     }
 }
 
+fn check_nested(src: &mut Cursor<&[u8]>, limits: &FrameLimits, depth: usize) -> Result<(), Error> {
+    if depth > limits.max_depth {
+        return Err(format!("frames nested deeper than {}", limits.max_depth).into());
+    }
+
+    match get_u8(src)? {
+        b'+' | b'-' | b'_' => {
+            get_line(src)?;
+            Ok(())
+        }
+        b':' => {
+            let _ = get_signed(src)?;
+            Ok(())
+        }
+        b',' => {
+            let _ = get_double(src)?;
+            Ok(())
+        }
+        b'#' => {
+            let _ = get_boolean(src)?;
+            Ok(())
+        }
+        b'(' => {
+            let _ = get_big_number(src)?;
+            Ok(())
+        }
+        b'$' => {
+            if b'-' == peek_u8(src)? {
+                // Skip '-1\r\n'
+                skip(src, 4)
+            } else {
+                // Read the bulk string
+                let len: usize = get_decimal(src)?.try_into()?;
+                if len > limits.max_bulk_len {
+                    return Err(format!("bulk string longer than {} bytes", limits.max_bulk_len).into());
+                }
+
+                // skip that number of bytes + 2 (\r\n).
+                skip(src, len + 2)
+            }
+        }
+        b'*' => {
+            let len = get_length(src, limits)?;
+
+            for _ in 0..len {
+                check_nested(src, limits, depth + 1)?;
+            }
+
+            Ok(())
+        }
+        b'%' => {
+            let len = get_length(src, limits)?;
+
+            for _ in 0..2 * len {
+                check_nested(src, limits, depth + 1)?;
+            }
+
+            Ok(())
+        }
+        b'|' => {
+            let len = get_length(src, limits)?;
+
+            // The attributes, then the frame they describe
+            for _ in 0..2 * len + 1 {
+                check_nested(src, limits, depth + 1)?;
+            }
+
+            Ok(())
+        }
+        actual => Err(format!("invalid frame type byte `{}`", actual).into()),
+    }
+}
+
+// Reads the number of elements in an aggregate frame
+fn get_length(src: &mut Cursor<&[u8]>, limits: &FrameLimits) -> Result<usize, Error> {
+    let len: usize = get_decimal(src)?.try_into()?;
+    if len > limits.max_array_len {
+        return Err(format!("more than {} elements in an aggregate frame", limits.max_array_len).into());
+    }
+    Ok(len)
+}
+
+fn parse_pairs(src: &mut Cursor<&[u8]>) -> Result<Vec<(Frame, Frame)>, Error> {
+    let len = get_decimal(src)?.try_into()?;
+    let mut pairs = Vec::with_capacity(cmp::min(len, src.remaining() / 6));
+
+    for _ in 0..len {
+        let key = Frame::parse(src)?;
+        let value = Frame::parse(src)?;
+        pairs.push((key, value));
+    }
+
+    Ok(pairs)
+}
+
 fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
     if !src.has_remaining() {
         return Err(Error::Incomplete());
@@ -201,14 +343,53 @@ fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
 
     let line = get_line(src)?;
 
-    atoi::<u64>(line).ok_or_else(|| "protocol error; invalid frame format".into())
+    atoi::<u64>(line).ok_or_else(|| "invalid frame format".into())
+}
+
+fn get_signed(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    use atoi::atoi;
+
+    let line = get_line(src)?;
+
+    atoi::<i64>(line).ok_or_else(|| "invalid frame format".into())
+}
+
+fn get_double(src: &mut Cursor<&[u8]>) -> Result<f64, Error> {
+    let line = get_line(src)?;
+
+    std::str::from_utf8(line).
+        ok().
+        and_then(|line| line.parse().ok()).
+        ok_or_else(|| "invalid double".into())
+}
+
+fn get_boolean(src: &mut Cursor<&[u8]>) -> Result<bool, Error> {
+    match get_line(src)? {
+        b"t" => Ok(true),
+        b"f" => Ok(false),
+        _ => Err("invalid boolean".into()),
+    }
+}
+
+fn get_big_number(src: &mut Cursor<&[u8]>) -> Result<String, Error> {
+    let line = get_line(src)?;
+    let digits = match line.first() {
+        Some(b'-') | Some(b'+') => &line[1..],
+        _ => line,
+    };
+
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return Err("invalid big number".into());
+    }
+
+    Ok(String::from_utf8(line.to_vec())?)
 }
 
 fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
     // Scan the bytes directly
     let start = src.position() as usize;
     // Scan to the second to last byte
-    let end = src.get_ref().len() - 1;
+    let end = src.get_ref().len().saturating_sub(1);
 
     for i in start..end {
         if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
@@ -225,7 +406,7 @@ fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
 
 impl From<String> for Error {
     fn from(src: String) -> Error {
-        Error::Other(src.into())
+        Error::Other(ServerError::Protocol(src).into())
     }
 }
 
@@ -237,13 +418,13 @@ impl From<&str> for Error {
 
 impl From<FromUtf8Error> for Error {
     fn from(_src: FromUtf8Error) -> Error {
-        "protocol error; invalid frame format".into()
+        "invalid frame format".into()
     }
 }
 
 impl From<TryFromIntError> for Error {
     fn from(_src: TryFromIntError) -> Error {
-        "protocol error; invalid frame format".into()
+        "invalid frame format".into()
     }
 }
 
@@ -296,3 +477,82 @@ impl From<IndexedFrame> for Frame {
         idx_frame.1
     }
 }
+
+#[cfg(test)]
+mod frame_test {
+    use super::*;
+
+    fn parse(input: &[u8]) -> Frame {
+        let mut check = Cursor::new(input);
+        Frame::check(&mut check, &FrameLimits::default()).unwrap();
+        assert_eq!(check.position() as usize, input.len());
+
+        Frame::parse(&mut Cursor::new(input)).unwrap()
+    }
+
+    #[test]
+    fn test_parse_resp3_scalars() {
+        assert!(matches!(parse(b":-42\r\n"), Frame::Integer(-42)));
+        assert!(matches!(parse(b",-1.5\r\n"), Frame::Double(x) if x == -1.5));
+        assert!(matches!(parse(b",inf\r\n"), Frame::Double(x) if x == f64::INFINITY));
+        assert!(matches!(parse(b"#t\r\n"), Frame::Boolean(true)));
+        assert!(matches!(parse(b"#f\r\n"), Frame::Boolean(false)));
+        assert!(matches!(parse(b"(-3492890328409238509324850943850943825024385\r\n"),
+            Frame::BigNumber(n) if n == "-3492890328409238509324850943850943825024385"));
+        assert!(matches!(parse(b"_\r\n"), Frame::Null()));
+
+        let limits = FrameLimits::default();
+        assert!(Frame::check(&mut Cursor::new(&b"#x\r\n"[..]), &limits).is_err());
+        assert!(Frame::check(&mut Cursor::new(&b"(12a\r\n"[..]), &limits).is_err());
+    }
+
+    #[test]
+    fn test_parse_resp3_aggregates() {
+        match parse(b"%2\r\n+first\r\n:1\r\n$6\r\nsecond\r\n,2.5\r\n") {
+            Frame::Map(pairs) => {
+                assert_eq!(pairs.len(), 2);
+                assert_eq!(pairs[0].0, "first");
+                assert!(matches!(pairs[0].1, Frame::Integer(1)));
+                assert_eq!(pairs[1].0, "second");
+                assert!(matches!(pairs[1].1, Frame::Double(x) if x == 2.5));
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+
+        match parse(b"|1\r\n+ttl\r\n:3600\r\n*2\r\n:1\r\n:2\r\n") {
+            Frame::Attribute(attributes, frame) => {
+                assert_eq!(attributes[0].0, "ttl");
+                assert!(matches!(*frame, Frame::Array(ref parts) if parts.len() == 2));
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+
+        // A map is incomplete until every value has arrived
+        let mut partial = Cursor::new(&b"%1\r\n+key\r\n"[..]);
+        assert!(matches!(Frame::check(&mut partial, &FrameLimits::default()), Err(Error::Incomplete())));
+    }
+
+    #[test]
+    fn test_check_enforces_limits() {
+        let limits = FrameLimits { max_buffer_size: 1024, max_bulk_len: 4, max_array_len: 2, max_depth: 1 };
+        let check = |input: &[u8]| Frame::check(&mut Cursor::new(input), &limits);
+
+        assert!(check(b"$4\r\nabcd\r\n").is_ok());
+        assert!(matches!(check(b"$5\r\n"), Err(Error::Other(_))));
+        assert!(check(b"*2\r\n:1\r\n:2\r\n").is_ok());
+        assert!(matches!(check(b"*3\r\n"), Err(Error::Other(_))));
+        assert!(matches!(check(b"%3\r\n"), Err(Error::Other(_))));
+        assert!(check(b"*1\r\n*0\r\n").is_ok());
+        assert!(matches!(check(b"*1\r\n*1\r\n*0\r\n"), Err(Error::Other(_))));
+        assert!(matches!(check(b"$18446744073709551615\r\n"), Err(Error::Other(_))));
+    }
+
+    #[test]
+    fn test_malformed_frames_are_errors() {
+        for input in [&b"?\r\n"[..], b"$-5\r\n", b"_x\r\n", b"$18446744073709551615\r\n"].iter() {
+            assert!(Frame::parse(&mut Cursor::new(*input)).is_err());
+        }
+        // Declared lengths don't decide how much is allocated up front
+        assert!(matches!(Frame::parse(&mut Cursor::new(&b"*4294967295\r\n"[..])), Err(Error::Incomplete())));
+    }
+}
@@ -1,49 +1,47 @@
-use tokio::sync::RwLock;
-use std::mem;
-use std::sync::Arc;
-use crate::net::{Frame, IndexedFrame, Database};
-use bytes::{Bytes, BytesMut, BufMut};
+use tokio::time::Instant;
+use crate::net::{deadline, CancelToken, Datasets, Database, Reply};
 use crate::lsh::vector::Vector;
 
 
-pub(crate) struct Get<DB: Database> 
-where
-    DB::Item: Vector<DType=f32>,
-    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
-{
+#[derive(Debug, PartialEq)]
+pub(crate) struct Get {
     dataset: String,
-    item: DB::Item
+    vector: Vec<f32>
 }
 
-impl<DB: Database> Get<DB> 
-where
-    DB::Item: Vector<DType=f32>,
-    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>,
-{
-    pub(crate) async fn execute(self, id: usize, db_ptr: Arc<RwLock<DB>>, tx: tokio::sync::mpsc::Sender<IndexedFrame>) 
-        -> crate::Result<()> 
+impl Get {
+    pub(crate) fn new(dataset: String, vector: Vec<f32>) -> Self {
+        Get { dataset, vector }
+    }
+
+    /// The nearest vector, or `None` if the client went away first.
+    pub(crate) async fn execute<DB: Database>(self, datasets: &Datasets<DB>, deadline: Option<Instant>, cancel: &CancelToken)
+        -> crate::Result<Option<Reply>>
+    where
+        DB::Item: Vector<DType=f32>,
+        for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>,
     {
-        // TODO: Check whether there is a timeout on waiting for the read() lock
-        let db = db_ptr.read().await; 
+        let dataset = datasets.get(&self.dataset)?;
+        let item = dataset.item(self.vector)?;
 
-        let resp = if let Some(value) = db.query(&self.item) {
-            let mut buf = BytesMut::with_capacity(mem::size_of::<f32>() * value.dimension());
-            for elt in value {
-                buf.put_f32_le(elt);
-            }
-            Frame::Bulk(Bytes::from(buf))
-        }
-        else {
-            Frame::Null()
+        let db = match deadline::read(dataset.database(), deadline).await {
+            Some(db) => db,
+            None => return Err(deadline::timeout()),
         };
-        tx.send(IndexedFrame::new(id, resp)).await?; 
-        Ok(())
-    }
 
-    pub(crate) fn from_blob(dataset: String, _bytes: Bytes) -> Self {
-        Get {
-            dataset,
-            item: DB::Item::default()
+        // As for TopK, the deadline stops scoring part way through
+        let nearest = match db.query_topk_cancellable(&item, 1, &cancel.with_deadline(deadline.map(Instant::into_std))) {
+            Some(nearest) => nearest,
+            None if deadline::expired(deadline) => return Err(deadline::timeout()),
+            // Nobody is waiting for the answer any more
+            None => return Ok(None),
+        };
+        let vector = nearest.first().map(|value| value.into_iter().collect::<Vec<f32>>());
+        drop(db);
+
+        if deadline::expired(deadline) {
+            return Err(deadline::timeout());
         }
+        Ok(Some(Reply::Vector(vector)))
     }
 }
@@ -0,0 +1,275 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::task;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::{Status, Streaming};
+use tracing::warn;
+use crate::lsh::vector::Vector;
+use crate::net::{accept_with_backoff, deadline, execute_request, Authenticator, CancelToken, ClientLimiter, ClientPermit, Command, Database, Datasets, Reply, ServerError, ServerStats};
+
+// The gRPC service defined in proto/rush.proto.  build.rs generates the
+// messages and the `rush_server` and `rush_client` modules from it.
+
+include!(concat!(env!("OUT_DIR"), "/rush.rs"));
+
+pub use rush_server::RushServer;
+pub use rush_client::RushClient;
+
+/// Serves the `Rush` gRPC service from the same datasets as the RESP
+/// `Listener`.  Wrap it in a `RushServer` to hand it to tonic.
+///
+/// When authentication is enabled, each call carries an `authorization`
+/// metadata entry of the form `Bearer <token>`.
+pub struct GrpcService<DB> {
+    datasets: Arc<Datasets<DB>>,
+    stats: Arc<ServerStats>,
+    authenticator: Arc<Authenticator>,
+    command_timeout: Option<Duration>,
+}
+
+impl<DB> GrpcService<DB>
+where
+    DB: Database + Sync + Send + 'static,
+    DB::Item: Vector<DType=f32> + Sync + Send,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    pub fn new(datasets: Arc<Datasets<DB>>, stats: Arc<ServerStats>) -> Self {
+        GrpcService { datasets, stats, authenticator: Arc::new(Authenticator::disabled()), command_timeout: None }
+    }
+
+    pub fn with_authenticator(mut self, authenticator: Arc<Authenticator>) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Gives each call a deadline, as the RESP listener's `command_timeout`.
+    pub fn with_command_timeout(mut self, command_timeout: Option<Duration>) -> Self {
+        self.command_timeout = command_timeout;
+        self
+    }
+
+    // Status is large, but it's what every handler returns anyway
+    #[allow(clippy::result_large_err)]
+    fn authorize<T>(&self, request: &tonic::Request<T>, write: bool) -> Result<(), Status> {
+        let token = request.
+            metadata().
+            get("authorization").
+            and_then(|value| value.to_str().ok()).
+            and_then(|value| value.strip_prefix("Bearer "));
+
+        let role = match token {
+            Some(token) => self.authenticator.authenticate(token.trim()),
+            None => self.authenticator.default_role(),
+        };
+        match role {
+            None => Err(Status::unauthenticated("authentication required")),
+            Some(role) if write && !role.can_write() => Err(Status::permission_denied("token is read-only")),
+            Some(_) => Ok(()),
+        }
+    }
+
+    // The command runs on a task of its own.  When a client goes away tonic
+    // drops the call, and with it the guard that cancels the command.
+    async fn execute(&self, command: Command) -> Result<Reply, Status> {
+        let cancel = CancelToken::new();
+        let _cancel_on_drop = cancel.cancel_on_drop();
+        let datasets = self.datasets.clone();
+        let stats = self.stats.clone();
+        let deadline = deadline::deadline(self.command_timeout);
+
+        let result = task::spawn(async move {
+            execute_request(command, &datasets, &stats, deadline, &cancel).
+                await.
+                map_err(|err| ServerError::from_error(&err))
+        }).await;
+
+        match result {
+            Ok(reply) => reply.map_err(status),
+            Err(err) => Err(Status::internal(format!("command failed; {}", err))),
+        }
+    }
+
+    async fn insert_one(&self, request: InsertRequest) -> Result<(), Status> {
+        match self.execute(Command::put(&request.dataset, request.vector)).await? {
+            Reply::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn query_one(&self, request: QueryRequest) -> Result<QueryResponse, Status> {
+        let k = request.k.max(1) as usize;
+        match self.execute(Command::topk(&request.dataset, request.vector, k)).await? {
+            Reply::Neighbours(neighbours) => {
+                let neighbours = neighbours.
+                    into_iter().
+                    map(|(vector, distance)| Neighbour { vector, distance }).
+                    collect();
+                Ok(QueryResponse { neighbours })
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn status(err: ServerError) -> Status {
+    match err {
+        ServerError::Protocol(msg) | ServerError::Validation(msg) => Status::invalid_argument(msg),
+        ServerError::NotFound(msg) => Status::not_found(msg),
+        ServerError::Timeout(msg) => Status::deadline_exceeded(msg),
+        ServerError::Capacity(msg) => Status::resource_exhausted(msg),
+        ServerError::Unauthenticated(msg) => Status::unauthenticated(msg),
+        ServerError::Forbidden(msg) => Status::permission_denied(msg),
+        ServerError::Internal(msg) => Status::internal(msg),
+    }
+}
+
+fn unexpected(response: Reply) -> Status {
+    Status::internal(format!("unexpected response {:?}", response))
+}
+
+#[tonic::async_trait]
+impl<DB> rush_server::Rush for GrpcService<DB>
+where
+    DB: Database + Sync + Send + 'static,
+    DB::Item: Vector<DType=f32> + Sync + Send,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    async fn insert(&self, request: tonic::Request<InsertRequest>) -> Result<tonic::Response<InsertResponse>, Status> {
+        self.authorize(&request, true)?;
+        self.insert_one(request.into_inner()).await?;
+        Ok(tonic::Response::new(InsertResponse { inserted: 1 }))
+    }
+
+    async fn insert_stream(&self, request: tonic::Request<Streaming<InsertRequest>>)
+        -> Result<tonic::Response<InsertResponse>, Status>
+    {
+        self.authorize(&request, true)?;
+        let mut stream = request.into_inner();
+        let mut inserted = 0;
+        while let Some(request) = stream.message().await? {
+            self.insert_one(request).await?;
+            inserted += 1;
+        }
+        Ok(tonic::Response::new(InsertResponse { inserted }))
+    }
+
+    async fn query(&self, request: tonic::Request<QueryRequest>) -> Result<tonic::Response<QueryResponse>, Status> {
+        self.authorize(&request, false)?;
+        Ok(tonic::Response::new(self.query_one(request.into_inner()).await?))
+    }
+
+    async fn batch_query(&self, request: tonic::Request<BatchQueryRequest>)
+        -> Result<tonic::Response<BatchQueryResponse>, Status>
+    {
+        self.authorize(&request, false)?;
+        let mut results = Vec::new();
+        for query in request.into_inner().queries {
+            results.push(self.query_one(query).await?);
+        }
+        Ok(tonic::Response::new(BatchQueryResponse { results }))
+    }
+
+    async fn delete(&self, request: tonic::Request<DeleteRequest>) -> Result<tonic::Response<DeleteResponse>, Status> {
+        self.authorize(&request, true)?;
+        let request = request.into_inner();
+        match self.execute(Command::delete(&request.dataset, request.vector)).await? {
+            Reply::Deleted(deleted) => Ok(tonic::Response::new(DeleteResponse { deleted })),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn stats(&self, request: tonic::Request<StatsRequest>) -> Result<tonic::Response<StatsResponse>, Status> {
+        self.authorize(&request, false)?;
+        let counters = self.stats.
+            snapshot().
+            into_iter().
+            map(|(name, value)| Counter { name: name.to_string(), value }).
+            collect();
+        Ok(tonic::Response::new(StatsResponse { counters }))
+    }
+}
+
+/// A connection accepted by `incoming`.  It holds its share of the connection
+/// limits until tonic drops it.
+pub struct GrpcConnection {
+    stream: TcpStream,
+    _connection_permit: OwnedSemaphorePermit,
+    _client_permit: Option<ClientPermit>,
+}
+
+/// Accepts connections on `listener` for tonic's `serve_with_incoming`,
+/// drawing on the same limits as the other listeners.  There is no way to
+/// tell an HTTP/2 client why it was refused, so a client over its own limit
+/// is simply disconnected.
+pub fn incoming(
+    listener: TcpListener,
+    connection_limiter: Arc<Semaphore>,
+    client_limiter: Arc<ClientLimiter>,
+    stats: Arc<ServerStats>,
+) -> ReceiverStream<io::Result<GrpcConnection>> {
+    let (sender, receiver) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        loop {
+            let connection_permit = match connection_limiter.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+
+            let (stream, addr) = accept_with_backoff(&listener, &stats).await;
+            let client_permit = match addr.map(|addr| (addr, client_limiter.try_acquire(addr.ip()))) {
+                Some((_, Some(permit))) => Some(permit),
+                None => None,
+                Some((addr, None)) => {
+                    stats.record_connection_rejected();
+                    warn!(%addr, "rejecting grpc connection; too many connections from client");
+                    continue;
+                }
+            };
+
+            stats.record_connection_accepted();
+            let connection = GrpcConnection { stream, _connection_permit: connection_permit, _client_permit: client_permit };
+            if sender.send(Ok(connection)).await.is_err() {
+                // The server has stopped
+                return;
+            }
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+impl Connected for GrpcConnection {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.stream.connect_info()
+    }
+}
+
+impl AsyncRead for GrpcConnection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for GrpcConnection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -1,37 +1,72 @@
-use tokio::sync::{mpsc, Semaphore, RwLock};
-use tokio::task;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+use tokio::{task, time};
+use tokio::time::Instant;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
 use std::sync::Arc;
-use crate::net::{Connection, Database, Frame, Command};
+use std::time::Duration;
+use tracing::{debug, error};
+use crate::net::{deadline, redis, Authenticator, CancelToken, ClientPermit, Connection, Database, Datasets, Frame, Command, IndexedFrame, RedisCommand, Role, ServerError, ServerStats};
 use crate::lsh::vector::Vector;
 
-enum Mode {
+/// How a connection sends its commands, chosen by the integer frame a client
+/// opens with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Commands are pipelined, each answered as it finishes.
     Stream = 0,
+    /// One array of commands, answered with one array of responses.
     Bulk = 1,
+    /// One command, after which the server hangs up.
     Single = 2
 }
 
 impl Mode {
-    fn from_u64(x: u64) -> Option<Self> {
+    fn from_i64(x: i64) -> Option<Self> {
         match x {
-            0u64 => Some(Mode::Stream),
-            1u64 => Some(Mode::Bulk),
-            2u64 => Some(Mode::Single),
+            0 => Some(Mode::Stream),
+            1 => Some(Mode::Bulk),
+            2 => Some(Mode::Single),
             _    => None
         }
     }
 }
 
-pub(crate) struct Handler<DB> 
+// What became of a wait for a command's response
+enum Wait {
+    Response(IndexedFrame),
+    // Every command has responded
+    Finished,
+    Disconnected,
+}
+
+pub(crate) struct Handler<DB, S = TcpStream> 
 where
     DB: Database + Sync + Send + 'static,
     DB::Item: Vector<DType=f32>,
     for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
 {
-    pub(crate) database: Arc<RwLock<DB>>,
-    pub(crate) connection: Connection,
-    pub(crate) connection_limiter: Arc<Semaphore>,
+    pub(crate) datasets: Arc<Datasets<DB>>,
+    pub(crate) connection: Connection<S>,
+    pub(crate) stats: Arc<ServerStats>,
+    pub(crate) idle_timeout: Option<Duration>,
+    // Applies to commands that don't set their own timeout
+    pub(crate) command_timeout: Option<Duration>,
+    pub(crate) authenticator: Arc<Authenticator>,
+    // The role this connection has authenticated as, if any
+    pub(crate) role: Option<Role>,
+    // Cancelled when the client goes away, to stop the commands it left behind
+    pub(crate) cancel: CancelToken,
+    // A frame read while waiting on a command, to be handled next
+    pub(crate) pending: Option<Frame>,
+    // The permits are never read; they're held so that dropping the handler 
+    // gives the connection slots back to the listener.
+    pub(crate) _connection_permit: OwnedSemaphorePermit,
+    pub(crate) _client_permit: Option<ClientPermit>,
     //shutdown: ShutdownSignal,
 }
 
@@ -48,6 +83,11 @@ Each message...
 THEN ON COMPLETE:
 $-1\r\n
 
+AUTH:
+*2\r\n+AUTH\r\n$N\r\n[token]\r\n
+When the server requires authentication, this may be sent after the mode in
+any mode, and is answered with +OK or an error.
+
 SINGLE:
 *3\r\n+GET\n\n+DATASET\r\n$[N: u32, f32xN]\r\n
 
@@ -56,23 +96,64 @@ BULK:
 
 */
 
-impl<DB> Handler<DB> 
+impl<DB, S> Handler<DB, S>
 where
     DB: Database + Sync + Send + 'static,
     DB::Item: Vector<DType=f32> + Sync + Send,
-    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>,
+    S: AsyncRead + AsyncWrite + Unpin,
 {
+    /// Run the TLS handshake, moving the handler onto the encrypted session.
+    /// A client that doesn't finish the handshake within the idle timeout is
+    /// dropped.
+    pub(crate) async fn start_tls(self, acceptor: &TlsAcceptor) -> crate::Result<Handler<DB, TlsStream<S>>> {
+        let limits = self.connection.limits();
+        let handshake = acceptor.accept(self.connection.into_inner());
+        let stream = match self.idle_timeout {
+            Some(timeout) => time::timeout(timeout, handshake).await.map_err(|_| "tls handshake timed out")??,
+            None => handshake.await?,
+        };
+
+        Ok(Handler {
+            datasets: self.datasets,
+            connection: Connection::with_limits(stream, limits),
+            stats: self.stats,
+            idle_timeout: self.idle_timeout,
+            command_timeout: self.command_timeout,
+            authenticator: self.authenticator,
+            role: self.role,
+            cancel: self.cancel,
+            pending: self.pending,
+            _connection_permit: self._connection_permit,
+            _client_permit: self._client_permit,
+        })
+    }
 
     pub(crate) async fn run(&mut self) -> crate::Result<()> {
-        let maybe_mode = match self.connection.read_frame().await? {
-            Some(Frame::Integer(mode)) => Mode::from_u64(mode),
+        let result = self.serve().await;
+
+        // Nothing still running on the client's behalf has anyone to answer to
+        self.cancel.cancel();
+
+        // Let the client know why it's being disconnected, if it's still listening
+        if let Err(err) = &result {
+            let _ = self.connection.write_frame(&ServerError::from_error(err).into()).await;
+        }
+        result
+    }
+
+    async fn serve(&mut self) -> crate::Result<()> {
+        let maybe_mode = match self.read_frame().await? {
+            Some(Frame::Integer(mode)) => Mode::from_i64(mode),
+            // Redis clients open with a command rather than a mode
+            Some(Frame::Array(command)) => return self.handle_redis(command).await,
             None => return Ok(()),
-            _ => return Err("protocol error; expected integer frame".into()), 
+            _ => return Err(ServerError::Protocol("expected integer frame".into()).into()), 
         };
 
         let mode = match maybe_mode {
             Some(mode) => mode,
-            None => return Err("protocol error; expected mode in [0, 1, 2]".into()),
+            None => return Err(ServerError::Protocol("expected mode in [0, 1, 2]".into()).into()),
         };
 
         match mode {
@@ -82,37 +163,113 @@ where
         }
     }
     
+    // Reads the next frame, giving up on the client if it stays quiet for longer
+    // than the idle timeout.  A timed out connection is treated like a closed one.
+    async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        if let Some(frame) = self.pending.take() {
+            return Ok(Some(frame));
+        }
+
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return self.connection.read_frame().await,
+        };
+
+        match time::timeout(timeout, self.connection.read_frame()).await {
+            Ok(result) => result,
+            Err(_) => {
+                debug!(?timeout, "closing idle connection");
+                Ok(None)
+            }
+        }
+    }
+
+    // Reads the next frame, first answering any AUTH commands sent ahead of it.
+    async fn read_command_frame(&mut self) -> crate::Result<Option<Frame>> {
+        loop {
+            match self.read_frame().await? {
+                Some(Frame::Array(array)) if is_auth(&array) => {
+                    let response = self.auth(array);
+                    self.connection.write_frame(&response).await?;
+                }
+                frame => return Ok(frame),
+            }
+        }
+    }
+
+    // Answers an AUTH command in the custom protocol.  A failed attempt leaves
+    // the connection's role as it was.
+    fn auth(&mut self, array: Vec<Frame>) -> Frame {
+        let token = match &array[..] {
+            [_, Frame::Simple(token)] => token.clone(),
+            [_, Frame::Bulk(token)] => String::from_utf8_lossy(token).into_owned(),
+            _ => return ServerError::Protocol("expected AUTH token".into()).into(),
+        };
+
+        if self.authenticate(&token) {
+            Frame::Simple("OK".into())
+        } else {
+            ServerError::Unauthenticated("invalid token".into()).into()
+        }
+    }
+
+    fn authenticate(&mut self, token: &str) -> bool {
+        match self.authenticator.authenticate(token) {
+            Some(role) => {
+                self.role = Some(role);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn check_role(&self, write: bool) -> Result<(), ServerError> {
+        match self.role {
+            None => Err(ServerError::Unauthenticated("authentication required".into())),
+            Some(role) if write && !role.can_write() => Err(ServerError::Forbidden("token is read-only".into())),
+            Some(_) => Ok(()),
+        }
+    }
+
     // This function awaits Array frames until receiving a Null frame, at which point we exit.
     async fn handle_stream(&mut self) -> crate::Result<()> {
         let (tx, mut rx) = mpsc::channel(64);
         let mut counter: usize = 0;
 
-        while let Some(frame) = self.connection.read_frame().await? {
+        while let Some(frame) = self.read_frame().await? {
             let arr = match frame {
                 Frame::Array(array) => array, 
                 Frame::Null() => return Ok(()),
-                _ => return Err("protocol error; streaming frames must be either Array or Null".into())
+                _ => return Err(ServerError::Protocol("streaming frames must be either Array or Null".into()).into())
             };
 
-            println!("Got array frame");
+            if is_auth(&arr) {
+                let response = self.auth(arr);
+                self.connection.write_frame(&response).await?;
+                continue;
+            }
+
+            let (cmd, deadline) = match self.parse_command(arr) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    self.connection.write_frame(&in_band(err)?).await?;
+                    continue;
+                }
+            };
+            if let Err(denied) = self.check_role(cmd.is_write()) {
+                self.connection.write_frame(&Frame::from(denied)).await?;
+                continue;
+            }
 
-            let cmd = Command::<DB>::parse(arr)?;
-            let db = self.database.clone();
-            let txx = tx.clone();
-            let id = counter; 
-            
-            println!("Spawning task");
-            task::spawn(async move {
-                match cmd.execute(id, db, txx).await {
-                    Err(_) => panic!("Error encountered while executing command. This should not have happened."),
-                    Ok(_) => {},
-                };
-            });
+            self.spawn(counter, cmd, deadline, tx.clone());
             
             // Wait for the response we'll need to send
-            if let Some(response) = rx.recv().await {
-                println!("Task response");
-                self.connection.write_frame(response.get_frame()).await?;
+            match self.wait_for_response(&mut rx).await? {
+                Wait::Response(response) => {
+                    self.connection.write_frame(response.get_frame()).await?;
+                }
+                Wait::Finished => {}
+                Wait::Disconnected => return Ok(()),
             }
 
             counter += 1;
@@ -126,34 +283,45 @@ where
 
     async fn handle_bulk(&mut self) -> crate::Result<()> {
         let (tx, mut rx) = mpsc::channel(64);
-        if let Some(frame) = self.connection.read_frame().await? {
+        if let Some(frame) = self.read_command_frame().await? {
             let frames = match frame {
                 Frame::Array(array) => array, 
-                _ => return Err("protocol error; bulk mode frame must be Array".into())
+                _ => return Err(ServerError::Protocol("bulk mode frame must be Array".into()).into())
             };
-            
+
+            // Refused commands are answered here, the rest by their tasks
+            let mut responses = BinaryHeap::new();
+
             for (id, frame) in frames.into_iter().enumerate() {
                 let array = match frame {
                     Frame::Array(array) => array,
-                    _ => return Err("protocol error; bulk mode commands must be Array frames".into())
+                    _ => return Err(ServerError::Protocol("bulk mode commands must be Array frames".into()).into())
                 };
-                let cmd = Command::<DB>::parse(array)?;
-                let db = self.database.clone();
-                let txx = tx.clone();
-
-                task::spawn(async move {
-                    match cmd.execute(id, db, txx).await {
-                        Err(_) => panic!("Error encountered while executing command. This should not have happened."),
-                        Ok(_) => {},
-                    };
-                });
+                let (cmd, deadline) = match self.parse_command(array) {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        responses.push(Reverse(IndexedFrame::new(id, in_band(err)?)));
+                        continue;
+                    }
+                };
+                if let Err(denied) = self.check_role(cmd.is_write()) {
+                    responses.push(Reverse(IndexedFrame::new(id, Frame::from(denied))));
+                    continue;
+                }
+                self.spawn(id, cmd, deadline, tx.clone());
             }
-            
-            let mut responses = BinaryHeap::new();
-            
+
+            // Each task holds a sender, so the channel closes once they have all
+            // reported back.
+            drop(tx);
+
             // Collect all the responses
-            while let Some(response) = rx.recv().await {
-                responses.push(Reverse(response))
+            loop {
+                match self.wait_for_response(&mut rx).await? {
+                    Wait::Response(response) => responses.push(Reverse(response)),
+                    Wait::Finished => break,
+                    Wait::Disconnected => return Ok(()),
+                }
             }
             
             // Sort them so the order agrees with the sender's order
@@ -169,6 +337,174 @@ where
     }
 
     async fn handle_single(&mut self) -> crate::Result<()> {
+        let (tx, mut rx) = mpsc::channel(1);
+        if let Some(frame) = self.read_command_frame().await? {
+            let array = match frame {
+                Frame::Array(array) => array,
+                _ => return Err(ServerError::Protocol("single mode frame must be Array".into()).into())
+            };
+            let (cmd, deadline) = match self.parse_command(array) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    self.connection.write_frame(&in_band(err)?).await?;
+                    return Ok(());
+                }
+            };
+            if let Err(denied) = self.check_role(cmd.is_write()) {
+                self.connection.write_frame(&Frame::from(denied)).await?;
+                return Ok(());
+            }
+            self.spawn(0, cmd, deadline, tx);
+
+            if let Wait::Response(response) = self.wait_for_response(&mut rx).await? {
+                self.connection.write_frame(response.get_frame()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Parses a command along with the deadline it runs to.
+    fn parse_command(&self, mut array: Vec<Frame>) -> crate::Result<(Command, Option<Instant>)> {
+        let deadline = deadline::deadline(deadline::split_timeout(&mut array)?.or(self.command_timeout));
+        Ok((Command::parse(array)?, deadline))
+    }
+
+    // Runs a command in the background, sending its response down `tx`.
+    fn spawn(&self, id: usize, cmd: Command, deadline: Option<Instant>, tx: mpsc::Sender<IndexedFrame>) {
+        let datasets = self.datasets.clone();
+        let stats = self.stats.clone();
+        let cancel = self.cancel.clone();
+
+        task::spawn(async move {
+            if let Err(err) = cmd.execute(id, datasets, stats, deadline, &cancel, tx).await {
+                // Once the client has gone there's nobody to send the response to
+                if !cancel.is_cancelled() {
+                    error!(cause = ?err, "error executing command");
+                }
+            }
+        });
+    }
+
+    // Waits for the next response from a command task while watching for the
+    // client going away, in which case every command it left running is
+    // cancelled.  Frames the client sends in the meantime are kept for later.
+    async fn wait_for_response(&mut self, rx: &mut mpsc::Receiver<IndexedFrame>) -> crate::Result<Wait> {
+        loop {
+            let event = tokio::select! {
+                response = rx.recv() => Ok(response),
+                frame = self.connection.read_frame(), if self.pending.is_none() => Err(frame),
+            };
+
+            match event {
+                Ok(Some(response)) => return Ok(Wait::Response(response)),
+                Ok(None) => return Ok(Wait::Finished),
+                Err(Ok(Some(frame))) => self.pending = Some(frame),
+                Err(Ok(None)) => {
+                    debug!("client disconnected; cancelling its commands");
+                    self.cancel.cancel();
+                    return Ok(Wait::Disconnected);
+                }
+                Err(Err(err)) => {
+                    self.cancel.cancel();
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    // Runs a Redis command in the background and waits for its response, as the
+    // other modes do, so that the client going away cancels it.  Returns `None`
+    // once the client has gone.
+    async fn execute_redis(&mut self, command: RedisCommand) -> crate::Result<Option<Frame>> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let datasets = self.datasets.clone();
+        let stats = self.stats.clone();
+        let cancel = self.cancel.clone();
+        let deadline = deadline::deadline(self.command_timeout);
+
+        task::spawn(async move {
+            let response = command.execute(&datasets, &stats, deadline, &cancel).await;
+            let _ = tx.send(IndexedFrame::new(0, response)).await;
+        });
+
+        match self.wait_for_response(&mut rx).await? {
+            Wait::Response(response) => Ok(Some(Frame::from(response))),
+            Wait::Finished => Err(ServerError::Internal("command completed without a response".into()).into()),
+            Wait::Disconnected => Ok(None),
+        }
+    }
+
+    // Answers Redis style commands until the client quits.  Unlike the other
+    // modes, a bad command gets an error reply rather than closing the connection.
+    async fn handle_redis(&mut self, first: Vec<Frame>) -> crate::Result<()> {
+        let mut next = Some(Frame::Array(first));
+
+        while let Some(frame) = next {
+            let command = match frame {
+                Frame::Array(array) => RedisCommand::parse(array),
+                frame => Err(format!("ERR expected command array, got {}", frame).into()),
+            };
+
+            match command {
+                Ok(RedisCommand::Auth(token)) => {
+                    let response = if self.authenticate(&token) {
+                        Frame::Simple("OK".into())
+                    } else {
+                        Frame::Error(redis::WRONGPASS.into())
+                    };
+                    self.connection.write_frame(&response).await?;
+                }
+                Ok(RedisCommand::Hello(protover, token)) => {
+                    let response = match token {
+                        Some(token) if !self.authenticate(&token) => Frame::Error(redis::WRONGPASS.into()),
+                        None if self.role.is_none() => Frame::Error(
+                            "NOAUTH HELLO must be called with the client already authenticated, \
+                             otherwise the HELLO <proto> AUTH <user> <pass> option can be used".into()
+                        ),
+                        _ => {
+                            let (protocol, response) = redis::hello(protover, self.connection.protocol());
+                            self.connection.set_protocol(protocol);
+                            response
+                        }
+                    };
+                    self.connection.write_frame(&response).await?;
+                }
+                Ok(RedisCommand::Quit) => {
+                    self.connection.write_frame(&Frame::Simple("OK".into())).await?;
+                    return Ok(());
+                }
+                Ok(command) => {
+                    let response = match self.check_role(command.is_write()) {
+                        Ok(()) => match self.execute_redis(command).await? {
+                            Some(response) => response,
+                            None => return Ok(()),
+                        },
+                        Err(denied) => denied.into(),
+                    };
+                    self.connection.write_frame(&response).await?;
+                }
+                Err(err) => self.connection.write_frame(&Frame::Error(err.to_string())).await?,
+            }
+
+            next = self.read_frame().await?;
+        }
+
         Ok(())
     }
 }
+
+// A command that is well formed but invalid, such as one with a negative
+// timeout, is answered with its error and the connection carries on.  Any
+// other error means the client and server no longer agree on where commands
+// start, so it ends the connection.
+fn in_band(err: crate::Error) -> crate::Result<Frame> {
+    match err.downcast_ref::<ServerError>() {
+        Some(ServerError::Protocol(_)) | None => Err(err),
+        Some(err) => Ok(err.clone().into()),
+    }
+}
+
+fn is_auth(array: &[Frame]) -> bool {
+    matches!(array.first(), Some(Frame::Simple(name)) if name.eq_ignore_ascii_case("auth"))
+}
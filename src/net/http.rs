@@ -0,0 +1,438 @@
+use tokio::sync::Semaphore;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Instant};
+use tracing::{debug, error, warn};
+use crate::net::{accept_with_backoff, deadline, execute_request, Authenticator, CancelToken, ClientLimiter, Command, Database, DatasetConfig, Datasets, Reply, ServerError, ServerStats};
+use crate::lsh::vector::Vector;
+
+/*
+
+A small HTTP/1.1 front end for clients that can't speak RESP.  Requests and
+responses carry JSON bodies, and every request is translated into the same
+`Command` the RESP handler would run, against the same datasets.
+
+GET    /datasets                                                   -> {"datasets": [name]}
+PUT    /datasets/{dataset}           {"dimension": usize, "hash_bits": usize, "tables": usize, "metric": "cosine", "hash": "sparse", "density": f32}
+                                                                   -> {"status": "ok"}
+DELETE /datasets/{dataset}                                         -> {"status": "ok"}
+POST   /datasets/{dataset}/vectors   {"vector": [f32]}             -> {"status": "ok"}
+DELETE /datasets/{dataset}/vectors   {"vector": [f32]}             -> {"deleted": bool}
+POST   /datasets/{dataset}/query     {"vector": [f32], "k": usize} -> {"neighbours": [{"vector": [f32], "distance": f32}]}
+GET    /stats                                                      -> {"counter": u64, ...}
+
+Errors are reported as {"error": "message"} with a 4xx or 5xx status.  Those
+raised while running a command also carry the RESP error code, as in
+{"error": "message", "code": "TIMEOUT"}.
+
+When the server requires authentication, requests carry a token in an
+`Authorization: Bearer <token>` header.  A missing or invalid token gets a
+401, and a read-only token attempting a write gets a 403.
+
+*/
+
+const MAX_HEADER_BYTES: usize = 16_384;
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+const REFUSAL_LINGER: Duration = Duration::from_secs(1);
+
+pub struct HttpListener<DB>
+where
+    DB: Database + Sync + Send + 'static,
+    DB::Item: Vector<DType=f32> + Sync + Send,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    pub datasets: Arc<Datasets<DB>>,
+    pub listener: TcpListener,
+    pub connection_limiter: Arc<Semaphore>,
+    pub client_limiter: Arc<ClientLimiter>,
+    pub idle_timeout: Option<Duration>,
+    /// Deadline for each request, as the RESP listener's `command_timeout`.
+    pub command_timeout: Option<Duration>,
+    pub stats: Arc<ServerStats>,
+    pub authenticator: Arc<Authenticator>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    token: Option<String>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+#[derive(Deserialize)]
+struct VectorBody {
+    vector: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct QueryBody {
+    vector: Vec<f32>,
+    #[serde(default = "default_k")]
+    k: usize,
+}
+
+fn default_k() -> usize {
+    1
+}
+
+impl<DB> HttpListener<DB>
+where
+    DB: Database + Sync + Send + 'static,
+    DB::Item: Vector<DType=f32> + Sync + Send,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    pub async fn run(&mut self) -> crate::Result<()> {
+        loop {
+            let permit = self.connection_limiter.clone().acquire_owned().await?;
+            let (mut socket, addr) = accept_with_backoff(&self.listener, &self.stats).await;
+
+            let client_permit = match addr.map(|addr| (addr, self.client_limiter.try_acquire(addr.ip()))) {
+                Some((_, Some(permit))) => Some(permit),
+                None => None,
+                Some((addr, None)) => {
+                    self.stats.record_connection_rejected();
+                    warn!(%addr, "rejecting http connection; too many connections from client");
+                    // As with the RESP listener, the refusal holds the permit
+                    // and gives up on a client that won't read it
+                    tokio::spawn(async move {
+                        let refusal = Response::server_error(ServerError::Capacity("too many connections from client".into()));
+                        let _ = time::timeout(REFUSAL_LINGER, write_response(&mut socket, &refusal, false)).await;
+                        // Closing with the request still unread would reset the
+                        // connection, and the client could lose the response
+                        let _ = socket.shutdown().await;
+                        let _ = time::timeout(REFUSAL_LINGER, tokio::io::copy(&mut socket, &mut tokio::io::sink())).await;
+                        drop(permit);
+                    });
+                    continue;
+                }
+            };
+
+            self.stats.record_connection_accepted();
+
+            let datasets = self.datasets.clone();
+            let stats = self.stats.clone();
+            let authenticator = self.authenticator.clone();
+            let idle_timeout = self.idle_timeout;
+            let command_timeout = self.command_timeout;
+
+            tokio::spawn(async move {
+                if let Err(err) = serve(socket, datasets, stats, authenticator, idle_timeout, command_timeout).await {
+                    error!(cause = ?err, "error handling http connection");
+                }
+                drop(permit);
+                drop(client_permit);
+            });
+        }
+    }
+}
+
+async fn serve<DB>(
+    socket: TcpStream,
+    datasets: Arc<Datasets<DB>>,
+    stats: Arc<ServerStats>,
+    authenticator: Arc<Authenticator>,
+    idle_timeout: Option<Duration>,
+    command_timeout: Option<Duration>,
+) -> crate::Result<()>
+where
+    DB: Database + Sync + Send + 'static,
+    DB::Item: Vector<DType=f32> + Sync + Send,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    let mut stream = BufReader::new(socket);
+
+    loop {
+        let next = match idle_timeout {
+            Some(timeout) => match time::timeout(timeout, read_request(&mut stream)).await {
+                Ok(next) => next,
+                Err(_) => {
+                    debug!(?timeout, "closing idle http connection");
+                    return Ok(());
+                }
+            },
+            None => read_request(&mut stream).await,
+        };
+
+        let request = match next {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                // The stream is out of sync with the request boundaries now, so
+                // answer once and hang up.
+                let response = Response::error(400, &err.to_string());
+                write_response(stream.get_mut(), &response, false).await?;
+                return Ok(());
+            }
+        };
+
+        // The request runs on a task of its own, so that it can be cancelled
+        // if the client hangs up before it's answered
+        let keep_alive = request.keep_alive;
+        let cancel = CancelToken::new();
+        let _cancel_on_drop = cancel.cancel_on_drop();
+        let routed = {
+            let (datasets, stats, authenticator) = (datasets.clone(), stats.clone(), authenticator.clone());
+            let deadline = deadline::deadline(command_timeout);
+            task::spawn(async move { route(&request, &datasets, &stats, &authenticator, deadline, &cancel).await })
+        };
+
+        let response = match wait_for_response(&mut stream, routed).await? {
+            Some(response) => response,
+            None => {
+                debug!("http client disconnected; cancelling its request");
+                return Ok(());
+            }
+        };
+        write_response(stream.get_mut(), &response, keep_alive).await?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// Waits for the response to a request while watching for the client hanging
+// up, returning `None` if it does.  A request sent ahead of the response is left
+// in the buffer for the next read.
+async fn wait_for_response(stream: &mut BufReader<TcpStream>, mut routed: JoinHandle<Response>) -> crate::Result<Option<Response>> {
+    let mut watching = true;
+    loop {
+        tokio::select! {
+            response = &mut routed => return Ok(Some(response?)),
+            read = stream.fill_buf(), if watching => match read {
+                Ok([]) | Err(_) => return Ok(None),
+                Ok(_) => watching = false,
+            },
+        }
+    }
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> crate::Result<Option<Request>> {
+    let mut header_bytes = 0;
+    let mut line = String::new();
+
+    if read_header_line(stream, &mut line, &mut header_bytes).await? == 0 {
+        return Ok(None);
+    }
+
+    let mut parts = line.split_whitespace();
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) => (method.to_string(), path.to_string(), version.to_string()),
+        _ => return Err("malformed request line".into()),
+    };
+
+    // HTTP/1.1 connections persist unless the client says otherwise
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut content_length = 0usize;
+    let mut token = None;
+
+    loop {
+        line.clear();
+        if read_header_line(stream, &mut line, &mut header_bytes).await? == 0 {
+            return Err("connection closed while reading headers".into());
+        }
+
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+            None => return Err("malformed header".into()),
+        };
+
+        match &name[..] {
+            "content-length" => content_length = value.parse().map_err(|_| "invalid content-length")?,
+            "authorization" => token = value.strip_prefix("Bearer ").map(|token| token.trim().to_string()),
+            "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+            "transfer-encoding" => return Err("chunked request bodies are not supported".into()),
+            _ => {}
+        }
+    }
+
+    if content_length > MAX_BODY_BYTES {
+        return Err("request body too large".into());
+    }
+
+    let mut body = vec![0u8; content_length];
+    stream.read_exact(&mut body).await?;
+
+    Ok(Some(Request { method, path, token, body, keep_alive }))
+}
+
+// Reads one line of the request line and headers into `line`, counting it
+// against the MAX_HEADER_BYTES they share.  The read stops at the limit, so a
+// line that never ends can't make the server buffer without bound.
+async fn read_header_line(stream: &mut BufReader<TcpStream>, line: &mut String, header_bytes: &mut usize) -> crate::Result<usize> {
+    let remaining = MAX_HEADER_BYTES - *header_bytes;
+    if remaining == 0 {
+        return Err("request headers too large".into());
+    }
+    let read = (&mut *stream).take(remaining as u64).read_line(line).await?;
+    *header_bytes += read;
+
+    if read > 0 && !line.ends_with('\n') {
+        if read == remaining {
+            return Err("request headers too large".into());
+        }
+        return Err("connection closed while reading headers".into());
+    }
+    Ok(read)
+}
+
+async fn write_response(socket: &mut TcpStream, response: &Response, keep_alive: bool) -> crate::Result<()> {
+    let body = response.body.to_string();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+        response.status,
+        reason(response.status),
+        body.len(),
+        if keep_alive { "keep-alive" } else { "close" },
+    );
+
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, msg: &str) -> Self {
+        Response { status, body: json!({ "error": msg }) }
+    }
+
+    fn server_error(err: ServerError) -> Self {
+        let status = match err {
+            ServerError::Protocol(_) | ServerError::Validation(_) => 400,
+            ServerError::Unauthenticated(_) => 401,
+            ServerError::Forbidden(_) => 403,
+            ServerError::NotFound(_) => 404,
+            ServerError::Capacity(_) => 503,
+            ServerError::Timeout(_) => 504,
+            ServerError::Internal(_) => 500,
+        };
+        Response { status, body: json!({ "error": err.message(), "code": err.code() }) }
+    }
+}
+
+async fn route<DB>(
+    request: &Request,
+    datasets: &Arc<Datasets<DB>>,
+    stats: &Arc<ServerStats>,
+    authenticator: &Authenticator,
+    deadline: Option<Instant>,
+    cancel: &CancelToken,
+) -> Response
+where
+    DB: Database + Sync + Send + 'static,
+    DB::Item: Vector<DType=f32> + Sync + Send,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    let role = match &request.token {
+        Some(token) => authenticator.authenticate(token),
+        None => authenticator.default_role(),
+    };
+    let role = match role {
+        Some(role) => role,
+        None => return Response::error(401, "authentication required"),
+    };
+
+    let path = request.path.split('?').next().unwrap_or("");
+    let segments = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<&str>>();
+
+    let result = match (&request.method[..], &segments[..]) {
+        ("GET", ["stats"]) => Ok(Command::stats()),
+        ("GET", ["datasets"]) => Ok(Command::list()),
+        ("PUT", ["datasets", dataset]) => {
+            parse_body::<DatasetConfig>(&request.body).
+                map(|config| Command::create(dataset, config))
+        }
+        ("DELETE", ["datasets", dataset]) => Ok(Command::drop(dataset)),
+        ("POST", ["datasets", dataset, "vectors"]) => {
+            parse_body::<VectorBody>(&request.body).
+                map(|body| Command::put(dataset, body.vector))
+        }
+        ("DELETE", ["datasets", dataset, "vectors"]) => {
+            parse_body::<VectorBody>(&request.body).
+                map(|body| Command::delete(dataset, body.vector))
+        }
+        ("POST", ["datasets", dataset, "query"]) => {
+            parse_body::<QueryBody>(&request.body).
+                map(|body| Command::topk(dataset, body.vector, body.k))
+        }
+        (_, ["stats"]) | (_, ["datasets"]) | (_, ["datasets", _]) | (_, ["datasets", _, "vectors"]) | (_, ["datasets", _, "query"]) => {
+            return Response::error(405, "method not allowed");
+        }
+        _ => return Response::error(404, "not found"),
+    };
+
+    let command = match result {
+        Ok(command) => command,
+        Err(err) => return Response::error(400, &err.to_string()),
+    };
+
+    if command.is_write() && !role.can_write() {
+        return Response::error(403, "token is read-only");
+    }
+
+    match execute_request(command, datasets, stats, deadline, cancel).await {
+        Ok(response) => Response::ok(to_json(response)),
+        Err(err) => Response::server_error(ServerError::from_error(&err)),
+    }
+}
+
+fn parse_body<'a, T: Deserialize<'a>>(body: &'a [u8]) -> crate::Result<T> {
+    serde_json::from_slice(body).map_err(|err| format!("invalid request body; {}", err).into())
+}
+
+fn to_json(response: Reply) -> Value {
+    match response {
+        Reply::Ok => json!({ "status": "ok" }),
+        Reply::Vector(vector) => json!({ "vector": vector }),
+        Reply::Deleted(removed) => json!({ "deleted": removed }),
+        Reply::Datasets(names) => json!({ "datasets": names }),
+        Reply::Neighbours(neighbours) => {
+            let neighbours = neighbours.
+                into_iter().
+                map(|(vector, distance)| json!({ "vector": vector, "distance": distance })).
+                collect::<Vec<Value>>();
+            json!({ "neighbours": neighbours })
+        }
+        Reply::Counters(counters) => Value::Object(
+            counters.
+                into_iter().
+                map(|(name, value)| (name.to_string(), json!(value))).
+                collect()
+        ),
+    }
+}
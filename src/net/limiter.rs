@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Tracks the number of open connections per source address so that a single
+/// client can't starve everyone else of the global connection budget.
+#[derive(Debug)]
+pub struct ClientLimiter {
+    max_per_client: usize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Held by a `Handler` for the lifetime of its connection.  Dropping the permit
+/// returns the slot to the owning `ClientLimiter`.
+#[derive(Debug)]
+pub struct ClientPermit {
+    limiter: Arc<ClientLimiter>,
+    addr: IpAddr,
+}
+
+impl ClientLimiter {
+    pub fn new(max_per_client: usize) -> Self {
+        ClientLimiter {
+            max_per_client,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `None` when `addr` already holds `max_per_client` connections.
+    pub fn try_acquire(self: &Arc<Self>, addr: IpAddr) -> Option<ClientPermit> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(addr).or_insert(0);
+
+        if *count >= self.max_per_client {
+            return None;
+        }
+
+        *count += 1;
+        Some(ClientPermit {
+            limiter: Arc::clone(self),
+            addr,
+        })
+    }
+
+    pub fn connections(&self, addr: &IpAddr) -> usize {
+        self.active.lock().unwrap().get(addr).copied().unwrap_or(0)
+    }
+
+    fn release(&self, addr: &IpAddr) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(addr) {
+            *count -= 1;
+            // Don't let the map grow with every address we've ever seen
+            if *count == 0 {
+                active.remove(addr);
+            }
+        }
+    }
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod limiter_test {
+    use super::*;
+
+    #[test]
+    fn test_client_limit_enforced() {
+        let limiter = Arc::new(ClientLimiter::new(2));
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.try_acquire(addr);
+        let second = limiter.try_acquire(addr);
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(limiter.try_acquire(addr).is_none());
+
+        // Other clients have their own budget
+        assert!(limiter.try_acquire(other).is_some());
+    }
+
+    #[test]
+    fn test_client_permit_released_on_drop() {
+        let limiter = Arc::new(ClientLimiter::new(1));
+        let addr: IpAddr = "::1".parse().unwrap();
+
+        let permit = limiter.try_acquire(addr).unwrap();
+        assert_eq!(limiter.connections(&addr), 1);
+        assert!(limiter.try_acquire(addr).is_none());
+
+        drop(permit);
+        assert_eq!(limiter.connections(&addr), 0);
+        assert!(limiter.try_acquire(addr).is_some());
+    }
+}
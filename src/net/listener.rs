@@ -1,48 +1,162 @@
-use tokio::sync::{broadcast, Semaphore, RwLock};
+use tokio::sync::{broadcast, Semaphore};
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tracing::error;
-use crate::net::{Connection, Database, Handler};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, warn};
+use crate::net::{Authenticator, CancelToken, ClientLimiter, Connection, Database, Datasets, Frame, FrameLimits, Handler, ServerError, ServerStats};
 use crate::lsh::vector::Vector;
 
-pub struct Listener<DB> 
+// Bounds for the delay between retries after a failed accept.  Errors such as
+// EMFILE clear up once other connections close, so we never give up entirely.
+const INITIAL_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+// How long a refused client has to read why
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A bound socket that a `Listener` accepts connections on.
+pub trait Accept {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Accept the next connection, along with the peer's address if it has
+    /// one.  Peers without an address aren't subject to the per-client limit.
+    fn accept_connection(&self) -> impl Future<Output=io::Result<(Self::Stream, Option<SocketAddr>)>> + Send;
+}
+
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept_connection(&self) -> io::Result<(TcpStream, Option<SocketAddr>)> {
+        let (socket, addr) = self.accept().await?;
+        Ok((socket, Some(addr)))
+    }
+}
+
+impl Accept for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept_connection(&self) -> io::Result<(UnixStream, Option<SocketAddr>)> {
+        let (socket, _) = self.accept().await?;
+        Ok((socket, None))
+    }
+}
+
+/// Serves the RESP protocol on a TCP or Unix domain socket.
+pub struct Listener<DB, L = TcpListener>
 where
     DB: Database + Sync + Send + 'static,
     DB::Item: Vector<DType=f32> + Sync + Send,
     for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
 {
-    pub database: Arc<RwLock<DB>>,
-    pub listener: TcpListener,
+    pub datasets: Arc<Datasets<DB>>,
+    pub listener: L,
     pub connection_limiter: Arc<Semaphore>,
+    pub client_limiter: Arc<ClientLimiter>,
+    pub idle_timeout: Option<Duration>,
+    /// Default deadline for commands that don't set their own.
+    pub command_timeout: Option<Duration>,
+    pub frame_limits: FrameLimits,
+    pub stats: Arc<ServerStats>,
+    pub authenticator: Arc<Authenticator>,
+    /// Terminate TLS on every connection when set.
+    pub tls_acceptor: Option<TlsAcceptor>,
     pub shutdown_signal: broadcast::Sender<()>,
 }
 
-impl<DB> Listener<DB>
+impl<DB, L> Listener<DB, L>
 where
     DB: Database + Sync + Send + 'static,
     DB::Item: Vector<DType=f32> + Sync + Send,
-    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>,
+    L: Accept,
 {
     pub async fn run(&mut self) -> crate::Result<()> {
         loop {
-            self.connection_limiter.acquire().await.unwrap().forget();
-            
-            // TODO: Implement exponential backoff
-            let (socket, _) = self.listener.accept().await.unwrap();
-            
+            // The permit is handed to the handler, and is returned to the semaphore
+            // when the handler is dropped at the end of the connection.
+            let connection_permit = self.connection_limiter.clone().acquire_owned().await?;
+
+            let (socket, addr) = accept_with_backoff(&self.listener, &self.stats).await;
+
+            let client_permit = match addr.map(|addr| (addr, self.client_limiter.try_acquire(addr.ip()))) {
+                Some((_, Some(permit))) => Some(permit),
+                // Clients on a Unix socket share the global limit only
+                None => None,
+                Some((addr, None)) => {
+                    self.stats.record_connection_rejected();
+                    warn!(%addr, "rejecting connection; too many connections from client");
+                    // A TLS client can't read a plaintext frame, so it is
+                    // just disconnected.  The refusal keeps the connection
+                    // permit until it's written, and gives up on a client that
+                    // won't read it.
+                    if self.tls_acceptor.is_none() {
+                        tokio::spawn(async move {
+                            let mut connection = Connection::new(socket);
+                            let refusal = Frame::from(ServerError::Capacity("too many connections from client".into()));
+                            let _ = time::timeout(REFUSAL_TIMEOUT, connection.write_frame(&refusal)).await;
+                            drop(connection_permit);
+                        });
+                    }
+                    continue;
+                }
+            };
+
+            self.stats.record_connection_accepted();
+
             let mut handler = Handler {
-                database: self.database.clone(),
-                connection: Connection::new(socket),
-                connection_limiter: self.connection_limiter.clone()
+                datasets: self.datasets.clone(),
+                connection: Connection::with_limits(socket, self.frame_limits),
+                stats: self.stats.clone(),
+                idle_timeout: self.idle_timeout,
+                command_timeout: self.command_timeout,
+                role: self.authenticator.default_role(),
+                authenticator: self.authenticator.clone(),
+                cancel: CancelToken::new(),
+                pending: None,
+                _connection_permit: connection_permit,
+                _client_permit: client_permit,
                 //shutdown: ShutdownSignal::new(self.shutdown.subscribe()),
             };
 
+            let tls_acceptor = self.tls_acceptor.clone();
             tokio::spawn(async move {
-                if let Err(err) = handler.run().await {
-                    println!("ERROR: {:?}", err);
+                let result = match tls_acceptor {
+                    Some(acceptor) => match handler.start_tls(&acceptor).await {
+                        Ok(mut handler) => handler.run().await,
+                        Err(err) => Err(err),
+                    },
+                    None => handler.run().await,
+                };
+                if let Err(err) = result {
                     error!(cause = ?err, "error handling connection");
                 }
-            }); 
+            });
         }
     }
 }
+
+// Accept the next connection, retrying with capped exponential backoff
+// rather than taking the whole server down on a transient error.
+pub(crate) async fn accept_with_backoff<L: Accept>(listener: &L, stats: &ServerStats) -> (L::Stream, Option<SocketAddr>) {
+    let mut backoff = INITIAL_ACCEPT_BACKOFF;
+
+    loop {
+        match listener.accept_connection().await {
+            Ok(accepted) => return accepted,
+            Err(err) => {
+                stats.record_accept_failure();
+                warn!(cause = %err, ?backoff, "failed to accept connection");
+            }
+        }
+
+        time::sleep(backoff).await;
+        backoff = cmp::min(backoff * 2, MAX_ACCEPT_BACKOFF);
+    }
+}
@@ -1,26 +1,58 @@
-use tokio::sync::RwLock;
+use tokio::time::Instant;
 use std::sync::Arc;
 use crate::lsh::vector::Vector;
+use blob::decode_vector;
+
+mod auth;
+pub use auth::{Authenticator, Role};
+
+mod config;
+pub use config::ServerConfig;
+
+mod error;
+pub use error::ServerError;
+
+mod tls;
+pub use tls::TlsConfig;
+
+pub(crate) mod blob;
+
+mod deadline;
+
+mod reply;
+pub(crate) use reply::Reply;
+
+mod dataset;
+pub use dataset::{Dataset, Datasets};
+pub use crate::lsh::{CancelToken, Database, DatasetConfig};
+use dataset::{CreateDataset, DropDataset, ListDatasets};
 
 mod connection;
-pub(crate) use connection::Connection;
+pub use connection::Connection;
 
 mod frame;
-pub(crate) use frame::Frame;
+pub use frame::{Frame, FrameLimits, Protocol};
 pub(crate) use frame::IndexedFrame;
 
 mod handler;
 pub(crate) use handler::Handler;
+pub use handler::Mode;
+
+mod redis;
+use redis::RedisCommand;
+
+mod limiter;
+pub use limiter::{ClientLimiter, ClientPermit};
 
 mod listener;
-pub use listener::Listener;
+pub use listener::{Accept, Listener};
+use listener::accept_with_backoff;
 
-pub trait Database {
-    type Item;
-    fn len(&self) -> usize;
-    fn insert(&mut self, item: Self::Item) -> crate::Result<()>;
-    fn query<'a>(&'a self, item: &Self::Item) -> Option<&'a Self::Item>;
-}
+mod http;
+pub use http::HttpListener;
+
+pub mod grpc;
+pub use grpc::GrpcService;
 
 mod get;
 use get::Get;
@@ -28,59 +60,210 @@ use get::Get;
 mod put;
 use put::Put;
 
+mod topk;
+use topk::TopK;
+
+mod delete;
+use delete::Delete;
+
+mod stats;
+pub use stats::ServerStats;
+use stats::Stats;
+
 // TODO: Implement ability to publish from S3 locations
 //mod publish;
 //use publish::Publish;
 
-pub(crate) enum Command<DB: Database> 
-where
-    <DB as Database>::Item: Vector<DType=f32> + Send + Sync,
-    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
-{
-    Get(Get<DB>),
-    Put(Put<DB>),
-    //Publish(Publish<DB>),
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    Get(Get),
+    Put(Put),
+    TopK(TopK),
+    Delete(Delete),
+    Stats(Stats),
+    Create(CreateDataset),
+    Drop(DropDataset),
+    List(ListDatasets),
+    //Publish(Publish),
 }
 
-impl<DB: Database> Command<DB> 
-where
-    <DB as Database>::Item: Vector<DType=f32> + Send + Sync,
-    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
-{
-    pub(crate) async fn execute(self, id: usize, db: Arc<RwLock<DB>>, ch: tokio::sync::mpsc::Sender<IndexedFrame>) -> crate::Result<()> {
-        match self {
-            Command::Get(cmd) => cmd.execute(id, db, ch).await,
-            Command::Put(cmd) => cmd.execute(id, db, ch).await,
-            //Command::Publish(cmd) => cmd.execute(id, db, ch).await,
+impl Command {
+    // Dataset names are case insensitive, so every constructor lowercases them
+    pub(crate) fn get(dataset: &str, vector: Vec<f32>) -> Self {
+        Command::Get(Get::new(dataset.to_lowercase(), vector))
+    }
+
+    pub(crate) fn put(dataset: &str, vector: Vec<f32>) -> Self {
+        Command::Put(Put::new(dataset.to_lowercase(), vector))
+    }
+
+    pub(crate) fn delete(dataset: &str, vector: Vec<f32>) -> Self {
+        Command::Delete(Delete::new(dataset.to_lowercase(), vector))
+    }
+
+    pub(crate) fn topk(dataset: &str, vector: Vec<f32>, k: usize) -> Self {
+        Command::TopK(TopK::new(dataset.to_lowercase(), vector, k))
+    }
+
+    pub(crate) fn create(dataset: &str, config: DatasetConfig) -> Self {
+        Command::Create(CreateDataset::new(dataset.to_lowercase(), config))
+    }
+
+    pub(crate) fn drop(dataset: &str) -> Self {
+        Command::Drop(DropDataset::new(dataset.to_lowercase()))
+    }
+
+    pub(crate) fn list() -> Self {
+        Command::List(ListDatasets)
+    }
+
+    pub(crate) fn stats() -> Self {
+        Command::Stats(Stats)
+    }
+
+    /// Run the command, sending its response down `ch`.  A command that misses
+    /// its `deadline` responds with a timeout error, and one that is cancelled
+    /// doesn't respond at all.
+    pub(crate) async fn execute<DB>(
+        self,
+        id: usize,
+        datasets: Arc<Datasets<DB>>,
+        stats: Arc<ServerStats>,
+        deadline: Option<Instant>,
+        cancel: &CancelToken,
+        ch: tokio::sync::mpsc::Sender<IndexedFrame>,
+    ) -> crate::Result<()>
+    where
+        DB: Database,
+        DB::Item: Vector<DType=f32> + Send + Sync,
+        for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+    {
+        let resp = match self.run(&datasets, &stats, deadline, cancel).await {
+            Ok(Some(reply)) => reply.into_frame(),
+            Ok(None) => return Ok(()),
+            // A command refused by the server answers with the reason
+            Err(err) => match err.downcast::<ServerError>() {
+                Ok(err) => Frame::from(*err),
+                Err(err) => return Err(err),
+            },
+        };
+        ch.send(IndexedFrame::new(id, resp)).await?;
+        Ok(())
+    }
+
+    /// Run the command, returning what it produced, or `None` if it was
+    /// cancelled.  A command that misses its `deadline` fails with a timeout
+    /// error.
+    pub(crate) async fn run<DB>(
+        self,
+        datasets: &Datasets<DB>,
+        stats: &ServerStats,
+        deadline: Option<Instant>,
+        cancel: &CancelToken,
+    ) -> crate::Result<Option<Reply>>
+    where
+        DB: Database,
+        DB::Item: Vector<DType=f32> + Send + Sync,
+        for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+    {
+        // The client may have gone while the command was queued
+        if cancel.is_cancelled() {
+            stats.record_command_cancelled();
+            return Ok(None);
+        }
+
+        let result = match self {
+            Command::Get(cmd) => cmd.execute(datasets, deadline, cancel).await,
+            Command::Put(cmd) => cmd.execute(datasets, deadline).await.map(Some),
+            Command::TopK(cmd) => cmd.execute(datasets, deadline, cancel).await,
+            Command::Delete(cmd) => cmd.execute(datasets, deadline).await.map(Some),
+            Command::Stats(cmd) => cmd.execute(stats).await.map(Some),
+            Command::Create(cmd) => cmd.execute(datasets).await.map(Some),
+            Command::Drop(cmd) => cmd.execute(datasets).await.map(Some),
+            Command::List(cmd) => cmd.execute(datasets).await.map(Some),
+            //Command::Publish(cmd) => cmd.execute(db).await,
+        };
+
+        if cancel.is_cancelled() {
+            stats.record_command_cancelled();
+            return Ok(None);
         }
+        result
+    }
+
+    /// Whether the command modifies the database, and so needs a read-write role.
+    pub(crate) fn is_write(&self) -> bool {
+        matches!(self, Command::Put(_) | Command::Delete(_) | Command::Create(_) | Command::Drop(_))
     }
 
     fn parse(array: Vec<Frame>) -> crate::Result<Self> {
         let mut it = array.into_iter();
         
-        let command_name = match it.next().unwrap() {
-            Frame::Simple(cmd) => cmd.to_lowercase(),
-            _ => return Err("protocol error; expected command name".into())
+        let command_name = match it.next() {
+            Some(Frame::Simple(cmd)) => cmd.to_lowercase(),
+            _ => return Err(ServerError::Protocol("expected command name".into()).into())
         };
+
+        // Server-level commands don't refer to a dataset
+        match &command_name[..] {
+            "stats" => return Ok(Command::stats()),
+            "list" => return Ok(Command::list()),
+            _ => {}
+        }
         
-        // TODO: For now, this doesn't matter.  Later I'm going to make it matter.
-        let dataset = match it.next().unwrap() {
-            Frame::Simple(ds) => ds.to_lowercase(),
-            _ => return Err("protocol error; expected dataset name".into())
+        let dataset = match it.next() {
+            Some(Frame::Simple(ds)) => ds.to_lowercase(),
+            _ => return Err(ServerError::Protocol("expected dataset name".into()).into())
         };
 
-        let blob = match it.next().unwrap() {
-            Frame::Bulk(data) => data,
-            _ => return Err("protocol error; expected dataset name".into())
+        match &command_name[..] {
+            "create" => return Ok(Command::Create(CreateDataset::parse(dataset, it)?)),
+            "drop" => return Ok(Command::Drop(DropDataset::new(dataset))),
+            _ => {}
+        }
+
+        let blob = match it.next() {
+            Some(Frame::Bulk(data)) => data,
+            _ => return Err(ServerError::Protocol("expected vector blob".into()).into())
         };
         
         let command = match &command_name[..] {
-            "get" => Command::Get(Get::<DB>::from_blob(dataset, blob)),
-            "put" => Command::Put(Put::<DB>::from_blob(dataset, blob)),
+            "get" => Command::get(&dataset, decode_vector(blob)?),
+            "put" => Command::put(&dataset, decode_vector(blob)?),
+            "delete" => Command::delete(&dataset, decode_vector(blob)?),
+            "topk" => {
+                let k = match it.next() {
+                    Some(Frame::Integer(k)) if k >= 0 => k as usize,
+                    Some(Frame::Integer(_)) => return Err(ServerError::Validation("neighbour count must not be negative".into()).into()),
+                    _ => return Err(ServerError::Protocol("expected integer neighbour count".into()).into())
+                };
+                Command::topk(&dataset, decode_vector(blob)?, k)
+            },
             //"publish" => Command::Publish(Publish::new(dataset, location)),
-            _ => return Err("parse error; unrecognized command".into()),
+            _ => return Err(ServerError::Protocol(format!("unrecognized command '{}'", command_name)).into()),
         };
         
         Ok(command)
     }
 }
+
+/// Runs one command outside of a RESP connection.  The Redis, HTTP and gRPC
+/// front ends go through here, so they behave exactly as a RESP client sending
+/// the same command would.  Each cancels `cancel` when its client goes away.
+pub(crate) async fn execute_request<DB>(
+    command: Command,
+    datasets: &Arc<Datasets<DB>>,
+    stats: &Arc<ServerStats>,
+    deadline: Option<Instant>,
+    cancel: &CancelToken,
+) -> crate::Result<Reply>
+where
+    DB: Database,
+    DB::Item: Vector<DType=f32> + Send + Sync,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    match command.run(datasets, stats, deadline, cancel).await? {
+        Some(reply) => Ok(reply),
+        None => Err(ServerError::Internal("command was cancelled".into()).into()),
+    }
+}
@@ -1,46 +1,38 @@
-use tokio::sync::RwLock;
-use std::sync::Arc;
-use crate::net::{Frame, IndexedFrame, Database};
-use bytes::Bytes;
+use tokio::time::Instant;
+use crate::net::{deadline, Datasets, Database, Reply, ServerError};
 use crate::lsh::vector::Vector;
 
 
-pub(crate) struct Put<DB: Database> 
-where
-    DB::Item: Vector<DType=f32>,
-    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
-{
+#[derive(Debug, PartialEq)]
+pub(crate) struct Put {
     dataset: String,
-    item: Option<DB::Item>
+    vector: Vec<f32>
 }
 
-impl<DB: Database> Put<DB> 
-where
-    DB::Item: Vector<DType=f32>,
-    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>,
-{
-    pub(crate) async fn execute(self, id: usize, db_ptr: Arc<RwLock<DB>>, tx: tokio::sync::mpsc::Sender<IndexedFrame>) 
-        -> crate::Result<()> 
+impl Put {
+    pub(crate) fn new(dataset: String, vector: Vec<f32>) -> Self {
+        Put { dataset, vector }
+    }
+
+    pub(crate) async fn execute<DB: Database>(self, datasets: &Datasets<DB>, deadline: Option<Instant>) -> crate::Result<Reply>
+    where
+        DB::Item: Vector<DType=f32>,
+        for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>,
     {
-        let item = self.item.unwrap(); 
+        let dataset = datasets.get(&self.dataset)?;
+        let item = dataset.item(self.vector)?;
+
         // We drop the write lock ASAP to keep the the locked segment tight.
-        let mut db = db_ptr.write().await;
+        let mut db = match deadline::write(dataset.database(), deadline).await {
+            Some(db) => db,
+            None => return Err(deadline::timeout()),
+        };
         let success = db.insert(item);
         drop(db); 
 
-        let resp = match success { 
-            Ok(_) => Frame::Simple("OK".into()),
-            _ => Frame::Error("unknown error inserting to LSH database".into()),
-        };
-
-        tx.send(IndexedFrame::new(id, resp)).await?; 
-        Ok(())
-    }
-
-    pub(crate) fn from_blob(dataset: String, _bytes: Bytes) -> Self {
-        Put {
-            dataset,
-            item: Some(DB::Item::default())
+        match success { 
+            Ok(_) => Ok(Reply::Ok),
+            Err(err) => Err(ServerError::Internal(format!("failed inserting to LSH database; {}", err)).into()),
         }
     }
 }
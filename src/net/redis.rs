@@ -0,0 +1,368 @@
+use std::sync::Arc;
+use tokio::time::Instant;
+use bytes::Bytes;
+use crate::lsh::vector::Vector;
+use crate::net::{execute_request, CancelToken, Command, Database, DatasetConfig, Datasets, Frame, Protocol, Reply, ServerError, ServerStats};
+use crate::net::blob::decode_vector;
+use crate::simd::dispatch;
+
+/*
+
+Commands for stock Redis clients.  A connection whose first frame is an array
+rather than the integer mode prefix is assumed to be a Redis client, and each
+array it sends is one of the commands below.  Arguments may be bulk or simple
+strings.
+
+AUTH [username] token
+PING [message]
+HELLO [protover [AUTH username token] [SETNAME name]]
+INFO [section]
+COMMAND [COUNT | DOCS | INFO name...]
+QUIT
+
+RUSH.PUT dataset vector
+RUSH.GET dataset vector
+RUSH.TOPK dataset vector k
+RUSH.DELETE dataset vector
+RUSH.CREATE dataset dimension [K bits] [L tables] [METRIC euclidean|cosine|dot] [HASH dense|hadamard|sparse] [DENSITY fraction]
+RUSH.DROP dataset
+RUSH.LIST
+
+A vector is given as either `FP32 blob`, where the blob holds little endian
+f32s, or `VALUES n v1 ... vn`, following the Redis VADD command.  Vectors in
+replies are arrays of doubles, which RESP2 clients receive as bulk strings.
+
+Connections start on RESP2, and `HELLO 3` switches them to RESP3.
+
+When the server requires authentication, only AUTH, HELLO and QUIT are
+accepted until the client has authenticated.  The username is ignored.
+
+*/
+
+// Name, arity and flags of every command, as reported by COMMAND.  As in Redis,
+// a negative arity -N means the command takes at least N arguments.
+const COMMANDS: &[(&str, i64, &[&str])] = &[
+    ("auth", -2, &["fast", "noauth", "stale"]),
+    ("ping", -1, &["fast", "stale"]),
+    ("hello", -1, &["fast", "noauth", "stale"]),
+    ("info", -1, &["stale"]),
+    ("command", -1, &["stale"]),
+    ("quit", 1, &["fast", "noauth"]),
+    ("rush.put", -4, &["write"]),
+    ("rush.get", -4, &["readonly"]),
+    ("rush.topk", -5, &["readonly"]),
+    ("rush.delete", -4, &["write"]),
+    ("rush.create", -3, &["write"]),
+    ("rush.drop", 2, &["write"]),
+    ("rush.list", 1, &["readonly"]),
+];
+
+pub(crate) const WRONGPASS: &str = "WRONGPASS invalid token";
+
+pub(crate) enum RedisCommand {
+    Auth(String),
+    Ping(Option<Bytes>),
+    Hello(Option<i64>, Option<String>),
+    Info(Option<String>),
+    Command(Vec<String>),
+    Quit,
+    Vector(Command),
+}
+
+impl RedisCommand {
+    pub(crate) fn parse(array: Vec<Frame>) -> crate::Result<Self> {
+        let mut args = array.into_iter();
+
+        let name = match args.next() {
+            Some(frame) => string(frame)?.to_lowercase(),
+            None => return Err("ERR empty command".into()),
+        };
+
+        let command = match &name[..] {
+            "auth" => {
+                // The token is the last argument, after an optional username
+                let first = string(next(&mut args, &name)?)?;
+                let token = match args.next() {
+                    Some(frame) => string(frame)?,
+                    None => first,
+                };
+                RedisCommand::Auth(token)
+            }
+            "ping" => RedisCommand::Ping(args.next().map(bytes).transpose()?),
+            "hello" => {
+                let protover = match args.next() {
+                    Some(frame) => Some(string(frame)?.parse().map_err(|_| "ERR protocol version is not an integer")?),
+                    None => None,
+                };
+                // The client name is accepted but ignored
+                let mut token = None;
+                while let Some(option) = args.next() {
+                    match &string(option)?.to_lowercase()[..] {
+                        "auth" => match (args.next(), args.next()) {
+                            (Some(_), Some(frame)) => token = Some(string(frame)?),
+                            _ => return Err("ERR syntax error in HELLO option 'auth'".into()),
+                        },
+                        "setname" if args.next().is_some() => {}
+                        option => return Err(format!("ERR syntax error in HELLO option '{}'", option).into()),
+                    }
+                }
+                RedisCommand::Hello(protover, token)
+            }
+            "info" => RedisCommand::Info(args.next().map(string).transpose()?),
+            "command" => RedisCommand::Command(args.by_ref().map(string).collect::<crate::Result<Vec<String>>>()?),
+            "quit" => RedisCommand::Quit,
+            "rush.put" => {
+                let dataset = string(next(&mut args, &name)?)?;
+                RedisCommand::Vector(Command::put(&dataset, vector(&mut args, &name)?))
+            }
+            "rush.get" => {
+                let dataset = string(next(&mut args, &name)?)?;
+                RedisCommand::Vector(Command::get(&dataset, vector(&mut args, &name)?))
+            }
+            "rush.delete" => {
+                let dataset = string(next(&mut args, &name)?)?;
+                RedisCommand::Vector(Command::delete(&dataset, vector(&mut args, &name)?))
+            }
+            "rush.topk" => {
+                let dataset = string(next(&mut args, &name)?)?;
+                let vector = vector(&mut args, &name)?;
+                let k = string(next(&mut args, &name)?)?.parse().map_err(|_| "ERR neighbour count is not an integer")?;
+                RedisCommand::Vector(Command::topk(&dataset, vector, k))
+            }
+            "rush.create" => {
+                let dataset = string(next(&mut args, &name)?)?;
+                RedisCommand::Vector(Command::create(&dataset, dataset_config(&mut args, &name)?))
+            }
+            "rush.drop" => RedisCommand::Vector(Command::drop(&string(next(&mut args, &name)?)?)),
+            "rush.list" => RedisCommand::Vector(Command::list()),
+            _ => return Err(format!("ERR unknown command '{}'", name).into()),
+        };
+
+        if let RedisCommand::Vector(_) | RedisCommand::Auth(_) = command {
+            if args.next().is_some() {
+                return Err(format!("ERR wrong number of arguments for '{}' command", name).into());
+            }
+        }
+
+        Ok(command)
+    }
+
+    /// Whether the command modifies the database, and so needs a read-write role.
+    pub(crate) fn is_write(&self) -> bool {
+        match self {
+            RedisCommand::Vector(command) => command.is_write(),
+            _ => false,
+        }
+    }
+
+    pub(crate) async fn execute<DB>(self, datasets: &Arc<Datasets<DB>>, stats: &Arc<ServerStats>, deadline: Option<Instant>, cancel: &CancelToken)
+        -> Frame
+    where
+        DB: Database,
+        DB::Item: Vector<DType=f32> + Send + Sync,
+        for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+    {
+        match self {
+            RedisCommand::Ping(None) => Frame::Simple("PONG".into()),
+            RedisCommand::Ping(Some(message)) => Frame::Bulk(message),
+            // The handler negotiates the protocol before the command runs
+            RedisCommand::Auth(_) | RedisCommand::Hello(..) => Frame::Error(
+                "ERR AUTH and HELLO must be handled by the connection".into()
+            ),
+            RedisCommand::Info(section) => {
+                let keyspace = (datasets.names().len(), datasets.vectors().await);
+                Frame::Bulk(info(section.as_deref(), keyspace, stats).into())
+            }
+            RedisCommand::Command(args) => command(&args),
+            RedisCommand::Quit => Frame::Simple("OK".into()),
+            RedisCommand::Vector(command) => match execute_request(command, datasets, stats, deadline, cancel).await {
+                Ok(response) => reply(response),
+                Err(err) => ServerError::from_error(&err).into(),
+            },
+        }
+    }
+}
+
+/// Negotiates the protocol requested by HELLO, returning the protocol to use
+/// from now on along with the reply.  The protocol is unchanged on error.
+pub(crate) fn hello(protover: Option<i64>, current: Protocol) -> (Protocol, Frame) {
+    let protocol = match protover {
+        None => current,
+        Some(2) => Protocol::Resp2,
+        Some(3) => Protocol::Resp3,
+        Some(_) => return (current, Frame::Error("NOPROTO unsupported protocol version".into())),
+    };
+
+    let reply = Frame::Map(vec![
+        (bulk("server"), bulk("rush")),
+        (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+        (bulk("proto"), Frame::Integer(if protocol == Protocol::Resp3 { 3 } else { 2 })),
+        (bulk("mode"), bulk("standalone")),
+        (bulk("role"), bulk("master")),
+        (bulk("modules"), Frame::Array(vec![])),
+    ]);
+    (protocol, reply)
+}
+
+fn next(args: &mut impl Iterator<Item=Frame>, name: &str) -> crate::Result<Frame> {
+    args.next().ok_or_else(|| format!("ERR wrong number of arguments for '{}' command", name).into())
+}
+
+fn bytes(frame: Frame) -> crate::Result<Bytes> {
+    match frame {
+        Frame::Bulk(data) => Ok(data),
+        Frame::Simple(data) => Ok(data.into()),
+        frame => Err(format!("ERR expected string argument, got {}", frame).into()),
+    }
+}
+
+fn string(frame: Frame) -> crate::Result<String> {
+    String::from_utf8(bytes(frame)?.to_vec()).map_err(|_| "ERR argument is not valid UTF-8".into())
+}
+
+fn vector(args: &mut impl Iterator<Item=Frame>, name: &str) -> crate::Result<Vec<f32>> {
+    let format = string(next(args, name)?)?.to_lowercase();
+    match &format[..] {
+        "fp32" => decode_vector(bytes(next(args, name)?)?),
+        "values" => {
+            let n = string(next(args, name)?)?.parse::<usize>().map_err(|_| "ERR VALUES count is not an integer")?;
+            (0..n).
+                map(|_| string(next(args, name)?)?.parse::<f32>().map_err(|_| "ERR vector value is not a float".into())).
+                collect()
+        }
+        _ => Err("ERR vectors must be given as FP32 blob or VALUES n v1 ... vn".into()),
+    }
+}
+
+fn dataset_config(args: &mut impl Iterator<Item=Frame>, name: &str) -> crate::Result<DatasetConfig> {
+    let integer = |frame| -> crate::Result<usize> {
+        string(frame)?.parse().map_err(|_| "ERR value is not a non-negative integer".into())
+    };
+
+    let mut config = DatasetConfig::new(integer(next(args, name)?)?);
+    while let Some(option) = args.next() {
+        match &string(option)?.to_lowercase()[..] {
+            "k" => config.hash_bits = integer(next(args, name)?)?,
+            "l" => config.tables = integer(next(args, name)?)?,
+            "metric" => config.metric = string(next(args, name)?)?.parse().map_err(|err| format!("ERR {}", err))?,
+            "hash" => config.hash = string(next(args, name)?)?.parse().map_err(|err| format!("ERR {}", err))?,
+            "density" => config.density = Some(string(next(args, name)?)?.parse().map_err(|_| "ERR density is not a float")?),
+            option => return Err(format!("ERR syntax error in option '{}'", option).into()),
+        }
+    }
+    Ok(config)
+}
+
+fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+}
+
+fn float_array(vector: &[f32]) -> Frame {
+    Frame::Array(vector.iter().map(|elt| Frame::Double(f64::from(*elt))).collect())
+}
+
+fn reply(response: Reply) -> Frame {
+    match response {
+        Reply::Ok => Frame::Simple("OK".into()),
+        Reply::Vector(Some(vector)) => float_array(&vector),
+        Reply::Vector(None) => Frame::Null(),
+        Reply::Deleted(removed) => Frame::Integer(removed as i64),
+        Reply::Datasets(names) => Frame::Array(names.iter().map(|name| bulk(name)).collect()),
+        Reply::Neighbours(neighbours) => Frame::Array(
+            neighbours.
+                iter().
+                map(|(vector, distance)| Frame::Array(vec![float_array(vector), Frame::Double(f64::from(*distance))])).
+                collect()
+        ),
+        // RUSH.* never runs STATS, but the counters read as they do in RESP
+        Reply::Counters(_) => response.into_frame(),
+    }
+}
+
+// The keyspace is the number of datasets and the number of vectors across them.
+fn info(section: Option<&str>, keyspace: (usize, usize), stats: &ServerStats) -> String {
+    let section = section.map(str::to_lowercase);
+    let wanted = |name: &str| match &section {
+        None => true,
+        Some(section) => section == "all" || section == "everything" || section == "default" || section == name,
+    };
+
+    let mut out = String::new();
+    if wanted("server") {
+        out.push_str(&format!("# Server\r\nrush_version:{}\r\n", env!("CARGO_PKG_VERSION")));
+        out.push_str(&format!("simd_backend:{}\r\n\r\n", dispatch::backend()));
+    }
+    if wanted("stats") {
+        out.push_str("# Stats\r\n");
+        for (name, value) in stats.snapshot() {
+            out.push_str(&format!("{}:{}\r\n", name, value));
+        }
+        out.push_str("\r\n");
+    }
+    if wanted("keyspace") {
+        out.push_str(&format!("# Keyspace\r\ndatasets:{}\r\nvectors:{}\r\n", keyspace.0, keyspace.1));
+    }
+    out
+}
+
+fn command(args: &[String]) -> Frame {
+    let describe = |&(name, arity, flags): &(&str, i64, &[&str])| Frame::Array(vec![
+        bulk(name),
+        Frame::Integer(arity),
+        Frame::Array(flags.iter().map(|flag| Frame::Simple(flag.to_string())).collect()),
+        // None of the commands take keys
+        Frame::Integer(0),
+        Frame::Integer(0),
+        Frame::Integer(0),
+    ]);
+
+    let subcommand = args.first().map(|arg| arg.to_lowercase());
+    match subcommand.as_deref() {
+        None => Frame::Array(COMMANDS.iter().map(describe).collect()),
+        Some("count") => Frame::Integer(COMMANDS.len() as i64),
+        // redis-cli asks for documentation on startup, but copes without it
+        Some("docs") => Frame::Array(vec![]),
+        Some("info") => Frame::Array(
+            args[1..].
+                iter().
+                map(|name| match COMMANDS.iter().find(|cmd| cmd.0 == name.to_lowercase()) {
+                    Some(cmd) => describe(cmd),
+                    None => Frame::Null(),
+                }).
+                collect()
+        ),
+        Some(other) => Frame::Error(format!("ERR unknown subcommand '{}'", other)),
+    }
+}
+
+#[cfg(test)]
+mod redis_test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<Frame> {
+        args.iter().map(|arg| bulk(arg)).collect()
+    }
+
+    #[test]
+    fn test_parse_vector_values() {
+        let command = RedisCommand::parse(args(&["RUSH.TOPK", "ds", "VALUES", "3", "1", "-2.5", "3e2", "10"])).unwrap();
+        match command {
+            RedisCommand::Vector(command) => assert_eq!(command, Command::topk("ds", vec![1.0, -2.5, 300.0], 10)),
+            _ => panic!("expected a vector command"),
+        }
+
+        assert!(RedisCommand::parse(args(&["RUSH.PUT", "ds", "VALUES", "3", "1", "2"])).is_err());
+        assert!(RedisCommand::parse(args(&["RUSH.PUT", "ds", "VALUES", "1", "1", "2"])).is_err());
+        assert!(RedisCommand::parse(args(&["RUSH.PUT", "ds", "[1, 2]"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_vector_blob() {
+        let mut frames = args(&["rush.get", "ds", "fp32"]);
+        frames.push(Frame::Bulk(Bytes::from_static(&[0, 0, 128, 63, 0, 0, 0, 64])));
+        match RedisCommand::parse(frames).unwrap() {
+            RedisCommand::Vector(command) => assert_eq!(command, Command::get("ds", vec![1.0, 2.0])),
+            _ => panic!("expected a vector command"),
+        }
+    }
+}
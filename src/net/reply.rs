@@ -0,0 +1,52 @@
+use bytes::Bytes;
+use crate::net::Frame;
+use crate::net::blob::encode_vector;
+
+/// What a command produced.  Each front end encodes it in its own way: RESP
+/// connections with `into_frame`, and the Redis, HTTP and gRPC front ends
+/// with their own types.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Reply {
+    Ok,
+    /// The nearest vector, if the dataset has any.
+    Vector(Option<Vec<f32>>),
+    /// Vectors along with their distance, nearest first.
+    Neighbours(Vec<(Vec<f32>, f32)>),
+    Deleted(bool),
+    /// Dataset names, in order.
+    Datasets(Vec<String>),
+    /// Name and value of every server counter.
+    Counters(Vec<(&'static str, u64)>),
+}
+
+impl Reply {
+    /// The response frame a RESP client reads.
+    pub(crate) fn into_frame(self) -> Frame {
+        match self {
+            Reply::Ok => Frame::Simple("OK".into()),
+            Reply::Vector(Some(vector)) => Frame::Bulk(encode_vector(vector)),
+            Reply::Vector(None) => Frame::Null(),
+            // Each neighbour is a [vector, distance] pair, with the distance
+            // sent as the little endian bytes of an f32
+            Reply::Neighbours(neighbours) => Frame::Array(
+                neighbours.
+                    into_iter().
+                    map(|(vector, distance)| Frame::Array(vec![
+                        Frame::Bulk(encode_vector(vector)),
+                        Frame::Bulk(Bytes::copy_from_slice(&distance.to_le_bytes())),
+                    ])).
+                    collect()
+            ),
+            // The number of items removed, which is either 0 or 1
+            Reply::Deleted(removed) => Frame::Integer(removed as i64),
+            Reply::Datasets(names) => Frame::Array(names.into_iter().map(Frame::Simple).collect()),
+            // Alternating counter names and values
+            Reply::Counters(counters) => Frame::Array(
+                counters.
+                    into_iter().
+                    flat_map(|(name, value)| vec![Frame::Simple(name.into()), Frame::Integer(value as i64)]).
+                    collect()
+            ),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::net::Reply;
+
+/// Counters describing the health of the server.  They are shared between the
+/// listener and every connection handler, so they are updated atomically.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    connections_accepted: AtomicU64,
+    connections_rejected: AtomicU64,
+    accept_failures: AtomicU64,
+    // Commands whose client went away before they finished
+    commands_cancelled: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        ServerStats::default()
+    }
+
+    pub fn record_connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_accept_failure(&self) {
+        self.accept_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_command_cancelled(&self) {
+        self.commands_cancelled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connections_accepted(&self) -> u64 {
+        self.connections_accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn connections_rejected(&self) -> u64 {
+        self.connections_rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn accept_failures(&self) -> u64 {
+        self.accept_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn commands_cancelled(&self) -> u64 {
+        self.commands_cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Name and value of every counter, in a stable order.
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("connections_accepted", self.connections_accepted()),
+            ("connections_rejected", self.connections_rejected()),
+            ("accept_failures", self.accept_failures()),
+            ("commands_cancelled", self.commands_cancelled()),
+        ]
+    }
+}
+
+/// The STATS command, which replies with every counter.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Stats;
+
+impl Stats {
+    pub(crate) async fn execute(self, stats: &ServerStats) -> crate::Result<Reply> {
+        Ok(Reply::Counters(stats.snapshot()))
+    }
+}
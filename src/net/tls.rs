@@ -0,0 +1,77 @@
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// PEM files for terminating TLS on the RESP listener.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// The server's certificate chain, leaf first.
+    pub cert_file: PathBuf,
+    /// The private key for the leaf certificate.
+    pub key_file: PathBuf,
+    /// When given, clients must present a certificate signed by one of the
+    /// CAs in this file.
+    pub client_ca_file: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn new<P: Into<PathBuf>>(cert_file: P, key_file: P) -> Self {
+        TlsConfig { cert_file: cert_file.into(), key_file: key_file.into(), client_ca_file: None }
+    }
+
+    pub fn with_client_ca<P: Into<PathBuf>>(mut self, client_ca_file: P) -> Self {
+        self.client_ca_file = Some(client_ca_file.into());
+        self
+    }
+
+    /// Load the certificates and key, ready to accept TLS sessions.
+    pub fn acceptor(&self) -> crate::Result<TlsAcceptor> {
+        let certs = load_certs(&self.cert_file)?;
+        let key = load_key(&self.key_file)?;
+
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+
+        let builder = match &self.client_ca_file {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(cert)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder.with_single_cert(certs, key)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn open(path: &Path) -> crate::Result<BufReader<File>> {
+    let file = File::open(path).map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+    Ok(BufReader::new(file))
+}
+
+fn load_certs(path: &Path) -> crate::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> crate::Result<PrivateKeyDer<'static>> {
+    match rustls_pemfile::private_key(&mut open(path)?)? {
+        Some(key) => Ok(key),
+        None => Err(format!("no private key found in {}", path.display()).into()),
+    }
+}
@@ -0,0 +1,55 @@
+use tokio::time::Instant;
+use crate::net::{deadline, Datasets, CancelToken, Database, Reply};
+use crate::lsh::vector::Vector;
+
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct TopK {
+    dataset: String,
+    vector: Vec<f32>,
+    k: usize
+}
+
+impl TopK {
+    pub(crate) fn new(dataset: String, vector: Vec<f32>, k: usize) -> Self {
+        TopK { dataset, vector, k }
+    }
+
+    /// The `k` nearest neighbours with their distances, nearest first, or
+    /// `None` if the client went away first.
+    pub(crate) async fn execute<DB: Database>(self, datasets: &Datasets<DB>, deadline: Option<Instant>, cancel: &CancelToken)
+        -> crate::Result<Option<Reply>>
+    where
+        DB::Item: Vector<DType=f32>,
+        for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>,
+    {
+        let dataset = datasets.get(&self.dataset)?;
+        let item = dataset.item(self.vector)?;
+        let metric = dataset.config().metric;
+
+        let db = match deadline::read(dataset.database(), deadline).await {
+            Some(db) => db,
+            None => return Err(deadline::timeout()),
+        };
+
+        // The deadline stops scoring part way through, rather than letting a
+        // slow query run to the end only to be thrown away
+        let neighbours = match db.query_topk_cancellable(&item, self.k, &cancel.with_deadline(deadline.map(Instant::into_std))) {
+            Some(neighbours) => neighbours,
+            None if deadline::expired(deadline) => return Err(deadline::timeout()),
+            // Nobody is waiting for the answer any more
+            None => return Ok(None),
+        };
+
+        let neighbours = neighbours.
+            into_iter().
+            map(|value| (value.into_iter().collect::<Vec<f32>>(), metric.distance(&item, value))).
+            collect::<Vec<(Vec<f32>, f32)>>();
+        drop(db);
+
+        if deadline::expired(deadline) {
+            return Err(deadline::timeout());
+        }
+        Ok(Some(Reply::Neighbours(neighbours)))
+    }
+}
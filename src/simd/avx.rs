@@ -17,6 +17,7 @@ impl SimdNativeType for __m256 {}
 pub type f32x8  = SimdTypeProxy<__m256>;
 
 impl f32x8 {
+    #[allow(clippy::too_many_arguments)]
     fn from_elts(s:f32, t: f32, u: f32, v: f32, w: f32, x: f32, y: f32, z: f32) -> Self {
         unsafe {
             f32x8::new(_mm256_set_ps(z, y, x, w, v, u, t, s))
@@ -40,7 +41,7 @@ impl PartialEq for f32x8 {
             let self_high:  f32x4 = f32x4::new(_mm256_extractf128_ps(self.0, 1));
             let other_low:  f32x4 = f32x4::new(_mm256_castps256_ps128(other.0));
             let other_high: f32x4 = f32x4::new(_mm256_extractf128_ps(other.0, 1));
-            return (self_low == other_low) && (self_high == other_high)
+            (self_low == other_low) && (self_high == other_high)
         }
     }
 }
@@ -85,7 +86,7 @@ impl AddAssign for f32x8 {
 impl SimdType for f32x8 { 
     type ElementType = f32; 
     const LANES: usize = 8;
-    fn pack(elts: &Vec<f32>) -> Self {
+    fn pack(elts: &[f32]) -> Self {
         if elts.len() != 8 {
             panic!("Error attempting to pack elts into f32x8")
         }
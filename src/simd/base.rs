@@ -1,6 +1,7 @@
 use core::ops::{Add, Sub, Div, Mul, AddAssign};
 use std::default::Default;
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub trait SimdNativeType {}
 
 pub trait FusedMulAdd {
@@ -26,18 +27,21 @@ pub trait SimdType:
         Clone +
         Copy;
     const LANES: usize;
-    fn pack(elts: &Vec<Self::ElementType>) -> Self;
+    fn pack(elts: &[Self::ElementType]) -> Self;
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[derive(Debug, Copy, Clone)]
 pub struct SimdTypeProxy<T: SimdNativeType>(pub T);
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 impl<T: SimdNativeType> SimdTypeProxy<T> {
     pub fn new(x: T) -> Self {
         SimdTypeProxy(x)
     }
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 macro_rules! create_binary_simd_trait {
     ($trait:ident, $method:ident, $type:ty) => {
         impl $trait for $type {
@@ -82,6 +86,7 @@ macro_rules! create_binary_simd_trait {
     };
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 macro_rules! create_ternary_simd_trait {
     ($trait:ident, $method:ident, $type:ty) => {
         impl $trait for $type {
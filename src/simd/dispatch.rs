@@ -0,0 +1,486 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+/*
+
+The inner products and distances that hashing and scoring spend their time in
+are computed by one of three backends, picked once for the CPU the server
+finds itself on:
+
+    avx2    f32x8 lanes with fused multiply-add, on CPUs with AVX2 and FMA
+    sse     f32x4 lanes, which every x86_64 CPU has
+    scalar  plain loops, for anything else, including every non-x86 target
+
+Nothing needs to be enabled at build time, so one binary runs on any host.
+Each kernel takes a pair of slices of the same length and works through them
+four registers at a time, finishing whatever is left over with plain loops.
+
+Hashing projects a vector onto many directions at once, so there is also a
+matrix-vector product over a row-major matrix.  It takes four rows at a time,
+loading each block of the vector once and using it for all four rows, so the
+vector is streamed from memory a quarter as often as with one dot per row.
+
+Structured hashes instead mix a vector with the fast Walsh-Hadamard transform,
+an in-place butterfly over a power of two elements.  The passes pairing
+elements a register or more apart add and subtract whole registers, two passes
+for each load and store, and the first few, which pair elements within a
+register, shuffle its lanes.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Avx2,
+    Sse,
+    Scalar,
+}
+
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+/// The backend in use, detected the first time it's asked for.
+pub fn backend() -> Backend {
+    *BACKEND.get_or_init(|| Backend::available()[0])
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    backend().dot(a, b)
+}
+
+pub fn distance(a: &[f32], b: &[f32]) -> f32 {
+    backend().squared_distance(a, b).sqrt()
+}
+
+/// Multiplies a row-major matrix with `out.len()` rows by `x`, into `out`.
+pub fn gemv(matrix: &[f32], x: &[f32], out: &mut [f32]) {
+    backend().gemv(matrix, x, out)
+}
+
+/// Applies the unnormalised Walsh-Hadamard transform to `data` in place.  The
+/// length must be a power of two.
+pub fn fwht(data: &mut [f32]) {
+    backend().fwht(data)
+}
+
+impl Backend {
+    /// Every backend this CPU can run, fastest first.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) fn available() -> Vec<Backend> {
+        let mut backends = Vec::new();
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            backends.push(Backend::Avx2);
+        }
+        if is_x86_feature_detected!("sse") {
+            backends.push(Backend::Sse);
+        }
+        backends.push(Backend::Scalar);
+        backends
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    pub(crate) fn available() -> Vec<Backend> {
+        vec![Backend::Scalar]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Avx2 => "avx2",
+            Backend::Sse => "sse",
+            Backend::Scalar => "scalar",
+        }
+    }
+
+    // Only ever called on one of the available backends
+    pub(crate) fn dot(&self, a: &[f32], b: &[f32]) -> f32 {
+        assert_eq!(a.len(), b.len());
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => unsafe { x86::dot_avx2(a, b) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Sse => unsafe { x86::dot_sse(a, b) },
+            _ => dot_scalar(a, b),
+        }
+    }
+
+    pub(crate) fn squared_distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        assert_eq!(a.len(), b.len());
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => unsafe { x86::squared_distance_avx2(a, b) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Sse => unsafe { x86::squared_distance_sse(a, b) },
+            _ => squared_distance_scalar(a, b),
+        }
+    }
+}
+
+impl Backend {
+    pub(crate) fn gemv(&self, matrix: &[f32], x: &[f32], out: &mut [f32]) {
+        assert_eq!(matrix.len(), x.len() * out.len());
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => unsafe { x86::gemv_avx2(matrix, x, out) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Sse => unsafe { x86::gemv_sse(matrix, x, out) },
+            _ => gemv_scalar(matrix, x, out),
+        }
+    }
+
+    pub(crate) fn fwht(&self, data: &mut [f32]) {
+        assert!(data.len().is_power_of_two(), "the transform needs a power of two elements");
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => unsafe { x86::fwht_avx2(data) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Sse => unsafe { x86::fwht_sse(data) },
+            _ => fwht_scalar(data),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.name().fmt(fmt)
+    }
+}
+
+fn dot_scalar(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+fn squared_distance_scalar(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+fn gemv_scalar(matrix: &[f32], x: &[f32], out: &mut [f32]) {
+    let cols = x.len();
+    for (idx, result) in out.iter_mut().enumerate() {
+        *result = dot_scalar(&matrix[idx * cols..(idx + 1) * cols], x);
+    }
+}
+
+fn fwht_scalar(data: &mut [f32]) {
+    let mut half = 1;
+    while half < data.len() {
+        for block in data.chunks_exact_mut(2 * half) {
+            let (lo, hi) = block.split_at_mut(half);
+            for (a, b) in lo.iter_mut().zip(hi.iter_mut()) {
+                let (x, y) = (*a, *b);
+                *a = x + y;
+                *b = x - y;
+            }
+        }
+        half *= 2;
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use crate::simd::base::FusedMulAdd;
+    use crate::simd::sse::f32x4;
+    use crate::simd::avx::f32x8;
+    use super::{dot_scalar, squared_distance_scalar, gemv_scalar, fwht_scalar};
+
+    #[inline(always)]
+    unsafe fn load4(data: &[f32], idx: usize) -> f32x4 {
+        f32x4::new(_mm_loadu_ps(data.as_ptr().add(idx)))
+    }
+
+    #[inline(always)]
+    unsafe fn load8(data: &[f32], idx: usize) -> f32x8 {
+        f32x8::new(_mm256_loadu_ps(data.as_ptr().add(idx)))
+    }
+
+    #[target_feature(enable = "sse")]
+    pub(super) unsafe fn dot_sse(a: &[f32], b: &[f32]) -> f32 {
+        let split = a.len() - a.len() % 16;
+        let (mut acc1, mut acc2, mut acc3, mut acc4) = (f32x4::default(), f32x4::default(), f32x4::default(), f32x4::default());
+        for (x, y) in a[..split].chunks_exact(16).zip(b[..split].chunks_exact(16)) {
+            acc1 = load4(x, 0) * load4(y, 0) + acc1;
+            acc2 = load4(x, 4) * load4(y, 4) + acc2;
+            acc3 = load4(x, 8) * load4(y, 8) + acc3;
+            acc4 = load4(x, 12) * load4(y, 12) + acc4;
+        }
+        let mut result = dot_scalar(&a[split..], &b[split..]);
+        result += (acc1 + acc2) + (acc3 + acc4);
+        result
+    }
+
+    #[target_feature(enable = "sse")]
+    pub(super) unsafe fn squared_distance_sse(a: &[f32], b: &[f32]) -> f32 {
+        let split = a.len() - a.len() % 16;
+        let (mut acc1, mut acc2, mut acc3, mut acc4) = (f32x4::default(), f32x4::default(), f32x4::default(), f32x4::default());
+        for (x, y) in a[..split].chunks_exact(16).zip(b[..split].chunks_exact(16)) {
+            let (d1, d2) = (load4(x, 0) - load4(y, 0), load4(x, 4) - load4(y, 4));
+            let (d3, d4) = (load4(x, 8) - load4(y, 8), load4(x, 12) - load4(y, 12));
+            acc1 = d1 * d1 + acc1;
+            acc2 = d2 * d2 + acc2;
+            acc3 = d3 * d3 + acc3;
+            acc4 = d4 * d4 + acc4;
+        }
+        let mut result = squared_distance_scalar(&a[split..], &b[split..]);
+        result += (acc1 + acc2) + (acc3 + acc4);
+        result
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dot_avx2(a: &[f32], b: &[f32]) -> f32 {
+        let split = a.len() - a.len() % 32;
+        let (mut acc1, mut acc2, mut acc3, mut acc4) = (f32x8::default(), f32x8::default(), f32x8::default(), f32x8::default());
+        for (x, y) in a[..split].chunks_exact(32).zip(b[..split].chunks_exact(32)) {
+            acc1 = load8(x, 0).fmadd(load8(y, 0), acc1);
+            acc2 = load8(x, 8).fmadd(load8(y, 8), acc2);
+            acc3 = load8(x, 16).fmadd(load8(y, 16), acc3);
+            acc4 = load8(x, 24).fmadd(load8(y, 24), acc4);
+        }
+        let mut result = dot_sse(&a[split..], &b[split..]);
+        result += (acc1 + acc2) + (acc3 + acc4);
+        result
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn squared_distance_avx2(a: &[f32], b: &[f32]) -> f32 {
+        let split = a.len() - a.len() % 32;
+        let (mut acc1, mut acc2, mut acc3, mut acc4) = (f32x8::default(), f32x8::default(), f32x8::default(), f32x8::default());
+        for (x, y) in a[..split].chunks_exact(32).zip(b[..split].chunks_exact(32)) {
+            let (d1, d2) = (load8(x, 0) - load8(y, 0), load8(x, 8) - load8(y, 8));
+            let (d3, d4) = (load8(x, 16) - load8(y, 16), load8(x, 24) - load8(y, 24));
+            acc1 = d1.fmadd(d1, acc1);
+            acc2 = d2.fmadd(d2, acc2);
+            acc3 = d3.fmadd(d3, acc3);
+            acc4 = d4.fmadd(d4, acc4);
+        }
+        let mut result = squared_distance_sse(&a[split..], &b[split..]);
+        result += (acc1 + acc2) + (acc3 + acc4);
+        result
+    }
+
+    #[target_feature(enable = "sse")]
+    pub(super) unsafe fn gemv_sse(matrix: &[f32], x: &[f32], out: &mut [f32]) {
+        let cols = x.len();
+        let split = cols - cols % 4;
+        if cols == 0 {
+            return gemv_scalar(matrix, x, out);
+        }
+
+        let mut blocks = matrix.chunks_exact(4 * cols).zip(out.chunks_exact_mut(4));
+        for (rows, results) in &mut blocks {
+            let (r1, r2, r3, r4) = (&rows[..cols], &rows[cols..2 * cols], &rows[2 * cols..3 * cols], &rows[3 * cols..]);
+            let (mut acc1, mut acc2, mut acc3, mut acc4) = (f32x4::default(), f32x4::default(), f32x4::default(), f32x4::default());
+            for col in (0..split).step_by(4) {
+                let v = load4(x, col);
+                acc1 = load4(r1, col) * v + acc1;
+                acc2 = load4(r2, col) * v + acc2;
+                acc3 = load4(r3, col) * v + acc3;
+                acc4 = load4(r4, col) * v + acc4;
+            }
+            for (result, (row, acc)) in results.iter_mut().zip([(r1, acc1), (r2, acc2), (r3, acc3), (r4, acc4)].iter()) {
+                *result = dot_scalar(&row[split..], &x[split..]);
+                *result += *acc;
+            }
+        }
+
+        let done = out.len() - out.len() % 4;
+        for (row, result) in matrix[done * cols..].chunks_exact(cols).zip(out[done..].iter_mut()) {
+            *result = dot_sse(row, x);
+        }
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn gemv_avx2(matrix: &[f32], x: &[f32], out: &mut [f32]) {
+        let cols = x.len();
+        let split = cols - cols % 8;
+        if cols == 0 {
+            return gemv_scalar(matrix, x, out);
+        }
+
+        let mut blocks = matrix.chunks_exact(4 * cols).zip(out.chunks_exact_mut(4));
+        for (rows, results) in &mut blocks {
+            let (r1, r2, r3, r4) = (&rows[..cols], &rows[cols..2 * cols], &rows[2 * cols..3 * cols], &rows[3 * cols..]);
+            let (mut acc1, mut acc2, mut acc3, mut acc4) = (f32x8::default(), f32x8::default(), f32x8::default(), f32x8::default());
+            for col in (0..split).step_by(8) {
+                let v = load8(x, col);
+                acc1 = load8(r1, col).fmadd(v, acc1);
+                acc2 = load8(r2, col).fmadd(v, acc2);
+                acc3 = load8(r3, col).fmadd(v, acc3);
+                acc4 = load8(r4, col).fmadd(v, acc4);
+            }
+            for (result, (row, acc)) in results.iter_mut().zip([(r1, acc1), (r2, acc2), (r3, acc3), (r4, acc4)].iter()) {
+                *result = dot_sse(&row[split..], &x[split..]);
+                *result += *acc;
+            }
+        }
+
+        let done = out.len() - out.len() % 4;
+        for (row, result) in matrix[done * cols..].chunks_exact(cols).zip(out[done..].iter_mut()) {
+            *result = dot_avx2(row, x);
+        }
+    }
+
+    // The passes pairing neighbours one and two apart, within a register
+    #[inline(always)]
+    unsafe fn fwht_in_register(a: __m128) -> __m128 {
+        let a = _mm_add_ps(
+            _mm_shuffle_ps(a, a, 0b10_10_00_00),
+            _mm_mul_ps(_mm_shuffle_ps(a, a, 0b11_11_01_01), _mm_setr_ps(1.0, -1.0, 1.0, -1.0))
+        );
+        _mm_add_ps(
+            _mm_shuffle_ps(a, a, 0b01_00_01_00),
+            _mm_mul_ps(_mm_shuffle_ps(a, a, 0b11_10_11_10), _mm_setr_ps(1.0, 1.0, -1.0, -1.0))
+        )
+    }
+
+    #[target_feature(enable = "sse")]
+    pub(super) unsafe fn fwht_sse(data: &mut [f32]) {
+        let (len, ptr) = (data.len(), data.as_mut_ptr());
+        if len < 4 {
+            return fwht_scalar(data);
+        }
+
+        for idx in (0..len).step_by(4) {
+            _mm_storeu_ps(ptr.add(idx), fwht_in_register(_mm_loadu_ps(ptr.add(idx))));
+        }
+        let mut half = 4;
+        while 2 * half < len {
+            for block in (0..len).step_by(4 * half) {
+                for idx in (block..block + half).step_by(4) {
+                    let (a, b) = (f32x4::new(_mm_loadu_ps(ptr.add(idx))), f32x4::new(_mm_loadu_ps(ptr.add(idx + half))));
+                    let (c, d) = (f32x4::new(_mm_loadu_ps(ptr.add(idx + 2 * half))), f32x4::new(_mm_loadu_ps(ptr.add(idx + 3 * half))));
+                    let (a, b, c, d) = (a + b, a - b, c + d, c - d);
+                    _mm_storeu_ps(ptr.add(idx), (a + c).0);
+                    _mm_storeu_ps(ptr.add(idx + half), (b + d).0);
+                    _mm_storeu_ps(ptr.add(idx + 2 * half), (a - c).0);
+                    _mm_storeu_ps(ptr.add(idx + 3 * half), (b - d).0);
+                }
+            }
+            half *= 4;
+        }
+        if half < len {
+            for idx in (0..half).step_by(4) {
+                let (a, b) = (f32x4::new(_mm_loadu_ps(ptr.add(idx))), f32x4::new(_mm_loadu_ps(ptr.add(idx + half))));
+                _mm_storeu_ps(ptr.add(idx), (a + b).0);
+                _mm_storeu_ps(ptr.add(idx + half), (a - b).0);
+            }
+        }
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn fwht_avx2(data: &mut [f32]) {
+        let (len, ptr) = (data.len(), data.as_mut_ptr());
+        if len < 8 {
+            return fwht_sse(data);
+        }
+
+        // The first three passes stay within a register, the third pairing
+        // its two halves
+        for idx in (0..len).step_by(8) {
+            let a = _mm256_loadu_ps(ptr.add(idx));
+            let a = _mm256_add_ps(
+                _mm256_shuffle_ps(a, a, 0b10_10_00_00),
+                _mm256_mul_ps(_mm256_shuffle_ps(a, a, 0b11_11_01_01), _mm256_setr_ps(1.0, -1.0, 1.0, -1.0, 1.0, -1.0, 1.0, -1.0))
+            );
+            let a = _mm256_add_ps(
+                _mm256_shuffle_ps(a, a, 0b01_00_01_00),
+                _mm256_mul_ps(_mm256_shuffle_ps(a, a, 0b11_10_11_10), _mm256_setr_ps(1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0))
+            );
+            let a = _mm256_add_ps(
+                _mm256_permute2f128_ps(a, a, 0x00),
+                _mm256_mul_ps(_mm256_permute2f128_ps(a, a, 0x11), _mm256_setr_ps(1.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0, -1.0))
+            );
+            _mm256_storeu_ps(ptr.add(idx), a);
+        }
+        let mut half = 8;
+        while 2 * half < len {
+            for block in (0..len).step_by(4 * half) {
+                for idx in (block..block + half).step_by(8) {
+                    let (a, b) = (f32x8::new(_mm256_loadu_ps(ptr.add(idx))), f32x8::new(_mm256_loadu_ps(ptr.add(idx + half))));
+                    let (c, d) = (f32x8::new(_mm256_loadu_ps(ptr.add(idx + 2 * half))), f32x8::new(_mm256_loadu_ps(ptr.add(idx + 3 * half))));
+                    let (a, b, c, d) = (a + b, a - b, c + d, c - d);
+                    _mm256_storeu_ps(ptr.add(idx), (a + c).0);
+                    _mm256_storeu_ps(ptr.add(idx + half), (b + d).0);
+                    _mm256_storeu_ps(ptr.add(idx + 2 * half), (a - c).0);
+                    _mm256_storeu_ps(ptr.add(idx + 3 * half), (b - d).0);
+                }
+            }
+            half *= 4;
+        }
+        if half < len {
+            for idx in (0..half).step_by(8) {
+                let (a, b) = (f32x8::new(_mm256_loadu_ps(ptr.add(idx))), f32x8::new(_mm256_loadu_ps(ptr.add(idx + half))));
+                _mm256_storeu_ps(ptr.add(idx), (a + b).0);
+                _mm256_storeu_ps(ptr.add(idx + half), (a - b).0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod dispatch_test {
+    use super::*;
+
+    #[test]
+    fn test_backends_agree() {
+        // Lengths either side of every register and unrolled loop boundary
+        for len in [0usize, 1, 3, 4, 15, 16, 17, 31, 32, 33, 100, 768].iter() {
+            let a = (0..*len).map(|i| (i as f32 * 0.37).sin()).collect::<Vec<f32>>();
+            let b = (0..*len).map(|i| (i as f32 * 0.91).cos()).collect::<Vec<f32>>();
+            let dot = dot_scalar(&a, &b);
+            let squared_distance = squared_distance_scalar(&a, &b);
+
+            for backend in Backend::available() {
+                assert!((backend.dot(&a, &b) - dot).abs() < 1e-3, "{} dot of length {}", backend, len);
+                assert!((backend.squared_distance(&a, &b) - squared_distance).abs() < 1e-3, "{} distance of length {}", backend, len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gemv_backends_agree() {
+        // Row counts either side of the blocks of four, and column counts
+        // either side of a register
+        for (rows, cols) in [(0usize, 8usize), (1, 1), (3, 7), (4, 8), (5, 17), (64, 768), (7, 0)].iter() {
+            let matrix = (0..rows * cols).map(|i| (i as f32 * 0.13).sin()).collect::<Vec<f32>>();
+            let x = (0..*cols).map(|i| (i as f32 * 0.71).cos()).collect::<Vec<f32>>();
+            let expected = (0..*rows).map(|row| dot_scalar(&matrix[row * cols..(row + 1) * cols], &x)).collect::<Vec<f32>>();
+
+            for backend in Backend::available() {
+                let mut out = vec![f32::NAN; *rows];
+                backend.gemv(&matrix, &x, &mut out);
+                for (actual, expected) in out.iter().zip(expected.iter()) {
+                    assert!((actual - expected).abs() < 1e-3, "{} gemv of {}x{}", backend, rows, cols);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fwht_backends_agree() {
+        for len in [1usize, 2, 4, 8, 16, 64, 1024].iter() {
+            let data = (0..*len).map(|i| (i as f32 * 0.29).sin()).collect::<Vec<f32>>();
+
+            // Each output is the inner product with a row of the Sylvester
+            // Hadamard matrix, whose entries are the parity of row & col
+            let expected = (0..*len).
+                map(|row| data.iter().enumerate().map(|(col, x)| if (row & col).count_ones() % 2 == 0 { *x } else { -x }).sum::<f32>()).
+                collect::<Vec<f32>>();
+
+            for backend in Backend::available() {
+                let mut out = data.clone();
+                backend.fwht(&mut out);
+                for (actual, expected) in out.iter().zip(expected.iter()) {
+                    assert!((actual - expected).abs() < 1e-3, "{} transform of length {}", backend, len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_backend_is_fastest_available() {
+        assert_eq!(backend(), Backend::available()[0]);
+        assert_eq!(Backend::available().last(), Some(&Backend::Scalar));
+    }
+}
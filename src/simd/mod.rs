@@ -1,9 +1,19 @@
 #[macro_use]
 mod base;
-mod murmur;
+pub mod murmur;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub mod sse;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub mod avx;
+pub mod scalar;
 pub mod vec;
-pub use vec::SimdVecImpl;
+pub mod dispatch;
+pub use vec::{SimdVecImpl, SimdVector};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub use sse::f32x4;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub use avx::f32x8;
+// Without the x86 registers, the same names stand for plain arrays
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub use scalar::{f32x4, f32x8};
+pub use dispatch::Backend;
@@ -1,30 +1,49 @@
-use crate::simd::sse::f32x4;
-//TODO: f32x8
+use std::convert::TryInto;
+use std::mem::size_of_val;
+use crate::simd::base::SimdType;
+
+/*
+
+MurmurHash3, as in the reference implementation from SMHasher.  The hashes
+take raw bytes, or a slice of SIMD chunks, which are hashed as the bytes they
+are made of.  Blocks are read little endian, so the output is the same on
+every target and matches other implementations, e.g. Python's mmh3.
+
+*/
 
 #[inline(always)]
-fn getblock32(block: &f32x4, idx: u8) -> u32 {
-    unsafe {
-        let chunk_ptr = std::mem::transmute::<&f32x4, *const u32>(block);     
-        let elt_ptr = chunk_ptr.offset(idx.into());
-        *elt_ptr
-    }
+fn getblock32(data: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes(data[4 * idx..4 * idx + 4].try_into().unwrap())
 }
 
 #[inline(always)]
-fn getblock64(block: &f32x4, idx: u8) -> u64 {
+fn getblock64(data: &[u8], idx: usize) -> u64 {
+    u64::from_le_bytes(data[8 * idx..8 * idx + 8].try_into().unwrap())
+}
+
+// Reads up to `size` bytes of a tail as a little endian integer
+#[inline(always)]
+fn gettail(tail: &[u8], from: usize, size: usize) -> Option<u64> {
+    if tail.len() <= from {
+        return None;
+    }
+    let bytes = &tail[from..tail.len().min(from + size)];
+    Some(bytes.iter().rev().fold(0u64, |acc, byte| (acc << 8) | *byte as u64))
+}
+
+fn chunk_bytes<T: SimdType>(data: &[T]) -> &[u8] {
+    // SIMD chunks are plain lanes of numbers with no padding between them
     unsafe {
-        let chunk_ptr = std::mem::transmute::<&f32x4, *const u64>(block);     
-        let elt_ptr = chunk_ptr.offset(idx.into());
-        *elt_ptr
+        std::slice::from_raw_parts(data.as_ptr() as *const u8, size_of_val(data))
     }
 }
 
 #[inline(always)]
 fn fmix32(mut h: u32) -> u32 {
   h ^= h >> 16;
-  h *= 0x85ebca6bu32;
+  h = h.wrapping_mul(0x85ebca6bu32);
   h ^= h >> 13;
-  h *= 0xc2b2ae35u32;
+  h = h.wrapping_mul(0xc2b2ae35u32);
   h ^= h >> 16;
   h
 }
@@ -32,110 +51,164 @@ fn fmix32(mut h: u32) -> u32 {
 #[inline(always)]
 fn fmix64(mut k: u64) -> u64 {
   k ^= k >> 33;
-  k *= 0xff51afd7ed558ccdu64;
+  k = k.wrapping_mul(0xff51afd7ed558ccdu64);
   k ^= k >> 33;
-  k *= 0xc4ceb9fe1a85ec53u64;
+  k = k.wrapping_mul(0xc4ceb9fe1a85ec53u64);
   k ^= k >> 33;
   k
 }
 
 #[inline(always)]
-fn rotl32(x: u32, r: i8) -> u32 {
-    (x << r) | (x >> (32 - r))
+fn rotl32(x: u32, r: u32) -> u32 {
+    x.rotate_left(r)
 }
 
 #[inline(always)]
-fn rotl64(x: u64, r: i8) -> u64 {
-  return (x << r) | (x >> (64 - r));
+fn rotl64(x: u64, r: u32) -> u64 {
+    x.rotate_left(r)
 }
 
-const C32: &'static [u32] = &[0x239b961b, 0xab0e9789, 0x38b34ae5, 0xa1e38b93];
-const C64: &'static [u64] = &[0x87c37b91114253d5, 0x4cf5ad432745937f];
+const C32: &[u32] = &[0x239b961b, 0xab0e9789, 0x38b34ae5, 0xa1e38b93];
+const C64: &[u64] = &[0x87c37b91114253d5, 0x4cf5ad432745937f];
+
+pub fn murmur3_x86_128<T: SimdType>(data: &[T], seed: u32) -> u128 {
+    murmur3_x86_128_bytes(chunk_bytes(data), seed)
+}
 
-pub fn murmur3_x86_128(data: &[f32x4], seed: u32) -> u128 {
+pub fn murmur3_x86_128_bytes(data: &[u8], seed: u32) -> u128 {
     let mut h: [u32; 4] = [seed; 4];
     let mut k = [0u32; 4];
-    let len: u32 = (data.len() as u32) * 4 * 4; // Length of data, in bytes
+    let len: u32 = data.len() as u32; // Length of data, in bytes
 
-    for block in data {
+    let blocks = data.chunks_exact(16);
+    let tail = blocks.remainder();
+    for block in blocks {
         k[0] = getblock32(block, 0);
         k[1] = getblock32(block, 1);
         k[2] = getblock32(block, 2);
         k[3] = getblock32(block, 3);
         
-        k[0] *= C32[0]; k[0] = rotl32(k[0], 15); k[0] *= C32[1];
-        h[0] ^= k[0];   h[0] = rotl32(h[0], 19); h[0] += h[1];
-        h[0] = h[0] * 5 + 0x561ccd1bu32;
+        k[0] = k[0].wrapping_mul(C32[0]); k[0] = rotl32(k[0], 15); k[0] = k[0].wrapping_mul(C32[1]);
+        h[0] ^= k[0];   h[0] = rotl32(h[0], 19); h[0] = h[0].wrapping_add(h[1]);
+        h[0] = h[0].wrapping_mul(5).wrapping_add(0x561ccd1bu32);
         
-        k[1] *= C32[1]; k[1] = rotl32(k[1], 16); k[1] *= C32[2];
-        h[1] ^= k[1];   h[1] = rotl32(h[1], 17); h[1] += h[2];
-        h[1] = h[1] * 5 + 0x0bcaa747u32;
+        k[1] = k[1].wrapping_mul(C32[1]); k[1] = rotl32(k[1], 16); k[1] = k[1].wrapping_mul(C32[2]);
+        h[1] ^= k[1];   h[1] = rotl32(h[1], 17); h[1] = h[1].wrapping_add(h[2]);
+        h[1] = h[1].wrapping_mul(5).wrapping_add(0x0bcaa747u32);
         
-        k[2] *= C32[2]; k[2] = rotl32(k[2], 17); k[2] *= C32[3];
-        h[2] ^= k[2];   h[2] = rotl32(h[2], 15); h[2] += h[3];
-        h[2] = h[2] * 5 + 0x96cd1c35u32;
+        k[2] = k[2].wrapping_mul(C32[2]); k[2] = rotl32(k[2], 17); k[2] = k[2].wrapping_mul(C32[3]);
+        h[2] ^= k[2];   h[2] = rotl32(h[2], 15); h[2] = h[2].wrapping_add(h[3]);
+        h[2] = h[2].wrapping_mul(5).wrapping_add(0x96cd1c35u32);
         
-        k[3] *= C32[3]; k[3] = rotl32(k[3], 18); k[3] *= C32[0];
-        h[3] ^= k[3];   h[3] = rotl32(h[3], 13); h[3] += h[0];
-        h[3] = h[3] * 5 + 0x32ac3b17u32;
+        k[3] = k[3].wrapping_mul(C32[3]); k[3] = rotl32(k[3], 18); k[3] = k[3].wrapping_mul(C32[0]);
+        h[3] ^= k[3];   h[3] = rotl32(h[3], 13); h[3] = h[3].wrapping_add(h[0]);
+        h[3] = h[3].wrapping_mul(5).wrapping_add(0x32ac3b17u32);
+    }
+
+    // The last few bytes fill the lanes from the first, mixed without the
+    // rotation and addition of the state
+    const TAIL_ROTATIONS: [u32; 4] = [15, 16, 17, 18];
+    for lane in 0..4 {
+        if let Some(tail) = gettail(tail, 4 * lane, 4) {
+            let mut k = (tail as u32).wrapping_mul(C32[lane]);
+            k = rotl32(k, TAIL_ROTATIONS[lane]);
+            h[lane] ^= k.wrapping_mul(C32[(lane + 1) % 4]);
+        }
     }
 
     h[0] ^= len; h[1] ^= len; h[2] ^= len; h[3] ^= len;
 
-    h[0] += h[1]; h[0] += h[2]; h[0] += h[3];
-    h[1] += h[0]; h[2] += h[0]; h[3] += h[0];
+    h[0] = h[0].wrapping_add(h[1]); h[0] = h[0].wrapping_add(h[2]); h[0] = h[0].wrapping_add(h[3]);
+    h[1] = h[1].wrapping_add(h[0]); h[2] = h[2].wrapping_add(h[0]); h[3] = h[3].wrapping_add(h[0]);
 
     h[0] = fmix32(h[0]);
     h[1] = fmix32(h[1]);
     h[2] = fmix32(h[2]);
     h[3] = fmix32(h[3]);
 
-    h[0] += h[1]; h[0] += h[2]; h[0] += h[3];
-    h[1] += h[0]; h[2] += h[0]; h[3] += h[0];
+    h[0] = h[0].wrapping_add(h[1]); h[0] = h[0].wrapping_add(h[2]); h[0] = h[0].wrapping_add(h[3]);
+    h[1] = h[1].wrapping_add(h[0]); h[2] = h[2].wrapping_add(h[0]); h[3] = h[3].wrapping_add(h[0]);
     
-    unsafe {
-        let result_ptr = std::mem::transmute::<&[u32; 4], *const u128>(&h);
-        *result_ptr
-    }
+    h.iter().rev().fold(0u128, |acc, word| (acc << 32) | *word as u128)
 }
 
-pub fn murmur3_x64_128(data: &[f32x4], seed: u32) -> u128 {
+pub fn murmur3_x64_128<T: SimdType>(data: &[T], seed: u32) -> u128 {
+    murmur3_x64_128_bytes(chunk_bytes(data), seed)
+}
+
+pub fn murmur3_x64_128_bytes(data: &[u8], seed: u32) -> u128 {
     let mut h: [u64; 2] = [seed as u64; 2]; 
     let mut k: [u64; 2] = [0; 2];
-    let len: u64 = (data.len() as u64) * 4 * 4; 
+    let len: u64 = data.len() as u64; 
 
-    for block in data {
+    let blocks = data.chunks_exact(16);
+    let tail = blocks.remainder();
+    for block in blocks {
         k[0] = getblock64(block, 0);
         k[1] = getblock64(block, 1);
         
-        k[0] *= C64[0]; k[0] = rotl64(k[0], 31); k[0] *= C64[1]; h[0] ^= k[0];
-        h[0] = rotl64(h[0], 27); h[0] += h[1]; h[0] = h[0] * 5 + 0x52dce729u64;
+        k[0] = k[0].wrapping_mul(C64[0]); k[0] = rotl64(k[0], 31); k[0] = k[0].wrapping_mul(C64[1]); h[0] ^= k[0];
+        h[0] = rotl64(h[0], 27); h[0] = h[0].wrapping_add(h[1]); h[0] = h[0].wrapping_mul(5).wrapping_add(0x52dce729u64);
+
+        k[1] = k[1].wrapping_mul(C64[1]); k[1] = rotl64(k[1], 33); k[1] = k[1].wrapping_mul(C64[0]); h[1] ^= k[1];
+        h[1] = rotl64(h[1],31); h[1] = h[1].wrapping_add(h[0]); h[1] = h[1].wrapping_mul(5).wrapping_add(0x38495ab5u64);
+    }
 
-        k[1] *= C64[1]; k[1] = rotl64(k[1], 33); k[1] *= C64[0]; h[1] ^= k[1];
-        h[1] = rotl64(h[1],31); h[1] += h[0]; h[1] = h[1] * 5 + 0x38495ab5u64;
+    if let Some(tail) = gettail(tail, 8, 8) {
+        let k = rotl64(tail.wrapping_mul(C64[1]), 33);
+        h[1] ^= k.wrapping_mul(C64[0]);
+    }
+    if let Some(tail) = gettail(tail, 0, 8) {
+        let k = rotl64(tail.wrapping_mul(C64[0]), 31);
+        h[0] ^= k.wrapping_mul(C64[1]);
     }
 
     h[0] ^= len; h[1] ^= len;
-    h[0] += h[1];
-    h[1] += h[0];
+    h[0] = h[0].wrapping_add(h[1]);
+    h[1] = h[1].wrapping_add(h[0]);
 
     h[0] = fmix64(h[0]);
     h[1] = fmix64(h[1]);
 
-    h[0] += h[1];
-    h[1] += h[0];
+    h[0] = h[0].wrapping_add(h[1]);
+    h[1] = h[1].wrapping_add(h[0]);
 
-    unsafe {
-        let result_ptr = std::mem::transmute::<&[u64; 2], *const u128>(&h);
-        *result_ptr
-    }
+    ((h[1] as u128) << 64) | h[0] as u128
 }
 
 #[cfg(test)]
 mod murmurhash_test {
     use super::*;
-    use crate::simd::sse::*;
-    
+    use crate::simd::{f32x4, f32x8};
+
+    // SMHasher's check: hash keys 0, 0 1, 0 1 2, ... of every length up to
+    // 255 with seed 256 - length, then hash the concatenated hashes
+    fn verification(hash: fn(&[u8], u32) -> u128) -> u32 {
+        let key = (0..=255u8).collect::<Vec<u8>>();
+        let hashes = (0..256).
+            flat_map(|len| hash(&key[..len], 256 - len as u32).to_le_bytes().to_vec()).
+            collect::<Vec<u8>>();
+        hash(&hashes, 0) as u32
+    }
+
+    #[test]
+    fn test_mmh_reference_verification() {
+        assert_eq!(verification(murmur3_x64_128_bytes), 0x6384BA69);
+        assert_eq!(verification(murmur3_x86_128_bytes), 0xB3ECE62A);
+    }
+
+    #[test]
+    fn test_mmh_chunks_hash_as_bytes() {
+        let lanes = (0..16).map(|i| i as f32 - 7.5).collect::<Vec<f32>>();
+        let bytes = lanes.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+        let narrow = lanes.chunks(4).map(f32x4::pack).collect::<Vec<f32x4>>();
+        let wide = lanes.chunks(8).map(f32x8::pack).collect::<Vec<f32x8>>();
+
+        assert_eq!(murmur3_x64_128(&narrow, 7), murmur3_x64_128_bytes(&bytes, 7));
+        assert_eq!(murmur3_x64_128(&wide, 7), murmur3_x64_128_bytes(&bytes, 7));
+        assert_eq!(murmur3_x86_128(&wide, 7), murmur3_x86_128_bytes(&bytes, 7));
+    }
+
     #[test]
     fn test_mmh_x64_128_zero() {
         let data = [f32x4::default();1];
@@ -0,0 +1,161 @@
+#![allow(non_camel_case_types)]
+use std::convert::TryInto;
+use std::default::Default;
+use core::ops::{Add, Sub, Div, Mul, AddAssign};
+use crate::simd::base::*;
+
+/*
+
+Plain arrays standing in for the x86 registers on targets without them.  They
+are laid out and aligned like `__m128` and `__m256`, so code that reads a
+vector's memory directly, such as the murmur hash, sees the same bytes.  The
+compiler is usually able to vectorise the lane by lane loops itself.
+
+*/
+
+macro_rules! scalar_simd_type {
+    ($name:ident, $lanes:literal, $align:literal) => {
+        #[derive(Debug, Copy, Clone, PartialEq)]
+        #[repr(C, align($align))]
+        pub struct $name(pub [f32; $lanes]);
+
+        impl $name {
+            #[inline(always)]
+            fn lanewise(self, other: Self, op: impl Fn(f32, f32) -> f32) -> Self {
+                $name(std::array::from_fn(|idx| op(self.0[idx], other.0[idx])))
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name([0f32; $lanes])
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl Add for $name {
+            type Output = $name;
+            #[inline(always)]
+            fn add(self, other: Self) -> Self {
+                self.lanewise(other, |x, y| x + y)
+            }
+        }
+
+        impl Sub for $name {
+            type Output = $name;
+            #[inline(always)]
+            fn sub(self, other: Self) -> Self {
+                self.lanewise(other, |x, y| x - y)
+            }
+        }
+
+        impl Mul for $name {
+            type Output = $name;
+            #[inline(always)]
+            fn mul(self, other: Self) -> Self {
+                self.lanewise(other, |x, y| x * y)
+            }
+        }
+
+        impl Div for $name {
+            type Output = $name;
+            #[inline(always)]
+            fn div(self, other: Self) -> Self {
+                self.lanewise(other, |x, y| x / y)
+            }
+        }
+
+        impl FusedMulAdd for $name {
+            type Output = $name;
+            #[inline(always)]
+            fn fmadd(self, b: Self, c: Self) -> Self {
+                $name(std::array::from_fn(|idx| self.0[idx].mul_add(b.0[idx], c.0[idx])))
+            }
+        }
+
+        impl Mul<$name> for f32 {
+            type Output = $name;
+            #[inline(always)]
+            fn mul(self, rhs: $name) -> $name {
+                $name(rhs.0.map(|x| self * x))
+            }
+        }
+
+        impl AddAssign<$name> for f32 {
+            #[inline(always)]
+            fn add_assign(&mut self, rhs: $name) {
+                *self += rhs.0.iter().sum::<f32>();
+            }
+        }
+
+        impl AddAssign for $name {
+            #[inline(always)]
+            fn add_assign(&mut self, rhs: $name) {
+                *self = *self + rhs;
+            }
+        }
+
+        impl SimdType for $name {
+            type ElementType = f32;
+            const LANES: usize = $lanes;
+            fn pack(elts: &[f32]) -> Self {
+                match elts.try_into() {
+                    Ok(lanes) => $name(lanes),
+                    Err(_) => panic!(concat!("Error attempting to pack elts into ", stringify!($name))),
+                }
+            }
+        }
+    };
+}
+
+scalar_simd_type!(f32x4, 4, 16);
+scalar_simd_type!(f32x8, 8, 32);
+
+#[cfg(test)]
+mod simd_scalar_tests {
+    use super::*;
+    use crate::lsh::vector::Vector;
+    use crate::simd::vec::SimdVecImpl;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(std::mem::size_of::<f32x4>(), 16);
+        assert_eq!(std::mem::align_of::<f32x4>(), 16);
+        assert_eq!(std::mem::size_of::<f32x8>(), 32);
+        assert_eq!(std::mem::align_of::<f32x8>(), 32);
+    }
+
+    #[test]
+    fn test_arithmetic_f32x4() {
+        let x = f32x4([1.0, 2.0, 3.0, 4.0]);
+        let y = f32x4([1.5, 0.5, -1.0, 0.0]);
+        assert_eq!(x + y, f32x4([2.5, 2.5, 2.0, 4.0]));
+        assert_eq!(x - y, f32x4([-0.5, 1.5, 4.0, 4.0]));
+        assert_eq!(x * y, f32x4([1.5, 1.0, -3.0, 0.0]));
+        assert_eq!(x.fmadd(y, x), f32x4([2.5, 3.0, 0.0, 4.0]));
+        assert_ne!(x, y);
+
+        let mut result = 0f32;
+        result += x;
+        assert_eq!(result, 1.0 + 2.0 + 3.0 + 4.0);
+    }
+
+    #[test]
+    fn test_reduce_sum_f32x8() {
+        let x = f32x8::pack(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        let mut result = 0f32;
+        result += 2f32 * x;
+        assert_eq!(result, 72.0);
+    }
+
+    #[test]
+    fn test_simd_vec_impl() {
+        // The compile time vectors work the same over scalar lanes
+        let x = (0..32).map(|i| i as f32).collect::<SimdVecImpl<f32x8, 4>>();
+        let y = vec![1f32; 32].into_iter().collect::<SimdVecImpl<f32x8, 4>>();
+        assert_eq!(x.dot(&y), (0..32).sum::<i32>() as f32);
+        assert_eq!(x.distance(&x), 0f32);
+        assert_eq!(x.dimension(), 32);
+    }
+}
@@ -35,16 +35,8 @@ impl PartialEq for f32x4 {
     #[inline(always)]
     fn eq(&self, other: &Self) -> bool {
         unsafe {
-            let elementwise_result = _mm_cmpeq_ps(self.0, other.0);
-            let b_b_d_d = _mm_movehdup_ps(elementwise_result);
-            let ab_2b_cd_2d = _mm_and_ps(elementwise_result, b_b_d_d);
-            let cd_2d_d_d = _mm_movehl_ps(b_b_d_d, ab_2b_cd_2d);
-            let abcd_rest = _mm_and_ps(ab_2b_cd_2d, cd_2d_d_d);
-            let reduced_eq = _mm_cvtss_si32(abcd_rest);
-            // The only possibilities are that this is zero (neq), or 0xFFFFFFFF.
-            // I'm not sure the best way to compare 0xFFFFFFFF as i32 so I'll just
-            // compare to neq 0.
-            reduced_eq != 0
+            // One bit per lane, set where the lanes are equal
+            _mm_movemask_ps(_mm_cmpeq_ps(self.0, other.0)) == 0b1111
         }
     }
 }
@@ -55,17 +47,28 @@ create_binary_simd_trait!(Add, add, f32x4);
 create_binary_simd_trait!(Sub, sub, f32x4);
 create_binary_simd_trait!(Mul, mul, f32x4);
 create_binary_simd_trait!(Div, div, f32x4);
-create_ternary_simd_trait!(FusedMulAdd, fmadd, f32x4);
+
+// SSE has no fused multiply-add, and _mm_fmadd_ps needs FMA3, which not every
+// x86_64 CPU has.  A multiply and an add runs everywhere.
+impl FusedMulAdd for f32x4 {
+    type Output = f32x4;
+
+    #[inline(always)]
+    fn fmadd(self, b: Self, c: Self) -> Self {
+        self * b + c
+    }
+}
 
 impl AddAssign<f32x4> for f32 {
     #[inline(always)]
     fn add_assign(&mut self, rhs: f32x4) {
         unsafe {
-            // In our notation, z := a_b_c_d
-            let b_b_d_d = _mm_movehdup_ps(rhs.0);
-            let ab_2b_cd_2d = _mm_add_ps(rhs.0, b_b_d_d);
-            let cd_2d_d_d = _mm_movehl_ps(b_b_d_d, ab_2b_cd_2d);
-            let abcd_rest = _mm_add_ss(ab_2b_cd_2d, cd_2d_d_d);
+            // In our notation, z := a_b_c_d.  Shuffles rather than the SSE3
+            // _mm_movehdup_ps keep this to plain SSE.
+            let b_a_d_c = _mm_shuffle_ps(rhs.0, rhs.0, 0b10_11_00_01);
+            let ab_ab_cd_cd = _mm_add_ps(rhs.0, b_a_d_c);
+            let cd_cd_d_c = _mm_movehl_ps(b_a_d_c, ab_ab_cd_cd);
+            let abcd_rest = _mm_add_ss(ab_ab_cd_cd, cd_cd_d_c);
             let reduction: f32 = _mm_cvtss_f32(abcd_rest);
             *self += reduction;
         }
@@ -92,7 +95,7 @@ impl AddAssign for f32x4 {
 impl SimdType for f32x4 { 
     type ElementType = f32; 
     const LANES: usize = 4;
-    fn pack(elts: &Vec<f32>) -> Self {
+    fn pack(elts: &[f32]) -> Self {
         if elts.len() != 4 {
             panic!("Error attempting to pack elts into f32x4")
         }
@@ -1,14 +1,11 @@
 use crate::simd::base::*;
 use crate::lsh::lsh_database::Cacheable;
-use crate::simd::sse::f32x4;
-//use crate::simd::avx::f32x8;
 use crate::simd::murmur::murmur3_x64_128;
+use crate::simd::dispatch;
 use core::ops::{Add, Sub, Div, Mul, AddAssign};
 use std::iter::{IntoIterator, FromIterator, Iterator};
 use itertools::zip_eq;
 
-trait SimdVec  { } 
-
 #[derive(Debug)]
 pub struct SimdVecImpl<T: SimdType, const MMBLOCKS: usize> {
     chunks: [T; MMBLOCKS]
@@ -30,7 +27,7 @@ impl<'a, T: SimdType, const MMBLOCKS: usize> Iterator for SimdVecImplElementIter
             let chunk_idx = self.cur / T::LANES;
             let lane_idx = (self.cur % T::LANES) as isize;
             unsafe { 
-                let chunk_ptr = std::mem::transmute::<&T, *const T::ElementType>(&arr[chunk_idx]);
+                let chunk_ptr = &arr[chunk_idx] as *const T as *const T::ElementType;
                 let element_ptr = chunk_ptr.offset(lane_idx);
                 self.cur += 1;
                 Some(*element_ptr)
@@ -44,7 +41,7 @@ impl<'a, T: SimdType, const MMBLOCKS: usize> IntoIterator for &'a SimdVecImpl<T,
     type IntoIter = SimdVecImplElementIterator<'a, T, MMBLOCKS>;
     fn into_iter(self) -> <Self as IntoIterator>::IntoIter {
         SimdVecImplElementIterator {
-            obj: &self,
+            obj: self,
             cur: 0
         }
     }
@@ -59,14 +56,13 @@ impl<'a, T: SimdType, const MMBLOCKS: usize> Iterator for SimdVecImplIterator<'a
     type Item = (T, T, T, T);
     fn next(&mut self) -> Option<Self::Item> {
         let arr = &self.obj.chunks;
-        let result: Option<Self::Item>;
-        
-        if self.cur <= MMBLOCKS - 4 {
-            result = Some((arr[self.cur], arr[self.cur + 1], arr[self.cur + 2], arr[self.cur + 3]));
+        // Chunks past the last whole group of four are left to `tail_chunks`
+        let result = if self.cur + 4 <= MMBLOCKS {
+            Some((arr[self.cur], arr[self.cur + 1], arr[self.cur + 2], arr[self.cur + 3]))
         }
         else {
-            result = None
-        }
+            None
+        };
         self.cur += 4;        
         result
     }
@@ -86,10 +82,14 @@ impl<T: SimdType, const MMBLOCKS: usize> SimdVecImpl<T, MMBLOCKS> {
 
     fn chunk_iter<'a>(&'a self) -> SimdVecImplIterator<'a, T, MMBLOCKS> {
         SimdVecImplIterator {
-            obj: &self,
+            obj: self,
             cur: 0
         }
     }
+
+    fn tail_chunks(&self) -> &[T] {
+        &self.chunks[MMBLOCKS - MMBLOCKS % 4..]
+    }
 }
 
 impl<T: SimdType, const MMBLOCKS: usize> FromIterator<T::ElementType> for SimdVecImpl<T, MMBLOCKS> {
@@ -126,7 +126,7 @@ impl<T: SimdType, const MMBLOCKS: usize> FromIterator<T::ElementType> for SimdVe
         }
 
         // If we didn't complete a chunk, write the last one in.
-        if idx % T::LANES > 0 {
+        if !idx.is_multiple_of(T::LANES) {
             result.set_chunk(chunk_idx, T::pack(&chunk_vec))
         }
 
@@ -226,12 +226,15 @@ impl<T: SimdType<ElementType=f32>, const MMBLOCKS: usize> Vector for SimdVecImpl
     type DType = T::ElementType;
 
     fn dot(&self, other: &Self) -> <Self as Vector>::DType {
-        let (ns1, ns2, ns3, ns4) = 
+        let (mut ns1, ns2, ns3, ns4) = 
             zip_eq(self.chunk_iter(), other.chunk_iter()).
             fold((T::default(), T::default(), T::default(), T::default()), 
                  |(acc1, acc2, acc3, acc4), ((x1, x2, x3, x4), (y1, y2, y3, y4))| {
                      (x1.fmadd(y1, acc1), x2.fmadd(y2, acc2), x3.fmadd(y3, acc3), x4.fmadd(y4, acc4))
                  });
+        for (x, y) in zip_eq(self.tail_chunks(), other.tail_chunks()) {
+            ns1 = x.fmadd(*y, ns1);
+        }
         let mut hsum = 0f32;
         let r1 =  ns1 + ns2;
         let r2 =  ns3 + ns4;
@@ -241,7 +244,7 @@ impl<T: SimdType<ElementType=f32>, const MMBLOCKS: usize> Vector for SimdVecImpl
     }
 
     fn distance(&self, other: &Self) -> <Self as Vector>::DType {
-        let (ns1, ns2, ns3, ns4) = 
+        let (mut ns1, ns2, ns3, ns4) = 
             zip_eq(self.chunk_iter(), other.chunk_iter()).
                 fold((T::default(), T::default(), T::default(), T::default()), |(acc1, acc2, acc3, acc4), ((x1, x2, x3, x4), (y1, y2, y3, y4))| {
                     let delta1 = x1 - y1;
@@ -251,6 +254,10 @@ impl<T: SimdType<ElementType=f32>, const MMBLOCKS: usize> Vector for SimdVecImpl
                     (delta1 * delta1 + acc1, delta2 * delta2 + acc2, delta3 * delta3 + acc3, delta4 * delta4 + acc4)
                     //(delta1.fmadd(delta1, acc1), delta2.fmadd(delta2, acc2), delta3.fmadd(delta3, acc3), delta4.fmadd(delta4, acc4))
                 });
+        for (x, y) in zip_eq(self.tail_chunks(), other.tail_chunks()) {
+            let delta = *x - *y;
+            ns1 = delta * delta + ns1;
+        }
         let mut result = 0f32;
         let r1 =  ns1 + ns2;
         let r2 =  ns3 + ns4;
@@ -262,6 +269,12 @@ impl<T: SimdType<ElementType=f32>, const MMBLOCKS: usize> Vector for SimdVecImpl
     fn dimension(&self) -> usize {
         MMBLOCKS * T::LANES
     }
+
+    fn as_slice(&self) -> &[<Self as Vector>::DType] {
+        unsafe {
+            std::slice::from_raw_parts(self.chunks.as_ptr() as *const T::ElementType, MMBLOCKS * T::LANES)
+        }
+    }
 }
 
 impl<T: SimdType, const MMBLOCKS: usize> PartialEq for SimdVecImpl<T, MMBLOCKS> 
@@ -277,7 +290,7 @@ impl<T: SimdType, const MMBLOCKS: usize> Eq for SimdVecImpl<T, MMBLOCKS>
 where T: Default+PartialEq
 {}
 
-impl<const MMBLOCKS: usize> Cacheable for SimdVecImpl<f32x4, MMBLOCKS> {
+impl<T: SimdType, const MMBLOCKS: usize> Cacheable for SimdVecImpl<T, MMBLOCKS> {
     fn cache_id(&self) -> u128 {
         murmur3_x64_128(&self.chunks, 0u32)
     }
@@ -286,34 +299,296 @@ impl<const MMBLOCKS: usize> Cacheable for SimdVecImpl<f32x4, MMBLOCKS> {
 
 
 /*
-pub struct SimdVector {
-    vector: Box<dyn SimdVec>,
+
+`SimdVecImpl` fixes the dimension at compile time, which is fast but means a
+server can only hold vectors of one size.  `SimdVector` picks its dimension
+when it is built, from the number of elements it is collected from.  The
+chunks live in a `Vec`, which is aligned for `T`, with the last one padded
+with zeros.  Inner products and distances only ever see the elements
+themselves, and are computed by the backend `dispatch` picks for the CPU.
+
+*/
+
+#[derive(Debug, Clone)]
+pub struct SimdVector<T: SimdType> {
+    chunks: Vec<T>,
     dim: usize
 }
 
-pub struct SimdError(String);
-impl SimdVector {
-    pub fn new(dim: usize) -> Result<SimdVector, SimdError> {
-        let vector: Option<Box<dyn SimdVec>> = match dim {
-            0..=32       => Some(Box::new(SimdVecImpl::<f32x8, 4>::new())),
-            33..=128     => Some(Box::new(SimdVecImpl::<f32x8, 16>::new())),
-            129..=512    => Some(Box::new(SimdVecImpl::<f32x8, 64>::new())),
-            513..=768    => Some(Box::new(SimdVecImpl::<f32x8, 96>::new())),
-            769..=1024   => Some(Box::new(SimdVecImpl::<f32x8, 128>::new())),
-            1025..=2048  => Some(Box::new(SimdVecImpl::<f32x8, 256>::new())),
-            2049..=4096  => Some(Box::new(SimdVecImpl::<f32x8, 512>::new())),
-            _ => None 
+impl<T: SimdType> SimdVector<T> {
+    /// A vector of zeros with `dim` elements.
+    pub fn zeros(dim: usize) -> Self {
+        let blocks = dim.div_ceil(T::LANES);
+        SimdVector {
+            chunks: vec![T::default(); blocks],
+            dim
+        }
+    }
+
+    fn elements(&self) -> &[T::ElementType] {
+        // Every chunk is exactly LANES elements laid end to end
+        unsafe {
+            std::slice::from_raw_parts(self.chunks.as_ptr() as *const T::ElementType, self.dim)
+        }
+    }
+
+    fn clear_padding(&mut self) {
+        let len = self.chunks.len() * T::LANES;
+        let elements = unsafe {
+            std::slice::from_raw_parts_mut(self.chunks.as_mut_ptr() as *mut T::ElementType, len)
         };
+        for elt in elements[self.dim..].iter_mut() {
+            *elt = T::ElementType::default();
+        }
+    }
 
-        if let Some(item) = vector {
-            Ok(SimdVector{ vector: item, dim: dim })
+    fn map_chunks(&self, other: &Self, op: impl Fn(T, T) -> T) -> Self {
+        SimdVector {
+            chunks: zip_eq(self.chunks.iter(), other.chunks.iter()).map(|(a, b)| op(*a, *b)).collect(),
+            dim: self.dim
         }
-        else {
-            Err(SimdError("Maximum supported dimension of SIMD vector is 4096".to_string()))
+    }
+}
+
+impl<'a, T: SimdType> IntoIterator for &'a SimdVector<T> {
+    type Item = T::ElementType;
+    type IntoIter = std::iter::Copied<std::slice::Iter<'a, T::ElementType>>;
+    fn into_iter(self) -> <Self as IntoIterator>::IntoIter {
+        self.elements().iter().copied()
+    }
+}
+
+impl<T: SimdType> FromIterator<T::ElementType> for SimdVector<T> {
+    fn from_iter<I: IntoIterator<Item=T::ElementType>>(iter: I) -> Self {
+        let elements = iter.into_iter().collect::<Vec<T::ElementType>>();
+        let mut result = SimdVector::zeros(elements.len());
+        for (idx, chunk) in elements.chunks(T::LANES).enumerate() {
+            if chunk.len() == T::LANES {
+                result.chunks[idx] = T::pack(chunk);
+            }
+            else {
+                let mut padded = vec![T::ElementType::default(); T::LANES];
+                padded[..chunk.len()].copy_from_slice(chunk);
+                result.chunks[idx] = T::pack(&padded);
+            }
         }
+        result
     }
 }
-*/
+
+impl<T: SimdType> Default for SimdVector<T> {
+    fn default() -> Self {
+        SimdVector::zeros(0)
+    }
+}
+
+impl<T: SimdType> Add for SimdVector<T> {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        self.map_chunks(&other, |a, b| a + b)
+    }
+}
+
+impl<T: SimdType> Sub for SimdVector<T> {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        self.map_chunks(&other, |a, b| a - b)
+    }
+}
+
+impl<T: SimdType> Mul for SimdVector<T> {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        self.map_chunks(&other, |a, b| a * b)
+    }
+}
+
+impl<T: SimdType> Div for SimdVector<T> {
+    type Output = Self;
+    fn div(self, other: Self) -> Self {
+        // The padding would otherwise come out as 0 / 0
+        let mut result = self.map_chunks(&other, |a, b| a / b);
+        result.clear_padding();
+        result
+    }
+}
+
+impl<T: SimdType<ElementType=f32>> Mul<f32> for SimdVector<T> where
+    f32: Mul<T, Output=T>
+{
+    type Output = Self;
+    fn mul(self, c: T::ElementType) -> Self {
+        SimdVector {
+            chunks: self.chunks.iter().map(|chunk| c * *chunk).collect(),
+            dim: self.dim
+        }
+    }
+}
+
+impl<T: SimdType<ElementType=f32>> Div<f32> for SimdVector<T> where
+    Self: Mul<f32, Output=Self>
+{
+    type Output = Self;
+    fn div(self, c: T::ElementType) -> Self {
+        self * (1f32 / c)
+    }
+}
+
+impl<T: SimdType<ElementType=f32>> VectorArithmetic for SimdVector<T> where
+    f32: Mul<T, Output=T>
+{
+    type DType = T::ElementType;
+}
+
+impl<T: SimdType<ElementType=f32>> Vector for SimdVector<T> where
+    f32: Mul<T, Output=T>,
+    f32: AddAssign<T>
+{
+    type DType = T::ElementType;
+
+    fn dot(&self, other: &Self) -> <Self as Vector>::DType {
+        dispatch::dot(self.elements(), other.elements())
+    }
+
+    fn distance(&self, other: &Self) -> <Self as Vector>::DType {
+        dispatch::distance(self.elements(), other.elements())
+    }
+
+    fn dimension(&self) -> usize {
+        self.dim
+    }
+
+    fn as_slice(&self) -> &[<Self as Vector>::DType] {
+        self.elements()
+    }
+}
+
+impl<T: SimdType> PartialEq for SimdVector<T>
+where T::ElementType: PartialEq
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.dim == other.dim && self.elements() == other.elements()
+    }
+}
+
+impl<T: SimdType> Eq for SimdVector<T>
+where T::ElementType: PartialEq
+{}
+
+impl<T: SimdType> Cacheable for SimdVector<T> {
+    fn cache_id(&self) -> u128 {
+        murmur3_x64_128(&self.chunks, self.dim as u32)
+    }
+}
+
+#[cfg(test)]
+mod simd_vector_test {
+    use super::*;
+    use crate::simd::f32x4;
+
+    type V = SimdVector<f32x4>;
+
+    #[test]
+    fn test_simd_vector_dimension() {
+        let x = (0..10).map(|i| i as f32).collect::<V>();
+        assert_eq!(x.dimension(), 10);
+        assert_eq!((&x).into_iter().collect::<Vec<f32>>(), (0..10).map(|i| i as f32).collect::<Vec<f32>>());
+        assert_eq!(V::default().dimension(), 0);
+    }
+
+    #[test]
+    fn test_simd_vector_arithmetic() {
+        let x = (0..37).map(|i| i as f32).collect::<V>();
+        let y = (0..37).map(|i| 1f32 - i as f32).collect::<V>();
+        let naive_dot = (0..37).map(|i| i as f32 * (1f32 - i as f32)).sum::<f32>();
+        let naive_distance = (0..37).map(|i| (2f32 * i as f32 - 1f32).powi(2)).sum::<f32>().sqrt();
+
+        assert_eq!(x.dot(&y), naive_dot);
+        assert!((x.distance(&y) - naive_distance).abs() < 1e-3);
+        assert_eq!(x.clone() + y.clone(), vec![1f32; 37].into_iter().collect::<V>());
+        assert_eq!((x.clone() * 2f32).dot(&y), 2f32 * naive_dot);
+
+        let twos = vec![2f32; 37].into_iter().collect::<V>();
+        assert_eq!((x.clone() / twos).dot(&y), naive_dot / 2f32);
+    }
+
+    #[test]
+    fn test_simd_vector_cache_id() {
+        let x = vec![1f32; 5].into_iter().collect::<V>();
+        let padded = vec![1f32, 1f32, 1f32, 1f32, 1f32, 0f32].into_iter().collect::<V>();
+        assert_eq!(x.cache_id(), vec![1f32; 5].into_iter().collect::<V>().cache_id());
+        assert_ne!(x.cache_id(), padded.cache_id());
+    }
+}
+#[cfg(test)]
+mod simd_vec_property_test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use crate::simd::{Backend, f32x4, f32x8};
+
+    const TRIALS: usize = 20;
+
+    fn scalar_dot(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+    }
+
+    fn scalar_distance(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt()
+    }
+
+    fn close(actual: f32, expected: f32) -> bool {
+        (actual - expected).abs() <= 1e-3 * expected.abs().max(1f32)
+    }
+
+    // Compares a vector type against plain loops over random vectors of up to
+    // `capacity` elements, leaving the rest to be padded with zeros
+    fn check<V>(rng: &mut StdRng, capacity: usize)
+    where
+        V: Vector<DType=f32>,
+        for <'a> &'a V: IntoIterator<Item=f32>
+    {
+        for _ in 0..TRIALS {
+            let len = rng.gen_range(0..=capacity);
+            let a = (0..len).map(|_| rng.gen_range(-1f32..1f32)).collect::<Vec<f32>>();
+            let b = (0..len).map(|_| rng.gen_range(-1f32..1f32)).collect::<Vec<f32>>();
+            let (x, y) = (a.iter().copied().collect::<V>(), b.iter().copied().collect::<V>());
+
+            assert!(close(x.dot(&y), scalar_dot(&a, &b)), "dot of {} elements out of {}", len, capacity);
+            assert!(close(x.distance(&y), scalar_distance(&a, &b)), "distance of {} elements out of {}", len, capacity);
+
+            let elements = (&x).into_iter().collect::<Vec<f32>>();
+            assert_eq!(&elements[..len], &a[..]);
+            assert!(elements[len..].iter().all(|elt| *elt == 0f32));
+        }
+    }
+
+    macro_rules! check_blocks {
+        ($rng:expr, $lanes:ty, $($blocks:literal),*) => {
+            $( check::<SimdVecImpl<$lanes, $blocks>>($rng, $blocks * <$lanes as SimdType>::LANES); )*
+        };
+    }
+
+    #[test]
+    fn test_simd_vec_impl_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(46);
+        check_blocks!(&mut rng, f32x4, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 17, 31, 192);
+
+        // On x86, f32x8 is an AVX register
+        if cfg!(not(any(target_arch = "x86", target_arch = "x86_64"))) || Backend::available().contains(&Backend::Avx2) {
+            check_blocks!(&mut rng, f32x8, 1, 2, 3, 4, 5, 6, 7, 9, 96);
+        }
+    }
+
+    #[test]
+    fn test_simd_vector_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(46);
+        for capacity in [1usize, 4, 7, 16, 33, 100, 768].iter() {
+            check::<SimdVector<f32x4>>(&mut rng, *capacity);
+        }
+    }
+}
+
 /*
 #[cfg(test)]
 mod simd_vector_impl_tests {
@@ -361,6 +636,4 @@ mod simd_vector_impl_tests {
 */
 
 
-//impl<T: SimdType, const MMBLOCKS: usize> SimdVec for SimdVecImpl<T, MMBLOCKS> {}
-
 
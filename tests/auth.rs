@@ -0,0 +1,119 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use bytes::Bytes;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use rush::client::{Client, ClientConfig};
+use rush::net::{Authenticator, Connection, Frame, HttpListener, Listener, Role, ServerError, ServerStats};
+use common::{constant, Database, DIM};
+
+// Starts RESP and HTTP listeners accepting the tokens "writer" and "reader".
+async fn start_servers() -> (SocketAddr, SocketAddr) {
+    let (datasets, _) = common::datasets::<Database>();
+    let connection_limiter = Arc::new(Semaphore::new(64));
+    let stats = Arc::new(ServerStats::new());
+    let authenticator = Arc::new(Authenticator::new(vec![
+        ("writer".to_string(), Role::ReadWrite),
+        ("reader".to_string(), Role::ReadOnly),
+    ]));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let resp_addr = listener.local_addr().unwrap();
+    let mut server = Listener {
+        connection_limiter: connection_limiter.clone(),
+        stats: stats.clone(),
+        authenticator: authenticator.clone(),
+        ..common::listener(listener, datasets.clone())
+    };
+    tokio::spawn(async move { server.run().await });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_addr = listener.local_addr().unwrap();
+    let mut http_server = HttpListener { connection_limiter, stats, authenticator, ..common::http_listener(listener, datasets) };
+    tokio::spawn(async move { http_server.run().await });
+
+    (resp_addr, http_addr)
+}
+
+async fn client(addr: SocketAddr, token: Option<&str>) -> rush::Result<Client> {
+    let mut config = ClientConfig::new(&addr.to_string());
+    config.token = token.map(str::to_string);
+    Client::connect_with_config(config).await
+}
+
+async fn call(connection: &mut Connection, args: &[&str]) -> Frame {
+    let args = args.iter().map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes()))).collect();
+    connection.write_frame(&Frame::Array(args)).await.unwrap();
+    connection.read_frame().await.unwrap().unwrap()
+}
+
+// Sends a request with an optional bearer token and returns the status code.
+async fn http_status(addr: SocketAddr, method: &str, path: &str, token: Option<&str>, body: &str) -> u16 {
+    let auth = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method, path, auth, body.len(), body,
+    );
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    response.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+async fn tokens_grant_roles() {
+    let (addr, _) = start_servers().await;
+
+    let mut anonymous = client(addr, None).await.unwrap();
+    let err = anonymous.get("test", &constant(1.0)).await.unwrap_err();
+    assert!(err.to_string().contains("authentication required"));
+
+    assert!(client(addr, Some("guess")).await.is_err());
+
+    let mut writer = client(addr, Some("writer")).await.unwrap();
+    writer.put("test", &constant(1.0)).await.unwrap();
+
+    let mut reader = client(addr, Some("reader")).await.unwrap();
+    assert_eq!(reader.get("test", &constant(1.0)).await.unwrap(), Some(constant(1.0)));
+    let err = reader.put("test", &constant(2.0)).await.unwrap_err();
+    assert!(err.to_string().contains("read-only"));
+    assert_eq!(err.downcast_ref::<ServerError>().map(ServerError::code), Some("NOPERM"));
+}
+
+#[tokio::test]
+async fn redis_clients_authenticate() {
+    let (addr, _) = start_servers().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    assert!(matches!(call(&mut connection, &["PING"]).await, Frame::Error(msg) if msg.starts_with("NOAUTH")));
+    assert!(matches!(call(&mut connection, &["HELLO", "3"]).await, Frame::Error(msg) if msg.starts_with("NOAUTH")));
+    assert!(matches!(call(&mut connection, &["AUTH", "guess"]).await, Frame::Error(msg) if msg.starts_with("WRONGPASS")));
+
+    assert!(matches!(call(&mut connection, &["HELLO", "3", "AUTH", "default", "reader"]).await, Frame::Map(_)));
+    assert_eq!(call(&mut connection, &["PING"]).await, "PONG");
+    let put = call(&mut connection, &["RUSH.PUT", "test", "VALUES", "1", "1"]).await;
+    assert!(matches!(put, Frame::Error(msg) if msg.starts_with("NOPERM")));
+
+    assert_eq!(call(&mut connection, &["AUTH", "default", "writer"]).await, "OK");
+    let mut put = vec!["RUSH.PUT", "test", "VALUES", "16"];
+    put.extend(vec!["1"; DIM]);
+    assert_eq!(call(&mut connection, &put).await, "OK");
+}
+
+#[tokio::test]
+async fn http_requires_bearer_token() {
+    let (_, http_addr) = start_servers().await;
+    let body = json!({ "vector": constant(1.0) }).to_string();
+
+    assert_eq!(http_status(http_addr, "GET", "/stats", None, "").await, 401);
+    assert_eq!(http_status(http_addr, "GET", "/stats", Some("guess"), "").await, 401);
+    assert_eq!(http_status(http_addr, "GET", "/stats", Some("reader"), "").await, 200);
+    assert_eq!(http_status(http_addr, "POST", "/datasets/test/vectors", Some("reader"), &body).await, 403);
+    assert_eq!(http_status(http_addr, "POST", "/datasets/test/vectors", Some("writer"), &body).await, 200);
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use rush::net::{Connection, Dataset, Frame, HttpListener, Listener, ServerStats};
+use common::{blob, command, constant, Database};
+
+async fn start_server() -> (SocketAddr, Arc<Dataset<Database>>, Arc<ServerStats>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (datasets, dataset) = common::datasets();
+    let stats = Arc::new(ServerStats::new());
+
+    let mut server = Listener { stats: stats.clone(), ..common::listener(listener, datasets) };
+    tokio::spawn(async move { server.run().await });
+    (addr, dataset, stats)
+}
+
+async fn start_http_server() -> (SocketAddr, Arc<Dataset<Database>>, Arc<ServerStats>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (datasets, dataset) = common::datasets();
+    let stats = Arc::new(ServerStats::new());
+
+    let mut server = HttpListener { stats: stats.clone(), ..common::http_listener(listener, datasets) };
+    tokio::spawn(async move { server.run().await });
+    (addr, dataset, stats)
+}
+
+fn commands_cancelled(stats: &ServerStats) -> u64 {
+    stats.snapshot().into_iter().find(|(name, _)| *name == "commands_cancelled").map(|(_, value)| value).unwrap()
+}
+
+// Waits a while for a cancelled command to notice, returning how many have.
+async fn wait_for_cancelled(stats: &ServerStats) -> u64 {
+    let mut cancelled = 0;
+    for _ in 0..100 {
+        cancelled = commands_cancelled(stats);
+        if cancelled > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    cancelled
+}
+
+#[tokio::test]
+async fn disconnect_cancels_in_flight_query() {
+    let (addr, dataset, stats) = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Integer(0)).await.unwrap();
+    connection.write_frame(&command("PUT", 1.0)).await.unwrap();
+    assert_eq!(connection.read_frame().await.unwrap().unwrap(), "OK");
+
+    // Keep the query waiting on the lock until its client has gone
+    let guard = dataset.database().write().await;
+    let topk = match command("TOPK", 1.0) {
+        Frame::Array(mut frames) => {
+            frames.push(Frame::Integer(1));
+            Frame::Array(frames)
+        }
+        _ => unreachable!(),
+    };
+    connection.write_frame(&topk).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(connection);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(guard);
+    assert_eq!(wait_for_cancelled(&stats).await, 1);
+
+    // Other clients are unaffected
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Integer(2)).await.unwrap();
+    connection.write_frame(&command("GET", 1.1)).await.unwrap();
+    assert!(matches!(connection.read_frame().await.unwrap(), Some(Frame::Bulk(data)) if data == blob(1.0)));
+}
+
+#[tokio::test]
+async fn redis_disconnect_cancels_in_flight_query() {
+    let (addr, dataset, stats) = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    let guard = dataset.database().write().await;
+    let topk = ["RUSH.TOPK", "test", "FP32"].
+        iter().
+        map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes()))).
+        chain([Frame::Bulk(blob(1.0)), Frame::Bulk(Bytes::from_static(b"1"))]).
+        collect();
+    connection.write_frame(&Frame::Array(topk)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(connection);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(guard);
+    assert_eq!(wait_for_cancelled(&stats).await, 1);
+}
+
+#[tokio::test]
+async fn http_disconnect_cancels_in_flight_query() {
+    let (addr, dataset, stats) = start_http_server().await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    let guard = dataset.database().write().await;
+    let body = json!({ "vector": constant(1.0), "k": 1 }).to_string();
+    let request = format!("POST /datasets/test/query HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(stream);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(guard);
+    assert_eq!(wait_for_cancelled(&stats).await, 1);
+}
@@ -0,0 +1,213 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use rush::client::{Client, ClientConfig, Neighbour, Pool, Request, Response};
+use rush::lsh::LocalitySensitiveHashDatabase;
+use rush::lsh::{HashFamily, Metric};
+use rush::net::{Database as _, Dataset, DatasetConfig, Listener, ServerError};
+use rush::simd::{SimdVector, f32x4};
+use common::constant;
+
+type Database = LocalitySensitiveHashDatabase<SimdVector<f32x4>>;
+
+// Starts a server on an ephemeral port in the background of the test runtime.
+async fn start_server(idle_timeout: Option<Duration>) -> (SocketAddr, Arc<Dataset<Database>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (datasets, dataset) = common::datasets();
+
+    let mut server = Listener { idle_timeout, ..common::listener(listener, datasets) };
+    tokio::spawn(async move { server.run().await });
+    (addr, dataset)
+}
+
+#[tokio::test]
+async fn put_then_get_returns_nearest_neighbour() {
+    let (addr, _) = start_server(None).await;
+    let mut client = Client::connect(&addr.to_string()).await.unwrap();
+
+    assert_eq!(client.get("test", &constant(1.0)).await.unwrap(), None);
+
+    client.put("test", &constant(1.0)).await.unwrap();
+    client.put("test", &constant(-1.0)).await.unwrap();
+
+    let mut query = constant(1.0);
+    query[0] = 0.9;
+    assert_eq!(client.get("test", &query).await.unwrap(), Some(constant(1.0)));
+}
+
+#[tokio::test]
+async fn query_topk_orders_by_distance() {
+    let (addr, _) = start_server(None).await;
+    let mut client = Client::connect(&addr.to_string()).await.unwrap();
+
+    for value in [1.0, 2.0, 3.0, -1.0].iter() {
+        client.put("test", &constant(*value)).await.unwrap();
+    }
+
+    let neighbours = client.query_topk("test", &constant(1.2), 2).await.unwrap();
+    let vectors = neighbours.iter().map(|n| n.vector.clone()).collect::<Vec<Vec<f32>>>();
+    assert_eq!(vectors, vec![constant(1.0), constant(2.0)]);
+    assert!((neighbours[0].distance - 0.8).abs() < 1e-5);
+    assert!(neighbours[0].distance < neighbours[1].distance);
+}
+
+#[tokio::test]
+async fn delete_removes_vector() {
+    let (addr, dataset) = start_server(None).await;
+    let mut client = Client::connect(&addr.to_string()).await.unwrap();
+
+    client.put("test", &constant(1.0)).await.unwrap();
+    assert!(client.delete("test", &constant(1.0)).await.unwrap());
+    assert!(!client.delete("test", &constant(1.0)).await.unwrap());
+
+    assert_eq!(dataset.database().read().await.len(), 0);
+    assert_eq!(client.get("test", &constant(1.0)).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn pipelined_requests_answer_in_order() {
+    let (addr, _) = start_server(None).await;
+    let mut client = Client::connect(&addr.to_string()).await.unwrap();
+
+    let responses = client.pipeline(vec![
+        Request::put("test", &constant(1.0)),
+        Request::put("test", &constant(2.0)),
+        Request::get("test", &constant(2.1)),
+        Request::delete("test", &constant(1.0)),
+        Request::query_topk("test", &constant(1.0), 5),
+    ]).await.unwrap();
+
+    assert_eq!(responses, vec![
+        Response::Ok,
+        Response::Ok,
+        Response::Vector(Some(constant(2.0))),
+        Response::Deleted(true),
+        Response::Neighbours(vec![Neighbour { vector: constant(2.0), distance: 4.0 }]),
+    ]);
+}
+
+#[tokio::test]
+async fn bulk_requests_answer_in_order() {
+    let (addr, dataset) = start_server(None).await;
+    let mut client = Client::connect(&addr.to_string()).await.unwrap();
+
+    let requests = (1..=20).
+        map(|i| Request::put("test", &constant(i as f32))).
+        collect::<Vec<Request>>();
+    let responses = client.bulk(requests).await.unwrap();
+    assert_eq!(responses, vec![Response::Ok; 20]);
+    assert_eq!(dataset.database().read().await.len(), 20);
+
+    let responses = client.bulk(vec![
+        Request::get("test", &constant(3.1)),
+        Request::delete("test", &constant(100.0)),
+    ]).await.unwrap();
+    assert_eq!(responses, vec![Response::Vector(Some(constant(3.0))), Response::Deleted(false)]);
+}
+
+#[tokio::test]
+async fn stats_are_reported() {
+    let (addr, _) = start_server(None).await;
+    let mut client = Client::connect(&addr.to_string()).await.unwrap();
+
+    let stats = client.stats().await.unwrap();
+    assert!(stats.contains(&("connections_accepted".to_string(), 1)));
+    assert!(stats.contains(&("accept_failures".to_string(), 0)));
+}
+
+#[tokio::test]
+async fn datasets_are_created_and_dropped() {
+    let (addr, _) = start_server(None).await;
+    let mut client = Client::connect(&addr.to_string()).await.unwrap();
+
+    let err = client.create_dataset("small", DatasetConfig::new(0)).await.err().unwrap();
+    assert_eq!(err.downcast_ref::<ServerError>().map(ServerError::code), Some("INVALID"));
+    // Only protocol errors cost the connection
+    assert!(client.is_connected());
+
+    client.create_dataset("small", DatasetConfig::new(3).with_tables(4).with_metric(Metric::Cosine).with_hash(HashFamily::Hadamard)).await.unwrap();
+    assert_eq!(client.list_datasets().await.unwrap(), vec!["small", "test"]);
+
+    client.put("small", &[1.0, 0.0, 0.0]).await.unwrap();
+    let neighbours = client.query_topk("small", &[2.0, 0.0, 0.0], 1).await.unwrap();
+    assert_eq!(neighbours[0].vector, vec![1.0, 0.0, 0.0]);
+    assert!(neighbours[0].distance.abs() < 1e-6);
+
+    let err = client.put("small", &constant(1.0)).await.err().unwrap();
+    assert_eq!(err.downcast_ref::<ServerError>().map(ServerError::code), Some("INVALID"));
+    assert!(client.is_connected());
+
+    client.drop_dataset("small").await.unwrap();
+    let err = client.get("small", &[1.0, 0.0, 0.0]).await.err().unwrap();
+    assert_eq!(err.downcast_ref::<ServerError>().map(ServerError::code), Some("NOTFOUND"));
+    assert_eq!(client.list_datasets().await.unwrap(), vec!["test"]);
+}
+
+#[tokio::test]
+async fn requests_time_out() {
+    let (addr, dataset) = start_server(None).await;
+    let mut config = ClientConfig::new(&addr.to_string());
+    config.timeout = Duration::from_millis(100);
+    let mut client = Client::connect_with_config(config).await.unwrap();
+
+    // Queries can't make progress while a writer holds the lock
+    let guard = dataset.database().write().await;
+    assert!(client.get("test", &constant(1.0)).await.is_err());
+    assert!(!client.is_connected());
+    drop(guard);
+
+    // The next request goes out on a fresh connection
+    client.put("test", &constant(1.0)).await.unwrap();
+    assert!(client.is_connected());
+}
+
+#[tokio::test]
+async fn client_reconnects_after_server_closes_connection() {
+    let (addr, _) = start_server(Some(Duration::from_millis(50))).await;
+    let mut client = Client::connect(&addr.to_string()).await.unwrap();
+    client.put("test", &constant(1.0)).await.unwrap();
+
+    // Let the server drop us for being idle
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(client.get("test", &constant(1.0)).await.unwrap(), Some(constant(1.0)));
+}
+
+#[tokio::test]
+async fn client_does_not_resend_writes() {
+    let (addr, dataset) = start_server(Some(Duration::from_millis(50))).await;
+    let mut client = Client::connect(&addr.to_string()).await.unwrap();
+    client.put("test", &constant(1.0)).await.unwrap();
+
+    // The write fails along with the connection rather than going out again,
+    // and the caller decides whether to repeat it
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(client.put("test", &constant(2.0)).await.is_err());
+    assert!(client.pipeline(vec![Request::get("test", &constant(1.0)), Request::delete("test", &constant(1.0))]).await.is_ok());
+    assert_eq!(dataset.database().read().await.len(), 0);
+}
+
+#[tokio::test]
+async fn pool_reuses_connections() {
+    let (addr, _) = start_server(None).await;
+    let pool = Pool::new(ClientConfig::new(&addr.to_string()), 2);
+
+    {
+        let mut first = pool.get().await.unwrap();
+        let mut second = pool.get().await.unwrap();
+        first.put("test", &constant(1.0)).await.unwrap();
+        second.put("test", &constant(2.0)).await.unwrap();
+    }
+    assert_eq!(pool.idle(), 2);
+
+    let mut client = pool.get().await.unwrap();
+    assert_eq!(pool.idle(), 1);
+    assert_eq!(client.get("test", &constant(2.0)).await.unwrap(), Some(constant(2.0)));
+
+    let stats = client.stats().await.unwrap();
+    assert!(stats.contains(&("connections_accepted".to_string(), 2)));
+}
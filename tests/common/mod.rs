@@ -0,0 +1,84 @@
+// Each test binary only uses some of these
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use bytes::Bytes;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, Semaphore};
+use rush::lsh::LocalitySensitiveHashDatabase;
+use rush::lsh::vector::Vector;
+use rush::net::{Authenticator, ClientLimiter, Database as DatabaseTrait, Dataset, DatasetConfig, Datasets, Frame, FrameLimits, HttpListener, Listener, ServerStats};
+use rush::simd::{SimdVecImpl, f32x4};
+
+pub type Database = LocalitySensitiveHashDatabase<SimdVecImpl<f32x4, 4>>;
+
+pub const DIM: usize = 16;
+
+/// A set of datasets holding one empty dataset "test" of `DIM` dimensional
+/// vectors.
+pub fn datasets<DB>() -> (Arc<Datasets<DB>>, Arc<Dataset<DB>>)
+where
+    DB: DatabaseTrait,
+    DB::Item: Vector<DType=f32>,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    let datasets = Arc::new(Datasets::<DB>::new());
+    let dataset = datasets.create("test", DatasetConfig::new(DIM)).unwrap();
+    (datasets, dataset)
+}
+
+/// A RESP listener on `listener` with generous limits and everything optional
+/// turned off.  Tests set the fields they care about with struct update syntax.
+pub fn listener<DB, L>(listener: L, datasets: Arc<Datasets<DB>>) -> Listener<DB, L>
+where
+    DB: DatabaseTrait + Sync + Send + 'static,
+    DB::Item: Vector<DType=f32> + Sync + Send,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    let (shutdown_signal, _) = broadcast::channel(1);
+    Listener {
+        listener,
+        datasets,
+        connection_limiter: Arc::new(Semaphore::new(64)),
+        client_limiter: Arc::new(ClientLimiter::new(64)),
+        idle_timeout: None,
+        command_timeout: None,
+        frame_limits: FrameLimits::default(),
+        stats: Arc::new(ServerStats::new()),
+        authenticator: Arc::new(Authenticator::disabled()),
+        tls_acceptor: None,
+        shutdown_signal,
+    }
+}
+
+/// An HTTP listener on `listener`, set up like `listener`.
+pub fn http_listener<DB>(listener: TcpListener, datasets: Arc<Datasets<DB>>) -> HttpListener<DB>
+where
+    DB: DatabaseTrait + Sync + Send + 'static,
+    DB::Item: Vector<DType=f32> + Sync + Send,
+    for <'a> &'a DB::Item: IntoIterator<Item=<DB::Item as Vector>::DType>
+{
+    HttpListener {
+        listener,
+        datasets,
+        connection_limiter: Arc::new(Semaphore::new(64)),
+        client_limiter: Arc::new(ClientLimiter::new(64)),
+        idle_timeout: None,
+        command_timeout: None,
+        stats: Arc::new(ServerStats::new()),
+        authenticator: Arc::new(Authenticator::disabled()),
+    }
+}
+
+pub fn constant(value: f32) -> Vec<f32> {
+    vec![value; DIM]
+}
+
+pub fn blob(value: f32) -> Bytes {
+    [value; DIM].iter().flat_map(|elt| elt.to_le_bytes()).collect()
+}
+
+/// A command taking a vector of `value`s in the dataset "test".
+pub fn command(name: &str, value: f32) -> Frame {
+    Frame::Array(vec![Frame::Simple(name.into()), Frame::Simple("test".into()), Frame::Bulk(blob(value))])
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::time;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::{Channel, Server};
+use tonic::Code;
+use rush::net::{ClientLimiter, Database as _, Dataset, GrpcService, ServerStats};
+use rush::net::grpc::*;
+use common::{constant, Database};
+
+// Starts a gRPC server on an ephemeral port and connects a client to it.
+async fn start_server() -> (RushClient<Channel>, Arc<Dataset<Database>>) {
+    start_server_with_timeout(None).await
+}
+
+async fn start_server_with_timeout(command_timeout: Option<Duration>) -> (RushClient<Channel>, Arc<Dataset<Database>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let (datasets, dataset) = common::datasets();
+
+    let service = RushServer::new(GrpcService::new(datasets, Arc::new(ServerStats::new())).with_command_timeout(command_timeout));
+    tokio::spawn(Server::builder().add_service(service).serve_with_incoming(TcpListenerStream::new(listener)));
+
+    let client = RushClient::connect(format!("http://{}", addr)).await.unwrap();
+    (client, dataset)
+}
+
+fn insert(value: f32) -> InsertRequest {
+    InsertRequest { dataset: "test".into(), vector: constant(value) }
+}
+
+fn query(value: f32, k: u32) -> QueryRequest {
+    QueryRequest { dataset: "test".into(), vector: constant(value), k }
+}
+
+#[tokio::test]
+async fn insert_query_and_delete() {
+    let (mut client, dataset) = start_server().await;
+
+    for value in [1.0, 2.0, -1.0].iter() {
+        client.insert(insert(*value)).await.unwrap();
+    }
+    assert_eq!(dataset.database().read().await.len(), 3);
+
+    let response = client.query(query(1.2, 2)).await.unwrap().into_inner();
+    let vectors = response.neighbours.iter().map(|n| n.vector.clone()).collect::<Vec<Vec<f32>>>();
+    assert_eq!(vectors, vec![constant(1.0), constant(2.0)]);
+    assert!((response.neighbours[0].distance - 0.8).abs() < 1e-5);
+
+    let request = DeleteRequest { dataset: "test".into(), vector: constant(1.0) };
+    assert!(client.delete(request.clone()).await.unwrap().into_inner().deleted);
+    assert!(!client.delete(request).await.unwrap().into_inner().deleted);
+}
+
+#[tokio::test]
+async fn streaming_insert_and_batch_query() {
+    let (mut client, _) = start_server().await;
+
+    let requests = (1..=10).map(|i| insert(i as f32)).collect::<Vec<InsertRequest>>();
+    let response = client.insert_stream(tokio_stream::iter(requests)).await.unwrap().into_inner();
+    assert_eq!(response.inserted, 10);
+
+    let request = BatchQueryRequest { queries: vec![query(3.1, 1), query(8.9, 1)] };
+    let response = client.batch_query(request).await.unwrap().into_inner();
+    let nearest = response.results.iter().map(|r| r.neighbours[0].vector.clone()).collect::<Vec<Vec<f32>>>();
+    assert_eq!(nearest, vec![constant(3.0), constant(9.0)]);
+}
+
+#[tokio::test]
+async fn stats_are_reported() {
+    let (mut client, _) = start_server().await;
+
+    let stats = client.stats(StatsRequest {}).await.unwrap().into_inner();
+    assert!(stats.counters.iter().any(|c| c.name == "accept_failures" && c.value == 0));
+}
+
+#[tokio::test]
+async fn server_default_deadline() {
+    let (mut client, dataset) = start_server_with_timeout(Some(Duration::from_millis(20))).await;
+
+    let guard = dataset.database().write().await;
+    let status = client.query(query(1.0, 1)).await.unwrap_err();
+    assert_eq!(status.code(), Code::DeadlineExceeded);
+    drop(guard);
+
+    assert!(client.query(query(1.0, 1)).await.is_ok());
+}
+
+#[tokio::test]
+async fn connections_share_the_limits() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (datasets, _) = common::datasets::<Database>();
+    let stats = Arc::new(ServerStats::new());
+    let connection_limiter = Arc::new(Semaphore::new(2));
+    let client_limiter = Arc::new(ClientLimiter::new(1));
+
+    let service = RushServer::new(GrpcService::new(datasets, stats.clone()));
+    let incoming = incoming(listener, connection_limiter.clone(), client_limiter.clone(), stats);
+    tokio::spawn(Server::builder().add_service(service).serve_with_incoming(incoming));
+
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let mut client = RushClient::connect(format!("http://{}", addr)).await.unwrap();
+    client.stats(StatsRequest {}).await.unwrap();
+    assert_eq!(client_limiter.connections(&localhost), 1);
+
+    // A second connection from the same client is over its limit
+    let mut refused = RushClient::connect(format!("http://{}", addr)).await.unwrap();
+    assert!(refused.stats(StatsRequest {}).await.is_err());
+
+    // Both permits go back once the client hangs up.  One connection permit
+    // stays with the accept loop while it waits for the next client.
+    drop(client);
+    drop(refused);
+    time::timeout(Duration::from_secs(5), async {
+        while connection_limiter.available_permits() < 1 || client_limiter.connections(&localhost) > 0 {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await.expect("permits were not released");
+}
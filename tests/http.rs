@@ -0,0 +1,141 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time;
+use rush::client::Client;
+use rush::net::{ClientLimiter, HttpListener, Listener, ServerStats};
+use common::{constant, Database};
+
+// Starts RESP and HTTP listeners sharing one set of datasets, returning their addresses.
+async fn start_servers() -> (SocketAddr, SocketAddr) {
+    start_servers_with_client_limit(64).await
+}
+
+async fn start_servers_with_client_limit(max_per_client: usize) -> (SocketAddr, SocketAddr) {
+    let (datasets, _) = common::datasets::<Database>();
+    let connection_limiter = Arc::new(Semaphore::new(64));
+    let client_limiter = Arc::new(ClientLimiter::new(max_per_client));
+    let stats = Arc::new(ServerStats::new());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let resp_addr = listener.local_addr().unwrap();
+    let mut server = Listener {
+        connection_limiter: connection_limiter.clone(),
+        client_limiter: client_limiter.clone(),
+        stats: stats.clone(),
+        ..common::listener(listener, datasets.clone())
+    };
+    tokio::spawn(async move { server.run().await });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_addr = listener.local_addr().unwrap();
+    let mut http_server = HttpListener { connection_limiter, client_limiter, stats, ..common::http_listener(listener, datasets) };
+    tokio::spawn(async move { http_server.run().await });
+
+    (resp_addr, http_addr)
+}
+
+// Sends a single request and returns the status code and decoded JSON body.
+async fn request(addr: SocketAddr, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method, path, body.len(), body,
+    );
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[tokio::test]
+async fn http_and_resp_share_data() {
+    let (resp_addr, http_addr) = start_servers().await;
+    let mut client = Client::connect(&resp_addr.to_string()).await.unwrap();
+
+    let (status, body) = request(http_addr, "POST", "/datasets/test/vectors", Some(json!({ "vector": constant(1.0) }))).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "status": "ok" }));
+    assert_eq!(client.get("test", &constant(1.1)).await.unwrap(), Some(constant(1.0)));
+
+    client.put("test", &constant(2.0)).await.unwrap();
+    let (status, body) = request(http_addr, "POST", "/datasets/test/query", Some(json!({ "vector": constant(1.9), "k": 2 }))).await;
+    assert_eq!(status, 200);
+    let vectors = body["neighbours"].
+        as_array().
+        unwrap().
+        iter().
+        map(|n| n["vector"].clone()).
+        collect::<Vec<Value>>();
+    assert_eq!(vectors, vec![json!(constant(2.0)), json!(constant(1.0))]);
+
+    let (status, body) = request(http_addr, "DELETE", "/datasets/test/vectors", Some(json!({ "vector": constant(2.0) }))).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "deleted": true }));
+    assert_eq!(client.get("test", &constant(2.0)).await.unwrap(), Some(constant(1.0)));
+}
+
+#[tokio::test]
+async fn http_reports_errors_as_json() {
+    let (_, http_addr) = start_servers().await;
+
+    let (status, body) = request(http_addr, "POST", "/datasets/test/vectors", Some(json!({ "vector": "nope" }))).await;
+    assert_eq!(status, 400);
+    assert!(body["error"].as_str().unwrap().starts_with("invalid request body"));
+
+    let (status, _) = request(http_addr, "GET", "/datasets/test/query", None).await;
+    assert_eq!(status, 405);
+
+    let (status, _) = request(http_addr, "GET", "/nowhere", None).await;
+    assert_eq!(status, 404);
+
+    let (status, body) = request(http_addr, "GET", "/stats", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["accept_failures"], json!(0));
+}
+
+#[tokio::test]
+async fn http_and_resp_share_client_limit() {
+    let (resp_addr, http_addr) = start_servers_with_client_limit(1).await;
+    let mut client = Client::connect(&resp_addr.to_string()).await.unwrap();
+    client.put("test", &constant(1.0)).await.unwrap();
+
+    // The RESP connection uses up the client's only slot
+    let (status, body) = request(http_addr, "GET", "/stats", None).await;
+    assert_eq!(status, 503);
+    assert_eq!(body["code"], "CAPACITY");
+
+    // The slot comes back once the server notices the client has gone
+    drop(client);
+    time::timeout(Duration::from_secs(5), async {
+        while request(http_addr, "GET", "/stats", None).await.0 != 200 {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await.expect("client slot was not released");
+}
+
+#[tokio::test]
+async fn endless_header_lines_are_refused() {
+    let (_, http_addr) = start_servers().await;
+
+    // A request line that never ends, as long as all the headers may be
+    let line = format!("GET /{}", "a".repeat(16_384 - 5));
+    let mut socket = TcpStream::connect(http_addr).await.unwrap();
+    socket.write_all(line.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+    assert!(response.contains("request headers too large"));
+}
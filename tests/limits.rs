@@ -0,0 +1,87 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time;
+use rush::net::{ClientLimiter, Connection, Frame, Listener};
+use common::{blob, command, Database};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+// Starts a listener allowing `max_connections` in all and `max_per_client`
+// from each address, returning the limits so tests can watch them.
+async fn start_server(max_connections: usize, max_per_client: usize, idle_timeout: Option<Duration>)
+    -> (SocketAddr, Arc<Semaphore>, Arc<ClientLimiter>)
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (datasets, _) = common::datasets::<Database>();
+    let connection_limiter = Arc::new(Semaphore::new(max_connections));
+    let client_limiter = Arc::new(ClientLimiter::new(max_per_client));
+
+    let mut server = Listener {
+        connection_limiter: connection_limiter.clone(),
+        client_limiter: client_limiter.clone(),
+        idle_timeout,
+        ..common::listener(listener, datasets)
+    };
+    tokio::spawn(async move { server.run().await });
+    (addr, connection_limiter, client_limiter)
+}
+
+async fn connect(addr: SocketAddr) -> Connection {
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Integer(0)).await.unwrap();
+    connection
+}
+
+// Waits for the server to notice a connection has gone and give back its
+// permits.  The listener keeps one connection permit in hand while it waits
+// for the next client.
+async fn released(connection_limiter: &Semaphore, client_limiter: &ClientLimiter, max_connections: usize) {
+    time::timeout(Duration::from_secs(5), async {
+        while connection_limiter.available_permits() < max_connections - 1 || client_limiter.connections(&LOCALHOST) > 0 {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await.expect("permits were not released");
+}
+
+#[tokio::test]
+async fn permits_are_released_on_disconnect() {
+    let (addr, connection_limiter, client_limiter) = start_server(2, 1, None).await;
+
+    let mut connection = connect(addr).await;
+    connection.write_frame(&command("PUT", 1.0)).await.unwrap();
+    assert_eq!(connection.read_frame().await.unwrap().unwrap(), "OK");
+    assert_eq!(client_limiter.connections(&LOCALHOST), 1);
+
+    // A second connection from the same client is over its limit
+    let mut refused = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert!(matches!(refused.read_frame().await.unwrap(), Some(Frame::Error(msg)) if msg.starts_with("CAPACITY ")));
+
+    drop(connection);
+    released(&connection_limiter, &client_limiter, 2).await;
+
+    // With the slot back the client gets in again
+    let mut connection = connect(addr).await;
+    connection.write_frame(&command("GET", 1.0)).await.unwrap();
+    assert!(matches!(connection.read_frame().await.unwrap(), Some(Frame::Bulk(data)) if data == blob(1.0)));
+}
+
+#[tokio::test]
+async fn idle_connections_are_closed() {
+    let (addr, connection_limiter, client_limiter) = start_server(2, 1, Some(Duration::from_millis(50))).await;
+
+    let mut connection = connect(addr).await;
+    connection.write_frame(&command("GET", 1.0)).await.unwrap();
+    assert!(matches!(connection.read_frame().await.unwrap(), Some(Frame::Null())));
+    assert_eq!(client_limiter.connections(&LOCALHOST), 1);
+
+    // The server hangs up on the quiet client, and gives its slots back
+    let closed = time::timeout(Duration::from_secs(5), connection.read_frame()).await.expect("idle connection was not closed");
+    assert!(closed.unwrap().is_none());
+    released(&connection_limiter, &client_limiter, 2).await;
+}
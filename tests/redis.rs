@@ -0,0 +1,186 @@
+mod common;
+
+use std::net::SocketAddr;
+use bytes::Bytes;
+use tokio::net::{TcpListener, TcpStream};
+use rush::net::{Connection, Frame, FrameLimits};
+use common::{Database, DIM};
+
+async fn start_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (datasets, _) = common::datasets::<Database>();
+
+    let mut server = common::listener(listener, datasets);
+    tokio::spawn(async move { server.run().await });
+    addr
+}
+
+// Sends a command the way Redis clients do, as an array of bulk strings.
+async fn call(connection: &mut Connection, args: &[&str]) -> Frame {
+    let args = args.iter().map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes()))).collect();
+    connection.write_frame(&Frame::Array(args)).await.unwrap();
+    connection.read_frame().await.unwrap().unwrap()
+}
+
+fn values(value: f32) -> Vec<String> {
+    let mut args = vec!["VALUES".to_string(), DIM.to_string()];
+    args.extend((0..DIM).map(|_| value.to_string()));
+    args
+}
+
+fn command<'a>(prefix: &[&'a str], vector: &'a [String], suffix: &[&'a str]) -> Vec<&'a str> {
+    let mut args = prefix.to_vec();
+    args.extend(vector.iter().map(String::as_str));
+    args.extend_from_slice(suffix);
+    args
+}
+
+#[tokio::test]
+async fn redis_handshake() {
+    let addr = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    match call(&mut connection, &["HELLO", "2"]).await {
+        Frame::Array(fields) => {
+            assert_eq!(fields[0], "server");
+            assert_eq!(fields[1], "rush");
+        }
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+    assert!(matches!(call(&mut connection, &["HELLO", "9"]).await, Frame::Error(msg) if msg.starts_with("NOPROTO")));
+    assert_eq!(call(&mut connection, &["PING"]).await, "PONG");
+    assert_eq!(call(&mut connection, &["ping", "hello"]).await, "hello");
+    assert!(matches!(call(&mut connection, &["COMMAND", "COUNT"]).await, Frame::Integer(13)));
+    assert!(matches!(call(&mut connection, &["NOPE"]).await, Frame::Error(msg) if msg.starts_with("ERR unknown command")));
+
+    match call(&mut connection, &["INFO", "keyspace"]).await {
+        Frame::Bulk(info) => assert_eq!(&info[..], b"# Keyspace\r\ndatasets:1\r\nvectors:0\r\n"),
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+    match call(&mut connection, &["INFO", "server"]).await {
+        Frame::Bulk(info) => {
+            let backend = format!("simd_backend:{}\r\n", rush::simd::dispatch::backend());
+            assert!(String::from_utf8_lossy(&info).contains(&backend));
+        }
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+
+    assert_eq!(call(&mut connection, &["QUIT"]).await, "OK");
+    assert!(connection.read_frame().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn redis_vector_commands() {
+    let addr = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    let one = values(1.0);
+    let two = values(2.0);
+    assert_eq!(call(&mut connection, &command(&["RUSH.PUT", "test"], &one, &[])).await, "OK");
+    assert_eq!(call(&mut connection, &command(&["RUSH.PUT", "test"], &two, &[])).await, "OK");
+
+    let neighbours = call(&mut connection, &command(&["RUSH.TOPK", "test"], &values(1.2), &["1"])).await;
+    match neighbours {
+        Frame::Array(neighbours) => match &neighbours[..] {
+            [Frame::Array(neighbour)] => match &neighbour[..] {
+                [Frame::Array(vector), distance] => {
+                    assert_eq!(vector.len(), DIM);
+                    assert!(vector.iter().all(|elt| *elt == "1"));
+                    assert!(distance.to_string().parse::<f32>().unwrap() < 1.0);
+                }
+                frames => panic!("unexpected frames {:?}", frames),
+            },
+            frames => panic!("unexpected frames {:?}", frames),
+        },
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+
+    assert!(matches!(call(&mut connection, &command(&["RUSH.DELETE", "test"], &two, &[])).await, Frame::Integer(1)));
+    assert!(matches!(call(&mut connection, &command(&["RUSH.DELETE", "test"], &two, &[])).await, Frame::Integer(0)));
+
+    // Errors are reported without dropping the connection
+    assert!(matches!(call(&mut connection, &["RUSH.GET", "test", "VALUES", "2", "1"]).await, Frame::Error(_)));
+    assert_eq!(call(&mut connection, &["PING"]).await, "PONG");
+}
+
+#[tokio::test]
+async fn resp3_negotiation() {
+    let addr = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    match call(&mut connection, &["HELLO", "3"]).await {
+        Frame::Map(fields) => {
+            assert_eq!(fields[0].0, "server");
+            assert!(fields.iter().any(|(key, value)| *key == "proto" && matches!(value, Frame::Integer(3))));
+        }
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+
+    let one = values(1.0);
+    assert_eq!(call(&mut connection, &command(&["RUSH.PUT", "test"], &one, &[])).await, "OK");
+    match call(&mut connection, &command(&["RUSH.TOPK", "test"], &values(1.5), &["1"])).await {
+        Frame::Array(neighbours) => match &neighbours[..] {
+            [Frame::Array(neighbour)] => match &neighbour[..] {
+                [Frame::Array(vector), Frame::Double(distance)] => {
+                    assert!(vector.iter().all(|elt| matches!(elt, Frame::Double(x) if *x == 1.0)));
+                    assert!((distance - 2.0).abs() < 1e-6);
+                }
+                frames => panic!("unexpected frames {:?}", frames),
+            },
+            frames => panic!("unexpected frames {:?}", frames),
+        },
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+
+    // Missing vectors are RESP3 nulls, and COMMAND reports negative arities
+    assert!(matches!(call(&mut connection, &command(&["RUSH.DELETE", "test"], &one, &[])).await, Frame::Integer(1)));
+    assert!(matches!(call(&mut connection, &command(&["RUSH.GET", "test"], &one, &[])).await, Frame::Null()));
+    match call(&mut connection, &["COMMAND", "INFO", "ping"]).await {
+        Frame::Array(commands) => match &commands[..] {
+            [Frame::Array(info)] => assert!(matches!(info[1], Frame::Integer(-1))),
+            frames => panic!("unexpected frames {:?}", frames),
+        },
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+
+    // Back to RESP2, where doubles arrive as bulk strings
+    assert!(matches!(call(&mut connection, &["HELLO", "2"]).await, Frame::Array(_)));
+    assert_eq!(call(&mut connection, &command(&["RUSH.PUT", "test"], &one, &[])).await, "OK");
+    match call(&mut connection, &command(&["RUSH.GET", "test"], &one, &[])).await {
+        Frame::Array(vector) => assert!(vector.iter().all(|elt| *elt == "1")),
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+}
+
+#[tokio::test]
+async fn malformed_frames_are_rejected() {
+    let addr = start_server().await;
+
+    // Nested deeper than the default limit allows
+    let mut frame = Frame::Array(vec![]);
+    for _ in 0..FrameLimits::default().max_depth + 1 {
+        frame = Frame::Array(vec![frame]);
+    }
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&frame).await.unwrap();
+    assert!(matches!(connection.read_frame().await.unwrap(), Some(Frame::Error(msg)) if msg.starts_with("PROTOCOL ")));
+    assert!(connection.read_frame().await.unwrap().is_none());
+
+    // A command with missing arguments in stream mode
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Integer(0)).await.unwrap();
+    connection.write_frame(&Frame::Array(vec![Frame::Simple("GET".into())])).await.unwrap();
+    assert!(matches!(connection.read_frame().await.unwrap(), Some(Frame::Error(msg)) if msg.starts_with("PROTOCOL ")));
+
+    // A vector that isn't a blob
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Integer(0)).await.unwrap();
+    let get = Frame::Array(vec![Frame::Simple("GET".into()), Frame::Simple("test".into()), Frame::Integer(1)]);
+    connection.write_frame(&get).await.unwrap();
+    assert!(matches!(connection.read_frame().await.unwrap(), Some(Frame::Error(msg)) if msg == "PROTOCOL expected vector blob"));
+
+    // The server is still up
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(call(&mut connection, &["PING"]).await, "PONG");
+}
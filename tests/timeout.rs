@@ -0,0 +1,94 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use rush::net::{Connection, Dataset, Frame, HttpListener, Listener};
+use common::{blob, constant, Database};
+
+async fn start_server(command_timeout: Option<Duration>) -> (SocketAddr, Arc<Dataset<Database>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (datasets, dataset) = common::datasets();
+
+    let mut server = Listener { command_timeout, ..common::listener(listener, datasets) };
+    tokio::spawn(async move { server.run().await });
+    (addr, dataset)
+}
+
+fn command(name: &str, timeout_ms: Option<i64>) -> Frame {
+    let mut frames = vec![Frame::Simple(name.into()), Frame::Simple("test".into()), Frame::Bulk(blob(1f32))];
+    if let Some(ms) = timeout_ms {
+        frames.push(Frame::Simple("TIMEOUT".into()));
+        frames.push(Frame::Integer(ms));
+    }
+    Frame::Array(frames)
+}
+
+fn is_timeout(frame: &Option<Frame>) -> bool {
+    matches!(frame, Some(Frame::Error(msg)) if msg.starts_with("TIMEOUT "))
+}
+
+#[tokio::test]
+async fn client_deadline_covers_lock_wait() {
+    let (addr, dataset) = start_server(None).await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Integer(0)).await.unwrap();
+
+    // A long running writer holds the lock
+    let guard = dataset.database().write().await;
+    connection.write_frame(&command("GET", Some(20))).await.unwrap();
+    assert!(is_timeout(&connection.read_frame().await.unwrap()));
+    connection.write_frame(&command("PUT", Some(20))).await.unwrap();
+    assert!(is_timeout(&connection.read_frame().await.unwrap()));
+    drop(guard);
+
+    // The connection is still usable, and the put never happened
+    connection.write_frame(&command("GET", Some(1000))).await.unwrap();
+    assert!(matches!(connection.read_frame().await.unwrap(), Some(Frame::Null())));
+}
+
+#[tokio::test]
+async fn server_default_deadline() {
+    let (addr, dataset) = start_server(Some(Duration::from_millis(20))).await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Integer(1)).await.unwrap();
+
+    let guard = dataset.database().read().await;
+    connection.write_frame(&Frame::Array(vec![command("GET", None), command("PUT", None)])).await.unwrap();
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::Array(responses)) => match &responses[..] {
+            // Readers share the lock, so only the writer has to wait
+            [Frame::Null(), Frame::Error(msg)] => assert!(msg.starts_with("TIMEOUT ")),
+            frames => panic!("unexpected frames {:?}", frames),
+        },
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+    drop(guard);
+}
+
+#[tokio::test]
+async fn http_uses_server_default_deadline() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (datasets, dataset) = common::datasets::<Database>();
+    let mut server = HttpListener { command_timeout: Some(Duration::from_millis(20)), ..common::http_listener(listener, datasets) };
+    tokio::spawn(async move { server.run().await });
+
+    let guard = dataset.database().write().await;
+    let body = serde_json::json!({ "vector": constant(1.0) }).to_string();
+    let request = format!(
+        "POST /datasets/test/query HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(), body,
+    );
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    drop(guard);
+
+    assert!(response.starts_with("HTTP/1.1 504 "), "{}", response);
+    assert!(response.contains("TIMEOUT"), "{}", response);
+}
@@ -0,0 +1,159 @@
+mod common;
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use rush::net::{ClientLimiter, Connection, Frame, Listener, TlsConfig};
+use common::{blob, command, Database};
+
+// A throwaway CA, with a server certificate for localhost and a client
+// certificate, all signed by it.
+struct Pki {
+    ca: Certificate,
+    server: (Certificate, KeyPair),
+    client: (Certificate, KeyPair),
+}
+
+impl Pki {
+    fn generate() -> Pki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let leaf = |name: &str, usage| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            (params.signed_by(&key, &ca, &ca_key).unwrap(), key)
+        };
+        let server = leaf("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let client = leaf("client", ExtendedKeyUsagePurpose::ClientAuth);
+
+        Pki { ca, server, client }
+    }
+
+    // Writes the server's PEM files to a fresh directory, returning its path.
+    fn write_server_files(&self, name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rush-tls-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ca.pem"), self.ca.pem()).unwrap();
+        fs::write(dir.join("server.pem"), self.server.0.pem()).unwrap();
+        fs::write(dir.join("server.key"), self.server.1.serialize_pem()).unwrap();
+        dir
+    }
+
+    fn connector(&self, client_auth: bool) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.ca.der().clone()).unwrap();
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider())).
+            with_safe_default_protocol_versions().
+            unwrap().
+            with_root_certificates(roots);
+
+        let config = if client_auth {
+            let (cert, key) = &self.client;
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+            builder.with_client_auth_cert(vec![CertificateDer::from(cert.der().to_vec())], key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+}
+
+async fn start_server(tls: TlsConfig) -> SocketAddr {
+    start_server_with_client_limit(tls, 64).await
+}
+
+async fn start_server_with_client_limit(tls: TlsConfig, max_per_client: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (datasets, _) = common::datasets::<Database>();
+
+    let mut server = Listener {
+        tls_acceptor: Some(tls.acceptor().unwrap()),
+        client_limiter: Arc::new(ClientLimiter::new(max_per_client)),
+        ..common::listener(listener, datasets)
+    };
+    tokio::spawn(async move { server.run().await });
+    addr
+}
+
+async fn connect(addr: SocketAddr, connector: &TlsConnector) -> std::io::Result<Connection<TlsStream<TcpStream>>> {
+    let socket = TcpStream::connect(addr).await?;
+    let stream = connector.connect(ServerName::try_from("localhost").unwrap(), socket).await?;
+    Ok(Connection::new(stream))
+}
+
+#[tokio::test]
+async fn commands_over_tls() {
+    let pki = Pki::generate();
+    let dir = pki.write_server_files("plain");
+    let addr = start_server(TlsConfig::new(dir.join("server.pem"), dir.join("server.key"))).await;
+
+    let mut connection = connect(addr, &pki.connector(false)).await.unwrap();
+    connection.write_frame(&Frame::Integer(0)).await.unwrap();
+    connection.write_frame(&command("PUT", 1.0)).await.unwrap();
+    assert_eq!(connection.read_frame().await.unwrap().unwrap(), "OK");
+    connection.write_frame(&command("GET", 1.1)).await.unwrap();
+    assert!(matches!(connection.read_frame().await.unwrap(), Some(Frame::Bulk(data)) if data == blob(1.0)));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn mutual_tls_requires_client_certificate() {
+    let pki = Pki::generate();
+    let dir = pki.write_server_files("mutual");
+    let tls = TlsConfig::new(dir.join("server.pem"), dir.join("server.key")).with_client_ca(dir.join("ca.pem"));
+    let addr = start_server(tls).await;
+
+    // Under TLS 1.3 the client only learns it was rejected on its first read
+    let rejected = match connect(addr, &pki.connector(false)).await {
+        Ok(mut connection) => {
+            let _ = connection.write_frame(&Frame::Integer(0)).await;
+            let _ = connection.write_frame(&command("GET", 1.0)).await;
+            !matches!(connection.read_frame().await, Ok(Some(_)))
+        }
+        Err(_) => true,
+    };
+    assert!(rejected);
+
+    let mut connection = connect(addr, &pki.connector(true)).await.unwrap();
+    connection.write_frame(&Frame::Integer(2)).await.unwrap();
+    connection.write_frame(&command("PUT", 1.0)).await.unwrap();
+    assert_eq!(connection.read_frame().await.unwrap().unwrap(), "OK");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn refused_tls_clients_are_disconnected() {
+    let pki = Pki::generate();
+    let dir = pki.write_server_files("refused");
+    let addr = start_server_with_client_limit(TlsConfig::new(dir.join("server.pem"), dir.join("server.key")), 1).await;
+
+    let mut connection = connect(addr, &pki.connector(false)).await.unwrap();
+    connection.write_frame(&Frame::Integer(0)).await.unwrap();
+    connection.write_frame(&command("PUT", 1.0)).await.unwrap();
+    assert_eq!(connection.read_frame().await.unwrap().unwrap(), "OK");
+
+    // The server hangs up rather than answering the handshake in plaintext,
+    // which the client would see as InvalidData
+    let err = connect(addr, &pki.connector(false)).await.err().unwrap();
+    assert!(matches!(err.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset), "{:?}", err);
+
+    fs::remove_dir_all(dir).unwrap();
+}
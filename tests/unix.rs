@@ -0,0 +1,69 @@
+mod common;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use bytes::Bytes;
+use tokio::net::{UnixListener, UnixStream};
+use rush::net::{ClientLimiter, Connection, Frame, Listener};
+use common::{blob, command, Database};
+
+async fn start_server(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rush-{}-{}.sock", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let (datasets, _) = common::datasets::<Database>();
+
+    let mut server = Listener {
+        // Every connection comes from the same host, so this must not apply
+        client_limiter: Arc::new(ClientLimiter::new(1)),
+        ..common::listener(listener, datasets)
+    };
+    tokio::spawn(async move { server.run().await });
+    path
+}
+
+async fn connect(path: &PathBuf, mode: i64) -> Connection<UnixStream> {
+    let mut connection = Connection::new(UnixStream::connect(path).await.unwrap());
+    connection.write_frame(&Frame::Integer(mode)).await.unwrap();
+    connection
+}
+
+#[tokio::test]
+async fn modes_over_unix_socket() {
+    let path = start_server("modes").await;
+
+    let mut stream = connect(&path, 0).await;
+    stream.write_frame(&command("PUT", 1.0)).await.unwrap();
+    assert_eq!(stream.read_frame().await.unwrap().unwrap(), "OK");
+
+    // A second connection from the same host while the first is still open
+    let mut single = connect(&path, 2).await;
+    single.write_frame(&command("GET", 1.1)).await.unwrap();
+    assert!(matches!(single.read_frame().await.unwrap(), Some(Frame::Bulk(data)) if data == blob(1.0)));
+
+    let mut bulk = connect(&path, 1).await;
+    bulk.write_frame(&Frame::Array(vec![command("PUT", 2.0), command("GET", 2.1)])).await.unwrap();
+    match bulk.read_frame().await.unwrap() {
+        Some(Frame::Array(responses)) => match &responses[..] {
+            [ok, Frame::Bulk(data)] => {
+                assert_eq!(*ok, "OK");
+                assert_eq!(*data, blob(2.0));
+            }
+            frames => panic!("unexpected frames {:?}", frames),
+        },
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn redis_commands_over_unix_socket() {
+    let path = start_server("redis").await;
+    let mut connection = Connection::new(UnixStream::connect(&path).await.unwrap());
+
+    connection.write_frame(&Frame::Array(vec![Frame::Bulk(Bytes::from_static(b"PING"))])).await.unwrap();
+    assert_eq!(connection.read_frame().await.unwrap().unwrap(), "PONG");
+
+    std::fs::remove_file(path).unwrap();
+}